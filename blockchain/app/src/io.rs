@@ -1,4 +1,6 @@
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 use parity_scale_codec::{Decode, Encode};
 use sails_rs::prelude::ActorId;
 use scale_info::TypeInfo;
@@ -6,26 +8,76 @@ use scale_info::TypeInfo;
 #[derive(Encode, Decode, TypeInfo, Clone, Debug)]
 pub struct LendingInit {
     pub vft_address: ActorId,
+    pub debt_decimals: Option<u8>,
 }
 
 #[derive(Encode, Decode, TypeInfo, Clone, Debug)]
 pub enum LendingAction {
     DepositCollateral,
     Borrow,
-    Repay { user: ActorId, amount: u128 },
-    WithdrawCollateral { user: ActorId, amount: u128 },
+    Repay {
+        user: ActorId,
+        amount: u128,
+    },
+    WithdrawCollateral {
+        user: ActorId,
+        amount: u128,
+    },
     Lend,
     Withdraw(u128),
     Liquidate(ActorId),
+    ConfigureDex {
+        dex: ActorId,
+        max_slippage_bps: u128,
+    },
+    SetLiquidationMode(crate::LiquidationMode),
+    GetDexSwaps,
+    GetAuctions {
+        start_after: Option<u64>,
+        limit: u32,
+    },
     GetUserInfo(ActorId),
     Pause,
     Resume,
-    UpdateTvaraPrice(u128),
+    UpdateCollateralPrice(u128),
+    UpdateDebtPrice(u128),
     UtilizationRate,
     ClaimInterest,
     AdminWithdrawFunds(u128),
     AdminWithdrawTreasury(u128),
     GetContractState,
+    GetBorrowersPage {
+        start_after: Option<ActorId>,
+        limit: u32,
+        filter: crate::PositionFilter,
+    },
+    GetLiquidationOpportunities {
+        threshold: u128,
+        limit: u32,
+    },
+    PreviewDeposit {
+        user: ActorId,
+        amount: u128,
+    },
+    PreviewBorrow {
+        user: ActorId,
+        amount: u128,
+    },
+    PreviewRepay {
+        user: ActorId,
+        amount: u128,
+    },
+    PreviewWithdrawCollateral {
+        user: ActorId,
+        amount: u128,
+    },
+    PreviewLiquidate(ActorId),
+    DepositTerm(crate::DepositTier),
+    WithdrawTermDeposit(u64),
+    GetTermDeposit(u64),
+    ImportState(Box<crate::migration::VersionedState>),
+    GetVersion,
+    CheckInvariants,
 }
 
 #[derive(Encode, Decode, TypeInfo, Clone, Debug)]
@@ -34,7 +86,8 @@ pub enum LendingReply {
         collateral: u128,
         debt: u128,
         lender_balance: u128,
-        tvara_price: u128,
+        collateral_price: u128,
+        debt_price: u128,
         health_factor: u128,
         accrued_interest: u128,
         lender_interest_earned: u128,
@@ -43,4 +96,13 @@ pub enum LendingReply {
     Success,
     Error(String),
     ContractState(crate::ContractState),
+    UserInfoPage(crate::UserInfoPage),
+    LiquidationOpportunities(Vec<crate::LiquidationOpportunity>),
+    Preview(crate::ActionPreview),
+    TermDepositId(u64),
+    DexSwaps(Vec<crate::DexSwap>),
+    Auctions(Vec<crate::AuctionStatus>),
+    TermDeposit(Option<crate::TermDepositInfo>),
+    Version(crate::migration::VersionInfo),
+    InvariantReport(crate::InvariantReport),
 }
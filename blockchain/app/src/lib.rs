@@ -1,19 +1,25 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(static_mut_refs)]
 #[warn(dead_code)]
 use extended_vft_client::vft::io as vft_io;
 use sails_rs::prelude::*;
 extern crate alloc;
-use alloc::collections::BTreeMap;
-use sails_rs::gstd::exec::block_timestamp;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use core::ops::Bound;
+use gstd::{ReservationId, ReservationIdExt};
+use sails_rs::gstd::exec::{self, block_timestamp};
 use sails_rs::gstd::msg;
 use sails_rs::prelude::ActorId;
 use sails_rs::{program, service}; // Import energy_balance
 
+use crate::math::{Rounding, WAD};
+use crate::migration::{CODE_VERSION, STORAGE_VERSION, VersionInfo, VersionedState};
+use crate::pool::{Asset, AssetDecimals, Context, Effect, Pool};
+
 // Fixed decimal constants
-const WAD: u128 = 1_000_000_000_000_000_000; // 18 decimals for calculations
-const TVARA_UNIT: u128 = 1_000_000_000_000; // 12 decimals for TVARA/VFT tokens
-const DEFAULT_TVARA_PRICE: u128 = WAD; // 1 TVARA = 1 USD (in 18 decimal format for calculations)
+const VARA_DECIMALS: u8 = 12; // Native VARA collateral
+const MAX_ASSET_DECIMALS: u8 = 24; // Keeps 10^decimals and value conversions well inside u128
+const DEFAULT_PRICE: u128 = WAD; // 1 USD (in 18 decimal format for calculations)
 
 // Interest distribution percentages (as fractions of 100)
 const LENDER_INTEREST_SHARE: u128 = 4; // 4% for lenders
@@ -21,18 +27,87 @@ const TREASURY_INTEREST_SHARE: u128 = 2; // 2% for treasury
 #[allow(dead_code)]
 const TOTAL_INTEREST_SHARE_PERCENT: u128 = LENDER_INTEREST_SHARE + TREASURY_INTEREST_SHARE; // Total 6% from accrued interest
 
+const SECONDS_PER_YEAR: u128 = 365 * 24 * 3600;
+const LIQUIDATION_THRESHOLD: u128 = 120; // Health factor below which a position can be liquidated
+const BPS_DENOMINATOR: u128 = 10_000; // Basis points
+
+// Fixed-term loans
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+const MAX_TERM_DAYS: u32 = 365;
+const TERM_LOAN_GRACE_PERIOD: u64 = 3 * SECONDS_PER_DAY; // After maturity, before liquidation
+
+// Dutch auctions: the collateral discount grows linearly from start to max over the duration,
+// after which an unsold auction can be restarted, up to a limit before it is written off
+const AUCTION_START_DISCOUNT: u128 = 2; // %
+const AUCTION_MAX_DISCOUNT: u128 = 20; // %
+const AUCTION_DURATION: u64 = 6 * 3600; // Seconds
+const AUCTION_MAX_RESTARTS: u32 = 3;
+
+// DEX liquidations: the TVARA a swap returns may fall short of the collateral's oracle value by
+// at most the configured slippage
+const DEFAULT_MAX_SLIPPAGE_BPS: u128 = 300; // 3%
+const MAX_SLIPPAGE_BPS: u128 = 2_000; // Upper bound the admin can set, 20%
+const DEX_SWAP_GAS: u64 = 10_000_000_000;
+const DEX_SWAP_RESOLVE_DELAY: u64 = 3600; // Seconds before the admin may close a pending swap
+
+// Price history: the latest observations are kept for time-weighted average prices, which
+// liquidations weigh against spot so one price update can't trigger them alone
+const MAX_PRICE_OBSERVATIONS: usize = 64;
+const DEFAULT_TWAP_WINDOW: u64 = 30 * 60; // Seconds
+const MAX_TWAP_WINDOW: u64 = 24 * 3600;
+
+// Rate history: a checkpoint is kept at most once per period, at accrual
+const MAX_RATE_CHECKPOINTS: usize = 256;
+const DEFAULT_RATE_CHECKPOINT_PERIOD: u64 = 3600; // Seconds
+
+// Protocol fees, credited to the treasury
+const MAX_FEE_BPS: u128 = 1_000; // Upper bound the admin can set for each fee, 10%
+
+// Treasury revenue split, weights in basis points summing to BPS_DENOMINATOR
+const MAX_TREASURY_BENEFICIARIES: usize = 10;
+
+// Per-account position history, oldest entries dropped first
+const MAX_ACCOUNT_HISTORY: usize = 50;
+
+// Signed price updates
+const MAX_ORACLE_PUBLISHERS: usize = 16;
+
+// Term deposits: kept by the pool when a deposit is withdrawn before it unlocks, and paid to
+// the remaining lenders
+const EARLY_WITHDRAWAL_PENALTY: u128 = 5; // % of the deposit
+
+// Pagination limits for enumeration views
+const MAX_PAGE_LIMIT: u32 = 100; // Max entries returned in a single page
+const MAX_PAGE_SCAN: usize = 1_000; // Max entries inspected per page when filters are applied
+
+// Queued withdrawal requests served by a single operation, to bound gas
+const MAX_WITHDRAWAL_FILLS: usize = 20;
+
+// Keepers: a public `accrue` pays a bounty from the treasury once accrual is this stale
+const KEEPER_ACCRUAL_DELAY: u64 = 3600; // Seconds
+const KEEPER_BOUNTY_BPS: u128 = 10; // 0.1% of the treasury
+const MAX_POKE_USERS: usize = 50;
+
+// Accrual checkpoints
+const DEFAULT_HEALTH_WARNING_THRESHOLD: u128 = 130; // Health factor below which positions are flagged
+const MAX_HEALTH_WARNINGS: usize = 50; // Positions flagged per checkpoint, to bound gas
+
 static mut STORAGE: Option<LendingStorage> = None;
 
 #[derive(Clone, Debug)]
 pub struct LendingStorage {
+    pub storage_version: u32,
     pub vft_address: ActorId,
-    pub collateral: BTreeMap<ActorId, u128>, // in TVARA units (12 decimals)
-    pub tvara_price: u128,                   // in 18 decimal format for calculations
-    pub debt: BTreeMap<ActorId, u128>, // in TVARA units (12 decimals) - this will ONLY track PRINCIPAL debt
-    pub lender_balances: BTreeMap<ActorId, u128>, // in TVARA units (12 decimals)
+    pub decimals: AssetDecimals, // Collateral (VARA) and debt token (VFT) decimals
+    pub collateral: BTreeMap<ActorId, u128>, // in VARA units
+    pub collateral_price: u128,  // VARA price in USD (18 decimal format)
+    pub debt_price: u128,        // TVARA price in USD (18 decimal format)
+    pub debt: BTreeMap<ActorId, u128>, // in TVARA units - this will ONLY track PRINCIPAL debt
+    pub lender_balances: BTreeMap<ActorId, u128>, // in TVARA units
+    pub total_lender_balances: u128, // sum(lender_balances)
     pub lender_interest_earned: BTreeMap<ActorId, u128>, // New: Tracks interest earned by each lender
-    pub total_liquidity: u128,                           // in TVARA units (12 decimals)
-    pub treasury: u128,                                  // in TVARA units (12 decimals)
+    pub total_liquidity: u128,                           // in TVARA units
+    pub treasury: u128,                                  // in TVARA units
     pub paused: bool,
     pub reentrancy: bool,
     pub admin: ActorId,
@@ -40,18 +115,388 @@ pub struct LendingStorage {
     pub total_interest_earned: u128, // Keep this, but its purpose changes slightly (now total interest generated)
     pub user_accrued_interest: BTreeMap<ActorId, u128>, // Tracks accrued interest per borrower
     pub total_principal_borrowed: u128, // New: Sum of all principal debt
+    // Borrowers ordered by collateral-to-debt ratio (lowest = least healthy). The ratio is
+    // price-independent, so the ordering stays valid across price updates.
+    pub health_index: BTreeSet<(u128, ActorId)>,
+    pub health_index_keys: BTreeMap<ActorId, u128>, // Current index key per borrower
+    pub strict_invariants: bool, // Reject guarded operations that leave invariants broken
+    // FIFO of lender withdrawals waiting for liquidity; their VFT is burned when queued
+    pub withdrawal_queue: VecDeque<WithdrawalRequest>,
+    pub pending_withdrawals: BTreeMap<ActorId, u128>, // Unfilled queued amount per lender
+    pub next_withdrawal_id: u64,
+    // Fixed-rate term loans, tracked separately from the variable-rate `debt`
+    pub term_loans: BTreeMap<u64, TermLoan>,
+    pub user_term_loans: BTreeMap<ActorId, BTreeSet<u64>>, // Open term loan IDs per borrower
+    pub total_term_principal: u128,
+    pub next_term_loan_id: u64,
+    // Locked lender deposits; instant-access deposits stay in `lender_balances`
+    pub term_deposits: BTreeMap<u64, TermDeposit>,
+    pub lender_term_deposits: BTreeMap<ActorId, BTreeSet<u64>>, // Open deposit IDs per lender
+    pub total_term_deposits: u128,
+    pub next_term_deposit_id: u64,
+    // Accrual checkpoints the program runs on itself through delayed messages
+    pub checkpoint_interval: u32, // Blocks between checkpoints; 0 when disabled
+    pub checkpoint_gas: u64,      // Gas budget of each checkpoint message
+    pub health_warning_threshold: u128,
+    pub checkpoint_epoch: u64, // Bumped on reconfiguration so already scheduled checkpoints lapse
+    pub checkpoint_reservations: VecDeque<CheckpointReservation>, // Prepaid gas for future checkpoints
+    pub last_checkpoint_ts: u64,
+    // Dutch-auction liquidations
+    pub liquidation_mode: LiquidationMode,
+    pub auctions: BTreeMap<u64, Auction>,
+    pub next_auction_id: u64,
+    pub bad_debt: u128, // TVARA auctions and swaps failed to recover, a loss to lenders
+    // DEX liquidations
+    pub dex_address: Option<ActorId>,
+    pub max_slippage_bps: u128,
+    pub dex_swaps: BTreeMap<u64, DexSwap>, // Sent to the DEX and awaiting its reply
+    pub next_dex_swap_id: u64,
+    // Price history, oldest first, for TWAPs
+    pub price_observations: VecDeque<PriceObservation>,
+    pub twap_window: u64, // Seconds averaged when judging liquidations; 0 uses spot only
+    // Signed price updates anyone can relay
+    pub oracle_publishers: BTreeMap<[u8; 32], SignatureScheme>, // Public key -> its scheme
+    pub oracle_threshold: u32, // Distinct publisher signatures an update needs; 0 when disabled
+    pub collateral_price_updated_at: u64, // Last update, admin or signed
+    pub debt_price_updated_at: u64,
+    // Rate and utilization history, oldest first
+    pub rate_history: VecDeque<RateCheckpoint>,
+    pub rate_checkpoint_period: u64, // Min seconds between checkpoints; 0 records every accrual
+    // Interest earned per unit of principal (borrowers) and of deposits (lenders) since
+    // deployment, in WAD; realized APYs are read off their growth
+    pub borrow_yield_index: u128,
+    pub supply_yield_index: u128,
+    // Position history per borrower, recorded only while enabled
+    pub account_history_enabled: bool,
+    pub account_history: BTreeMap<ActorId, VecDeque<AccountHistoryEntry>>,
+    pub next_account_history_id: u64,
+    // Liquidity mining: reward tokens emitted per second, split pro rata over lender balances
+    // (supply) and principal debt (borrow)
+    pub reward_token: Option<ActorId>,
+    pub reward_payout: RewardPayout,
+    pub supply_reward_rate: u128, // Reward tokens per second across all lenders
+    pub borrow_reward_rate: u128, // Reward tokens per second across all borrowers
+    // Rewards per unit of balance since the start, in WAD
+    pub supply_reward_index: u128,
+    pub borrow_reward_index: u128,
+    pub rewards_updated_at: u64,
+    pub reward_snapshots: BTreeMap<ActorId, (u128, u128)>, // (supply, borrow) index last settled
+    pub unclaimed_rewards: BTreeMap<ActorId, u128>,
+    pub total_rewards_claimed: u128,
+    // Protocol fees in basis points, all 0 by default
+    pub origination_fee_bps: u128, // Of each borrow, kept from the minted amount
+    pub liquidation_fee_bps: u128, // Of the seized collateral
+    pub withdrawal_fee_bps: u128,  // Of the principal a lender withdraws
+    pub total_fees_collected: u128, // Debt-token units credited to the treasury
+    // Who `distribute_treasury` pays, and what each account has been paid in total
+    pub treasury_beneficiaries: Vec<TreasuryBeneficiary>,
+    pub treasury_distributed: BTreeMap<ActorId, u128>, // Debt-token units, kept after removal
+    // Emergency shutdown: set once and never cleared. Positions are replaced by VARA claims.
+    pub shutdown: Option<ShutdownStatus>,
+    pub shutdown_collateral_claims: BTreeMap<ActorId, u128>, // Excess collateral per borrower
+    pub shutdown_lender_claims: BTreeMap<ActorId, u128>,     // Pro-rata share per lender
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+pub struct ShutdownStatus {
+    pub timestamp: u64,
+    pub collateral_price: u128, // Final prices every position was settled at
+    pub debt_price: u128,
+    pub positions_settled: u32,
+    pub uncovered_debt: u128,    // Debt-token units collateral fell short of
+    pub collateral_claims: u128, // VARA owed back to borrowers
+    pub lender_assets: u128,     // VARA shared between lenders
+    pub lender_deposits: u128,   // Debt-token units the lender assets were split over
+    pub collateral_claimed: u128, // VARA paid out so far
+    pub lender_assets_claimed: u128,
+}
+
+// VARA `claim_shutdown` pays an account
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownClaim {
+    pub collateral: u128,   // Collateral left after the account's debt was paid
+    pub lender_share: u128, // Share of the remaining assets for the account's deposits
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+pub struct TreasuryBeneficiary {
+    pub account: ActorId,
+    pub weight_bps: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeKind {
+    Origination,
+    Liquidation,
+    Withdrawal,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct FeeSchedule {
+    pub origination_fee_bps: u128,
+    pub liquidation_fee_bps: u128,
+    pub withdrawal_fee_bps: u128,
+    pub max_fee_bps: u128,
+    pub total_fees_collected: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RewardPayout {
+    Mint,     // The program must be a minter of the reward token
+    Transfer, // Paid from reward tokens the program holds
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct RewardSchedule {
+    pub token: Option<ActorId>,
+    pub payout: RewardPayout,
+    pub supply_rate: u128, // Reward tokens per second
+    pub borrow_rate: u128,
+    pub total_supply: u128, // Lender balances sharing the supply emission
+    pub total_borrow: u128, // Principal debt sharing the borrow emission
+    pub total_claimed: u128,
+}
+
+// VARA amounts for collateral movements, interest settlement, liquidation and shutdown; debt
+// tokens for borrows and repayments
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountAction {
+    CollateralDeposited,
+    CollateralWithdrawn,
+    Borrowed,
+    TermLoanBorrowed,
+    Repaid,
+    TermLoanRepaid,
+    InterestSettled, // Accrued interest taken from collateral when a loan closes
+    Liquidated,      // The whole position was seized
+    ShutdownSettled, // Debt paid from collateral at the shutdown prices
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+pub struct AccountHistoryEntry {
+    pub id: u64, // Increases across all accounts, usable as a paging cursor
+    pub action: AccountAction,
+    pub amount: u128,
+    pub timestamp: u64,
+    // The position right after the operation
+    pub collateral: u128,
+    pub debt: u128, // Principal + accrued interest, variable and term
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+pub struct RateCheckpoint {
+    pub timestamp: u64,
+    pub utilization: u128,
+    pub borrow_rate: u128,
+    pub supply_rate: u128,
+    pub total_liquidity: u128,
+    pub total_borrowed: u128, // Principal + accrued interest, variable and term
+    pub collateral_price: u128,
+    pub debt_price: u128,
+    pub borrow_yield_index: u128,
+    pub supply_yield_index: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct RealizedApy {
+    pub window: u64,
+    pub covered: u64, // Seconds actually measured, less than `window` when history is shorter
+    pub borrow_apy: u128,
+    pub supply_apy: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    Sr25519,
+    Ed25519,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+pub struct OraclePublisher {
+    pub public_key: [u8; 32],
+    pub scheme: SignatureScheme,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct PublisherSignature {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct OracleConfig {
+    pub publishers: Vec<OraclePublisher>,
+    pub threshold: u32,
+    pub collateral_price_updated_at: u64,
+    pub debt_price_updated_at: u64,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriceObservation {
+    pub timestamp: u64, // The prices hold from here until the next observation
+    pub collateral_price: u128,
+    pub debt_price: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct Twap {
+    pub window: u64,
+    pub collateral_price: u128,
+    pub debt_price: u128,
+    pub covered: u64, // Seconds of the window with price history; spot prices when 0
+    pub spot_collateral_price: u128,
+    pub spot_debt_price: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiquidationMode {
+    Instant, // `liquidate` seizes the whole position into liquidity
+    Auction, // Unhealthy positions are kicked into Dutch auctions
+    Dex,     // `liquidate` sells the collateral for TVARA through the configured DEX
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct Auction {
+    pub id: u64,
+    pub user: ActorId,
+    pub kicker: ActorId,
+    pub collateral: u128, // VARA left to sell
+    pub debt: u128,       // TVARA still to recover
+    pub initial_collateral: u128,
+    pub initial_debt: u128,
+    pub started_at: u64, // Reset when an unsold auction restarts
+    pub restarts: u32,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct DexSwap {
+    pub id: u64,
+    pub user: ActorId,
+    pub liquidator: ActorId,
+    pub collateral: u128, // VARA sent to the DEX
+    pub debt: u128,       // TVARA to burn from the proceeds
+    pub min_out: u128,    // Oracle value of the collateral less the max slippage
+    pub started_at: u64,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct DexConfig {
+    pub dex: Option<ActorId>,
+    pub max_slippage_bps: u128,
+    pub pending_swaps: u32,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct AuctionStatus {
+    pub auction: Auction,
+    pub discount: u128,             // Current collateral discount (WAD)
+    pub expired: bool, // Reached the max discount; `settle_auction` restarts or writes it off
+    pub full_take_cost: u128, // TVARA a bidder would pay now to take as much as possible
+    pub full_take_collateral: u128, // VARA that payment would buy
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuctionOutcome {
+    Covered,    // Debt fully recovered, leftover collateral returned to the borrower
+    BadDebt,    // Collateral ran out first, the remaining debt was written off
+    Restarted,  // Expired unsold, back in the auction at the start discount
+    WrittenOff, // Expired unsold too often; collateral kept as liquidity, the shortfall written off
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepositTier {
+    Days30,
+    Days90,
+    Days180,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct TermDeposit {
+    pub id: u64,
+    pub lender: ActorId,
+    pub amount: u128, // TVARA units
+    pub tier: DepositTier,
+    pub boost: u128, // Multiplier (percent) on the deposit's interest share, fixed at deposit
+    pub deposited_at: u64,
+    pub unlocks_at: u64,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct TermDepositInfo {
+    pub deposit: TermDeposit,
+    pub matured: bool,
+    pub early_withdrawal_penalty: u128, // Kept if withdrawn now; 0 once matured
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct DepositTierInfo {
+    pub tier: DepositTier,
+    pub lockup_days: u32,
+    pub boost: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct TermLoan {
+    pub id: u64,
+    pub borrower: ActorId,
+    pub principal: u128,        // Outstanding TVARA principal
+    pub rate: u128,             // Yearly rate (WAD) locked at origination
+    pub accrued_interest: u128, // Settled from collateral once the principal is repaid
+    pub originated_at: u64,
+    pub maturity: u64,
+    pub grace_ends_at: u64, // Past this the loan can be liquidated whatever its health
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TermLoanStatus {
+    Active,
+    InGracePeriod,
+    Overdue,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct TermLoanSchedule {
+    pub loan: TermLoan,
+    pub status: TermLoanStatus,
+    pub interest_at_maturity: u128, // Projected total interest if held until maturity
+    pub amount_due_at_maturity: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct WithdrawalRequest {
+    pub id: u64,
+    pub lender: ActorId,
+    pub amount: u128,    // TVARA amount requested
+    pub remaining: u128, // TVARA amount still to be paid out
+    pub queued_at: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct CollateralDeposited {
     pub user: ActorId,
     pub amount: u128,
+    pub new_collateral: u128,
+    pub new_health_factor: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct CollateralWithdrawn {
+    pub user: ActorId,
+    pub amount: u128,
+    pub new_collateral: u128,
+    pub new_health_factor: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct Borrowed {
     pub user: ActorId,
     pub amount: u128,
+    pub new_debt: u128, // Principal debt after the borrow
+    pub new_health_factor: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
@@ -61,44 +506,523 @@ pub struct Repaid {
     pub collateral_to_return: u128,
     pub interest_deducted: u128, // This is the interest paid from collateral
     pub debt_fully_paid: bool,   // This means principal debt is fully paid
+    pub new_collateral: u128,
+    pub new_debt: u128,
+    pub new_health_factor: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct Liquidated {
     pub user: ActorId,
+    pub liquidator: ActorId,
     pub collateral_sold: u128,
     pub debt_cleared: u128, // This should reflect total debt (principal + accrued interest)
+    pub term_loans_cleared: Vec<u64>,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct LiquidityProvided {
     pub lender: ActorId,
     pub amount: u128,
+    pub new_lender_balance: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct LiquidityWithdrawn {
     pub lender: ActorId,
     pub amount: u128,
+    pub new_lender_balance: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
 pub struct InterestClaimed {
     pub lender: ActorId,
     pub amount: u128,
+    pub timestamp: u64,
 }
 
 #[derive(Encode, TypeInfo, Clone)]
+pub struct InterestAccrued {
+    pub borrow_rate: u128,
+    pub elapsed: u64,         // Seconds since the previous accrual
+    pub total_interest: u128, // Total interest generated in this period
+    pub treasury_cut: u128,
+    pub new_treasury: u128,
+    // Totals only, so the event stays the same size however many positions are open
+    pub borrower_interest: u128,  // Added across variable-rate borrowers
+    pub term_loan_interest: u128, // Added across term loans
+    pub lender_interest: u128,    // Credited across lenders
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TermLoanOriginated {
+    pub loan_id: u64,
+    pub borrower: ActorId,
+    pub amount: u128,
+    pub rate: u128,
+    pub maturity: u64,
+    pub grace_ends_at: u64,
+    pub new_health_factor: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TermLoanRepaid {
+    pub loan_id: u64,
+    pub borrower: ActorId,
+    pub amount: u128,            // Principal repaid
+    pub interest_deducted: u128, // Collateral taken for interest when the loan closes
+    pub remaining_principal: u128,
+    pub closed: bool,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TermDepositCreated {
+    pub deposit_id: u64,
+    pub lender: ActorId,
+    pub amount: u128,
+    pub tier: DepositTier,
+    pub boost: u128,
+    pub unlocks_at: u64,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TermDepositWithdrawn {
+    pub deposit_id: u64,
+    pub lender: ActorId,
+    pub amount: u128,
+    pub early: bool,                                // Withdrawn before it unlocked
+    pub penalty: u128,                              // Kept from `amount` when early
+    pub penalty_distribution: Vec<(ActorId, u128)>, // Penalty credited to each remaining lender
+    pub vara_sent: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct PriceUpdated {
+    pub asset: Asset,
+    pub old_price: u128,
+    pub new_price: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct PauseChanged {
+    pub admin: ActorId,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct AdminFundsWithdrawn {
+    pub admin: ActorId,
+    pub amount: u128,    // TVARA amount taken from liquidity
+    pub vara_sent: u128, // VARA actually transferred
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TreasuryWithdrawn {
+    pub admin: ActorId,
+    pub amount: u128,    // TVARA amount taken from the treasury
+    pub vara_sent: u128, // VARA actually transferred
+    pub new_treasury: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
 pub struct UserInfo {
     pub collateral: u128,
     pub debt: u128, // This will be principal debt
     pub lender_balance: u128,
-    pub tvara_price: u128,
+    pub collateral_price: u128,
+    pub debt_price: u128,
     pub health_factor: u128,
     pub accrued_interest: u128, // Added for clarity, as interest is separate
     pub lender_interest_earned: u128, // New: Lender's earned interest
 }
 
+// Which account set a paginated view enumerates
+#[derive(Clone, Copy)]
+enum PositionKind {
+    Borrowers,
+    Lenders,
+    CollateralHolders,
+}
+
+// Optional filters for paginated views; `None` disables a filter
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, Default)]
+pub struct PositionFilter {
+    pub min_debt: Option<u128>, // Minimum principal + accrued interest
+    pub min_health_factor: Option<u128>,
+    pub max_health_factor: Option<u128>,
+    pub has_accrued_interest: Option<bool>,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct UserInfoPage {
+    pub items: Vec<(ActorId, UserInfo)>,
+    pub next_cursor: Option<ActorId>, // Pass as `start_after` to fetch the next page
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct LiquidationOpportunity {
+    pub user: ActorId,
+    pub health_factor: u128,
+    pub collateral: u128,
+    pub total_debt: u128,                 // Principal + accrued interest
+    pub max_repayable_debt: u128,         // Debt cleared by liquidating now
+    pub expected_collateral_seized: u128, // Collateral the liquidation takes at the current price
+    pub overdue_term_loan: bool,          // Liquidatable whatever its health
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct InvariantCheck {
+    pub name: String,
+    pub expected: u128,
+    pub actual: u128,
+    pub holds: bool,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct InvariantReport {
+    pub checks: Vec<InvariantCheck>,
+    pub all_hold: bool,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct WithdrawalQueued {
+    pub lender: ActorId,
+    pub request_id: u64,
+    pub amount: u128,
+    pub position: u32,      // Index in the queue, 0 = next to be filled
+    pub amount_ahead: u128, // Unfilled amount queued before this request
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct WithdrawalFilled {
+    pub lender: ActorId,
+    pub request_id: u64,
+    pub filled: u128,    // TVARA amount paid out by this fill
+    pub vara_sent: u128, // VARA actually transferred
+    pub remaining: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct CheckpointsConfigured {
+    pub admin: ActorId,
+    pub interval_blocks: u32,
+    pub gas_per_checkpoint: u64,
+    pub warning_threshold: u128,
+    pub epoch: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct CheckpointGasReserved {
+    pub admin: ActorId,
+    pub count: u32,
+    pub gas_per_checkpoint: u64,
+    pub reservations: u32, // Checkpoints now prepaid in total
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct CheckpointRun {
+    pub epoch: u64,
+    pub warnings: u32,
+    pub next_scheduled: bool,
+    pub reservations_left: u32,
+    pub timestamp: u64,
+}
+
+// No further checkpoint is scheduled for `epoch` until the admin reserves gas and reconfigures
+#[derive(Encode, TypeInfo, Clone)]
+pub struct CheckpointsStopped {
+    pub epoch: u64,
+    pub last_checkpoint: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct HealthWarning {
+    pub user: ActorId,
+    pub health_factor: u128,
+    pub collateral: u128,
+    pub total_debt: u128,
+    pub liquidatable: bool, // Already below the liquidation threshold
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct LiquidationModeChanged {
+    pub admin: ActorId,
+    pub mode: LiquidationMode,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct AuctionKicked {
+    pub auction_id: u64,
+    pub user: ActorId,
+    pub kicker: ActorId,
+    pub collateral: u128,
+    pub debt: u128, // Principal + accrued interest, including term loans
+    pub health_factor: u128,
+    pub term_loans_cleared: Vec<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct AuctionTaken {
+    pub auction_id: u64,
+    pub bidder: ActorId,
+    pub paid: u128, // TVARA burned for the collateral
+    pub collateral_bought: u128,
+    pub discount: u128,
+    pub refunded: u128, // TVARA minted back when the bid exceeded what was left
+    pub remaining_collateral: u128,
+    pub remaining_debt: u128,
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct AuctionSettled {
+    pub auction_id: u64,
+    pub user: ActorId,
+    pub outcome: AuctionOutcome,
+    pub collateral_returned: u128,
+    pub bad_debt: u128, // Written off by this settlement
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct OraclePublishersChanged {
+    pub admin: ActorId,
+    pub publishers: u32,
+    pub threshold: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct SignedPriceAccepted {
+    pub relayer: ActorId,
+    pub asset: Asset,
+    pub price: u128,
+    pub signed_at: u64,
+    pub signers: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct RateCheckpointPeriodChanged {
+    pub admin: ActorId,
+    pub old_period: u64,
+    pub new_period: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TwapWindowChanged {
+    pub admin: ActorId,
+    pub old_window: u64,
+    pub new_window: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct DexConfigured {
+    pub admin: ActorId,
+    pub dex: ActorId,
+    pub max_slippage_bps: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct DexLiquidationStarted {
+    pub swap_id: u64,
+    pub user: ActorId,
+    pub liquidator: ActorId,
+    pub collateral: u128,
+    pub debt: u128, // Principal + accrued interest, including term loans
+    pub min_out: u128,
+    pub health_factor: u128,
+    pub term_loans_cleared: Vec<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct DexLiquidationSettled {
+    pub swap_id: u64,
+    pub user: ActorId,
+    pub proceeds: u128,    // TVARA the DEX returned
+    pub debt_burned: u128, // Burned from the proceeds
+    pub surplus: u128,     // Sent to the borrower
+    pub bad_debt: u128,    // Debt the proceeds did not cover
+    pub new_liquidity: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct DexLiquidationFailed {
+    pub swap_id: u64,
+    pub user: ActorId,
+    pub auction_id: u64, // The refunded collateral is auctioned instead
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct KeeperRewarded {
+    pub keeper: ActorId,
+    pub elapsed: u64,    // Seconds since the previous accrual
+    pub bounty: u128,    // TVARA amount taken from the treasury
+    pub vara_sent: u128, // VARA actually transferred
+    pub new_treasury: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct PositionsPoked {
+    pub keeper: ActorId,
+    pub positions: Vec<(ActorId, u128)>, // Health factor of each refreshed position
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct InvariantChecksChanged {
+    pub enabled: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TreasuryBeneficiariesChanged {
+    pub admin: ActorId,
+    pub beneficiaries: Vec<TreasuryBeneficiary>,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct ShutdownTriggered {
+    pub admin: ActorId,
+    pub status: ShutdownStatus,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct ShutdownClaimed {
+    pub user: ActorId,
+    pub collateral: u128,
+    pub lender_share: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct TreasuryPaidOut {
+    pub beneficiary: ActorId,
+    pub amount: u128,    // TVARA amount taken from the treasury
+    pub vara_sent: u128, // VARA actually transferred
+    pub total_distributed: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct FeesChanged {
+    pub admin: ActorId,
+    pub origination_fee_bps: u128,
+    pub liquidation_fee_bps: u128,
+    pub withdrawal_fee_bps: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct FeeCharged {
+    pub kind: FeeKind,
+    pub user: ActorId, // Whose borrow, liquidation or withdrawal paid it
+    pub amount: u128,  // Debt-token units credited to the treasury
+    pub new_treasury: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct RewardsConfigured {
+    pub admin: ActorId,
+    pub token: ActorId,
+    pub payout: RewardPayout,
+    pub supply_rate: u128,
+    pub borrow_rate: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct RewardsClaimed {
+    pub user: ActorId,
+    pub token: ActorId,
+    pub amount: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+pub struct AccountHistoryChanged {
+    pub enabled: bool,
+    pub timestamp: u64,
+}
+
+// A lender's queued request with its place in the queue
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct WithdrawalQueuePosition {
+    pub request: WithdrawalRequest,
+    pub position: u32,
+    pub amount_ahead: u128,
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct CheckpointConfig {
+    pub interval_blocks: u32,
+    pub gas_per_checkpoint: u64,
+    pub warning_threshold: u128,
+    pub epoch: u64,
+    pub reservations: u32,
+    pub last_checkpoint: u64,
+}
+
+// Gas reserved for a future checkpoint, usable until block `expires_at`
+#[derive(Clone, Copy, Debug)]
+pub struct CheckpointReservation {
+    pub id: ReservationId,
+    pub expires_at: u32,
+}
+
+// Outcome of a simulated action, computed after pending interest is accrued
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct ActionPreview {
+    pub amount: u128, // Amount the action would move (deposited, borrowed, repaid, withdrawn or seized)
+    pub collateral: u128,
+    pub debt: u128, // Principal debt
+    pub accrued_interest: u128,
+    pub health_factor: u128,
+    pub collateral_returned: u128, // VARA sent back to the user
+    pub liquidity: u128,
+    pub violation: Option<String>, // Rule the action would break; `None` if it would succeed
+}
+
 #[derive(Encode, TypeInfo)]
 pub enum LendingEvent {
     CollateralDeposited(CollateralDeposited),
@@ -108,6 +1032,49 @@ pub enum LendingEvent {
     LiquidityProvided(LiquidityProvided),
     LiquidityWithdrawn(LiquidityWithdrawn),
     InterestClaimed(InterestClaimed), // New event
+    CollateralWithdrawn(CollateralWithdrawn),
+    InterestAccrued(InterestAccrued),
+    PriceUpdated(PriceUpdated),
+    Paused(PauseChanged),
+    Resumed(PauseChanged),
+    AdminFundsWithdrawn(AdminFundsWithdrawn),
+    TreasuryWithdrawn(TreasuryWithdrawn),
+    InvariantChecksChanged(InvariantChecksChanged),
+    WithdrawalQueued(WithdrawalQueued),
+    WithdrawalPartiallyFilled(WithdrawalFilled),
+    WithdrawalCompleted(WithdrawalFilled),
+    TermLoanOriginated(TermLoanOriginated),
+    TermLoanRepaid(TermLoanRepaid),
+    TermDepositCreated(TermDepositCreated),
+    TermDepositWithdrawn(TermDepositWithdrawn),
+    CheckpointsConfigured(CheckpointsConfigured),
+    CheckpointGasReserved(CheckpointGasReserved),
+    CheckpointRun(CheckpointRun),
+    CheckpointsStopped(CheckpointsStopped),
+    HealthWarning(HealthWarning),
+    KeeperRewarded(KeeperRewarded),
+    PositionsPoked(PositionsPoked),
+    LiquidationModeChanged(LiquidationModeChanged),
+    AuctionKicked(AuctionKicked),
+    AuctionTaken(AuctionTaken),
+    AuctionSettled(AuctionSettled),
+    DexConfigured(DexConfigured),
+    DexLiquidationStarted(DexLiquidationStarted),
+    DexLiquidationSettled(DexLiquidationSettled),
+    DexLiquidationFailed(DexLiquidationFailed),
+    TwapWindowChanged(TwapWindowChanged),
+    OraclePublishersChanged(OraclePublishersChanged),
+    SignedPriceAccepted(SignedPriceAccepted),
+    RateCheckpointPeriodChanged(RateCheckpointPeriodChanged),
+    AccountHistoryChanged(AccountHistoryChanged),
+    RewardsConfigured(RewardsConfigured),
+    RewardsClaimed(RewardsClaimed),
+    FeesChanged(FeesChanged),
+    FeeCharged(FeeCharged),
+    TreasuryBeneficiariesChanged(TreasuryBeneficiariesChanged),
+    TreasuryPaidOut(TreasuryPaidOut),
+    ShutdownTriggered(ShutdownTriggered),
+    ShutdownClaimed(ShutdownClaimed),
 }
 
 pub struct LendingService(());
@@ -134,11 +1101,15 @@ impl LendingService {
     }
 }
 
+// The exported state layout (v4). Operational settings travel with it, but no checkpoint is
+// scheduled on import: `configure_checkpoints` restarts the chain.
 #[derive(Clone, Debug, Encode, Decode, TypeInfo)]
 pub struct ContractState {
+    pub storage_version: u32,
     pub vft_address: ActorId,
     pub collateral: BTreeMap<ActorId, u128>,
-    pub tvara_price: u128,
+    pub collateral_price: u128,
+    pub debt_price: u128,
     pub debt: BTreeMap<ActorId, u128>,
     pub lender_balances: BTreeMap<ActorId, u128>,
     pub lender_interest_earned: BTreeMap<ActorId, u128>,
@@ -150,14 +1121,45 @@ pub struct ContractState {
     pub total_interest_earned: u128,
     pub user_accrued_interest: BTreeMap<ActorId, u128>,
     pub total_principal_borrowed: u128,
+    pub bad_debt: u128,
+    pub oracle_publishers: BTreeMap<[u8; 32], SignatureScheme>,
+    pub oracle_threshold: u32,
+    pub reward_token: Option<ActorId>,
+    pub reward_payout: RewardPayout,
+    pub supply_reward_rate: u128,
+    pub borrow_reward_rate: u128,
+    pub supply_reward_index: u128,
+    pub borrow_reward_index: u128,
+    pub rewards_updated_at: u64,
+    pub reward_snapshots: BTreeMap<ActorId, (u128, u128)>,
+    pub unclaimed_rewards: BTreeMap<ActorId, u128>,
+    pub total_rewards_claimed: u128,
+    pub origination_fee_bps: u128,
+    pub liquidation_fee_bps: u128,
+    pub withdrawal_fee_bps: u128,
+    pub total_fees_collected: u128,
+    pub treasury_beneficiaries: Vec<TreasuryBeneficiary>,
+    pub treasury_distributed: BTreeMap<ActorId, u128>,
+    pub liquidation_mode: LiquidationMode,
+    pub dex_address: Option<ActorId>,
+    pub max_slippage_bps: u128,
+    pub twap_window: u64,
+    pub checkpoint_interval: u32,
+    pub checkpoint_gas: u64,
+    pub health_warning_threshold: u128,
+    pub rate_checkpoint_period: u64,
+    pub account_history_enabled: bool,
+    pub strict_invariants: bool,
 }
 
 impl From<&LendingStorage> for ContractState {
     fn from(storage: &LendingStorage) -> Self {
         Self {
+            storage_version: storage.storage_version,
             vft_address: storage.vft_address,
             collateral: storage.collateral.clone(),
-            tvara_price: storage.tvara_price,
+            collateral_price: storage.collateral_price,
+            debt_price: storage.debt_price,
             debt: storage.debt.clone(),
             lender_balances: storage.lender_balances.clone(),
             lender_interest_earned: storage.lender_interest_earned.clone(),
@@ -169,44 +1171,335 @@ impl From<&LendingStorage> for ContractState {
             total_interest_earned: storage.total_interest_earned,
             user_accrued_interest: storage.user_accrued_interest.clone(),
             total_principal_borrowed: storage.total_principal_borrowed,
+            bad_debt: storage.bad_debt,
+            oracle_publishers: storage.oracle_publishers.clone(),
+            oracle_threshold: storage.oracle_threshold,
+            reward_token: storage.reward_token,
+            reward_payout: storage.reward_payout,
+            supply_reward_rate: storage.supply_reward_rate,
+            borrow_reward_rate: storage.borrow_reward_rate,
+            supply_reward_index: storage.supply_reward_index,
+            borrow_reward_index: storage.borrow_reward_index,
+            rewards_updated_at: storage.rewards_updated_at,
+            reward_snapshots: storage.reward_snapshots.clone(),
+            unclaimed_rewards: storage.unclaimed_rewards.clone(),
+            total_rewards_claimed: storage.total_rewards_claimed,
+            origination_fee_bps: storage.origination_fee_bps,
+            liquidation_fee_bps: storage.liquidation_fee_bps,
+            withdrawal_fee_bps: storage.withdrawal_fee_bps,
+            total_fees_collected: storage.total_fees_collected,
+            treasury_beneficiaries: storage.treasury_beneficiaries.clone(),
+            treasury_distributed: storage.treasury_distributed.clone(),
+            liquidation_mode: storage.liquidation_mode,
+            dex_address: storage.dex_address,
+            max_slippage_bps: storage.max_slippage_bps,
+            twap_window: storage.twap_window,
+            checkpoint_interval: storage.checkpoint_interval,
+            checkpoint_gas: storage.checkpoint_gas,
+            health_warning_threshold: storage.health_warning_threshold,
+            rate_checkpoint_period: storage.rate_checkpoint_period,
+            account_history_enabled: storage.account_history_enabled,
+            strict_invariants: storage.strict_invariants,
         }
     }
 }
 
+impl From<ContractState> for LendingStorage {
+    fn from(state: ContractState) -> Self {
+        let total_lender_balances = state.lender_balances.values().sum();
+        let mut storage = Self {
+            storage_version: STORAGE_VERSION,
+            vft_address: state.vft_address,
+            decimals: AssetDecimals::default(),
+            collateral: state.collateral,
+            collateral_price: state.collateral_price,
+            debt_price: state.debt_price,
+            debt: state.debt,
+            lender_balances: state.lender_balances,
+            total_lender_balances,
+            lender_interest_earned: state.lender_interest_earned,
+            total_liquidity: state.total_liquidity,
+            treasury: state.treasury,
+            paused: state.paused,
+            reentrancy: false,
+            admin: state.admin,
+            last_accrual_ts: state.last_accrual_ts,
+            total_interest_earned: state.total_interest_earned,
+            user_accrued_interest: state.user_accrued_interest,
+            total_principal_borrowed: state.total_principal_borrowed,
+            health_index: BTreeSet::new(),
+            health_index_keys: BTreeMap::new(),
+            strict_invariants: state.strict_invariants,
+            withdrawal_queue: VecDeque::new(),
+            pending_withdrawals: BTreeMap::new(),
+            next_withdrawal_id: 0,
+            term_loans: BTreeMap::new(),
+            user_term_loans: BTreeMap::new(),
+            total_term_principal: 0,
+            next_term_loan_id: 0,
+            term_deposits: BTreeMap::new(),
+            lender_term_deposits: BTreeMap::new(),
+            total_term_deposits: 0,
+            next_term_deposit_id: 0,
+            checkpoint_interval: state.checkpoint_interval,
+            checkpoint_gas: state.checkpoint_gas,
+            health_warning_threshold: state.health_warning_threshold,
+            checkpoint_epoch: 0,
+            checkpoint_reservations: VecDeque::new(),
+            last_checkpoint_ts: 0,
+            liquidation_mode: state.liquidation_mode,
+            auctions: BTreeMap::new(),
+            next_auction_id: 0,
+            bad_debt: state.bad_debt,
+            dex_address: state.dex_address,
+            max_slippage_bps: state.max_slippage_bps,
+            dex_swaps: BTreeMap::new(),
+            next_dex_swap_id: 0,
+            price_observations: VecDeque::new(),
+            twap_window: state.twap_window,
+            oracle_publishers: state.oracle_publishers,
+            oracle_threshold: state.oracle_threshold,
+            collateral_price_updated_at: state.last_accrual_ts,
+            debt_price_updated_at: state.last_accrual_ts,
+            rate_history: VecDeque::new(),
+            rate_checkpoint_period: state.rate_checkpoint_period,
+            borrow_yield_index: 0,
+            supply_yield_index: 0,
+            account_history_enabled: state.account_history_enabled,
+            account_history: BTreeMap::new(),
+            next_account_history_id: 0,
+            reward_token: state.reward_token,
+            reward_payout: state.reward_payout,
+            supply_reward_rate: state.supply_reward_rate,
+            borrow_reward_rate: state.borrow_reward_rate,
+            supply_reward_index: state.supply_reward_index,
+            borrow_reward_index: state.borrow_reward_index,
+            rewards_updated_at: state.rewards_updated_at,
+            reward_snapshots: state.reward_snapshots,
+            unclaimed_rewards: state.unclaimed_rewards,
+            total_rewards_claimed: state.total_rewards_claimed,
+            origination_fee_bps: state.origination_fee_bps,
+            liquidation_fee_bps: state.liquidation_fee_bps,
+            withdrawal_fee_bps: state.withdrawal_fee_bps,
+            total_fees_collected: state.total_fees_collected,
+            treasury_beneficiaries: state.treasury_beneficiaries,
+            treasury_distributed: state.treasury_distributed,
+            shutdown: None,
+            shutdown_collateral_claims: BTreeMap::new(),
+            shutdown_lender_claims: BTreeMap::new(),
+        };
+        // Price history starts at the imported prices
+        storage.record_price_observation(storage.last_accrual_ts);
+        // The health index is derived data, rebuild it from the imported positions
+        let borrowers: Vec<ActorId> = storage.debt.keys().cloned().collect();
+        for user in borrowers {
+            storage.reindex_health(user);
+        }
+        storage
+    }
+}
+
 #[service(events = LendingEvent)]
 impl LendingService {
-    pub async fn init(vft_address: ActorId) -> Self {
+    // `debt_decimals` overrides the VFT's own metadata, which is queried when it's `None`
+    pub async fn init(vft_address: ActorId, debt_decimals: Option<u8>) -> Self {
+        let debt_decimals = match debt_decimals {
+            Some(decimals) => decimals,
+            None => Self::fetch_decimals(vft_address).await,
+        };
         unsafe {
-            STORAGE = Some(LendingStorage {
+            STORAGE = Some(LendingStorage::new(
                 vft_address,
-                tvara_price: DEFAULT_TVARA_PRICE,
-                collateral: BTreeMap::new(),
-                debt: BTreeMap::new(),
-                lender_balances: BTreeMap::new(),
-                lender_interest_earned: BTreeMap::new(), // Initialize new map
-                total_liquidity: 0,
-                treasury: 0,
-                paused: false,
-                reentrancy: false,
-                admin: msg::source(),
-                last_accrual_ts: block_timestamp(),
-                total_interest_earned: 0,
-                user_accrued_interest: BTreeMap::new(),
-                total_principal_borrowed: 0, // Initialize new field
-            });
+                msg::source(),
+                current_timestamp(),
+                AssetDecimals::new(VARA_DECIMALS, debt_decimals),
+            ));
         }
         Self(())
     }
 
-    fn get_price(storage: &LendingStorage) -> u128 {
-        storage.tvara_price
+    async fn fetch_decimals(vft_address: ActorId) -> u8 {
+        let decimals_call = vft_io::Decimals::encode_call();
+        let reply =
+            msg::send_bytes_with_gas_for_reply(vft_address, decimals_call, 5_000_000_000, 0, 0)
+                .expect("Decimals call failed")
+                .await
+                .expect("Decimals query failed");
+        vft_io::Decimals::decode_reply(reply).expect("Invalid decimals reply")
     }
 
-    pub fn update_tvara_price(&mut self, new_price: u128) {
-        let storage = self.get_mut();
-        assert_eq!(msg::source(), storage.admin, "Only admin can update price");
-        assert!(new_price > 0, "Price must be positive");
-        storage.tvara_price = new_price;
+    fn context() -> Context {
+        Context {
+            caller: msg::source(),
+            value: msg::value(),
+            timestamp: current_timestamp(),
+            block_height: exec::block_height(),
+            balance: spendable_balance(),
+            program: exec::program_id(),
+        }
+    }
+
+    // Runs `f` against the pure pool core and returns its result with the effects to execute
+    fn run<R>(&mut self, f: impl FnOnce(&mut Pool) -> R) -> (R, Vec<Effect>) {
+        let mut pool = Pool::new(self.get_mut(), Self::context());
+        let res = f(&mut pool);
+        (res, pool.into_effects())
+    }
+
+    fn apply_effect(&mut self, effect: Effect) {
+        match effect {
+            Effect::Transfer { to, amount } => {
+                let sent = msg::send(to, (), amount);
+                assert!(sent.is_ok(), "VARA transfer failed");
+            }
+            Effect::Event(event) => {
+                let _ = self.emit_event(event);
+            }
+            Effect::ScheduleCheckpoint {
+                epoch,
+                delay,
+                reservation,
+            } => self.schedule_checkpoint(epoch, delay, reservation),
+            Effect::ReserveCheckpointGas { gas, duration } => {
+                let id = ReservationId::reserve(gas, duration).expect("Gas reservation failed");
+                self.get_mut()
+                    .checkpoint_reservations
+                    .push_back(CheckpointReservation {
+                        id,
+                        expires_at: exec::block_height().saturating_add(duration),
+                    });
+            }
+            Effect::Mint { .. }
+            | Effect::Burn { .. }
+            | Effect::TokenTransfer { .. }
+            | Effect::Reward { .. } => panic!("Token calls require an async entrypoint"),
+        }
+    }
+
+    // Sends the program a delayed `checkpoint` call, paid from `reservation` or, without one,
+    // from the current message's gas
+    fn schedule_checkpoint(&mut self, epoch: u64, delay: u32, reservation: Option<ReservationId>) {
+        let payload = checkpoint_payload(epoch);
+        let sent = match reservation {
+            Some(reservation) => msg::send_bytes_delayed_from_reservation(
+                reservation,
+                exec::program_id(),
+                &payload,
+                0,
+                delay,
+            ),
+            None => msg::send_bytes_with_gas_delayed(
+                exec::program_id(),
+                payload,
+                self.get().checkpoint_gas,
+                0,
+                delay,
+            ),
+        };
+        assert!(sent.is_ok(), "Checkpoint scheduling failed");
+    }
+
+    fn apply_effects(&mut self, effects: Vec<Effect>) {
+        for effect in effects {
+            self.apply_effect(effect);
+        }
+    }
+
+    async fn apply_effects_async(&mut self, effects: Vec<Effect>) {
+        let vft_address = self.get().vft_address;
+        for effect in effects {
+            match effect {
+                Effect::Mint { to, amount } => {
+                    let mint_call = vft_io::Mint::encode_call(to, amount.into());
+                    msg::send_bytes_with_gas_for_reply(vft_address, mint_call, 5_000_000_000, 0, 0)
+                        .expect("Mint call failed")
+                        .await
+                        .expect("Mint failed");
+                }
+                Effect::Burn { from, amount } => {
+                    let burn_call = vft_io::Burn::encode_call(from, amount.into());
+                    msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
+                        .expect("Burn call failed")
+                        .await
+                        .expect("Burn failed");
+                }
+                Effect::TokenTransfer { to, amount } => {
+                    let transfer_call = vft_io::Transfer::encode_call(to, amount.into());
+                    msg::send_bytes_with_gas_for_reply(
+                        vft_address,
+                        transfer_call,
+                        5_000_000_000,
+                        0,
+                        0,
+                    )
+                    .expect("Transfer call failed")
+                    .await
+                    .expect("Transfer failed");
+                }
+                Effect::Reward {
+                    token,
+                    to,
+                    amount,
+                    payout,
+                } => {
+                    let call = match payout {
+                        RewardPayout::Mint => vft_io::Mint::encode_call(to, amount.into()),
+                        RewardPayout::Transfer => vft_io::Transfer::encode_call(to, amount.into()),
+                    };
+                    msg::send_bytes_with_gas_for_reply(token, call, 5_000_000_000, 0, 0)
+                        .expect("Reward payout call failed")
+                        .await
+                        .expect("Reward payout failed");
+                }
+                effect => self.apply_effect(effect),
+            }
+        }
+    }
+
+    pub fn update_collateral_price(&mut self, new_price: u128) {
+        let ((), effects) = self.run(|pool| pool.update_price(Asset::Collateral, new_price));
+        self.apply_effects(effects);
+    }
+
+    pub fn update_debt_price(&mut self, new_price: u128) {
+        let ((), effects) = self.run(|pool| pool.update_price(Asset::Debt, new_price));
+        self.apply_effects(effects);
+    }
+
+    // Relays a price signed off-chain by the oracle publishers; open to anyone
+    pub fn submit_signed_price(
+        &mut self,
+        asset: Asset,
+        price: u128,
+        timestamp: u64,
+        signatures: Vec<PublisherSignature>,
+    ) {
+        let ((), effects) =
+            self.run(|pool| pool.submit_signed_price(asset, price, timestamp, signatures));
+        self.apply_effects(effects);
+    }
+
+    // Replaces the publisher set and the number of distinct signatures an update needs
+    pub fn set_oracle_publishers(&mut self, publishers: Vec<OraclePublisher>, threshold: u32) {
+        let ((), effects) = self.run(|pool| pool.set_oracle_publishers(publishers, threshold));
+        self.apply_effects(effects);
+    }
+
+    pub fn get_oracle_config(&self) -> OracleConfig {
+        let storage = self.get();
+        OracleConfig {
+            publishers: storage
+                .oracle_publishers
+                .iter()
+                .map(|(public_key, scheme)| OraclePublisher {
+                    public_key: *public_key,
+                    scheme: *scheme,
+                })
+                .collect(),
+            threshold: storage.oracle_threshold,
+            collateral_price_updated_at: storage.collateral_price_updated_at,
+            debt_price_updated_at: storage.debt_price_updated_at,
+        }
     }
 
     pub fn get_user_info(&self, user: ActorId) -> UserInfo {
@@ -216,13 +1509,13 @@ impl LendingService {
         let accrued_interest = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
         let lender_balance = *storage.lender_balances.get(&user).unwrap_or(&0);
         let lender_earned = *storage.lender_interest_earned.get(&user).unwrap_or(&0); // New
-        let price = storage.tvara_price;
 
         UserInfo {
             collateral,
             debt, // Principal debt
             lender_balance,
-            tvara_price: price,
+            collateral_price: storage.collateral_price,
+            debt_price: storage.debt_price,
             health_factor: if debt > 0 || accrued_interest > 0 {
                 // Check if any debt (principal or interest) exists
                 self.get_health_factor(user) // This function will now consider total debt
@@ -238,204 +1531,107 @@ impl LendingService {
         let storage = self.get();
         let collateral = *storage.collateral.get(&user).unwrap_or(&0);
         let debt = *storage.debt.get(&user).unwrap_or(&0); // This is principal debt
-        let price = storage.tvara_price;
-        // Convert TVARA collateral to value using price (for 18-decimal calculations)
-        let collateral_value = (collateral * price) / TVARA_UNIT;
+        let price = storage.collateral_price;
+        // Convert VARA collateral to value using price (for 18-decimal calculations)
+        let collateral_value =
+            storage
+                .pricing()
+                .value_of(Asset::Collateral, collateral, Rounding::Down);
 
         (collateral, debt, collateral_value, price)
     }
 
+    // A borrower's recorded operations, oldest first, starting after the entry with ID `cursor`
+    pub fn get_account_history(
+        &self,
+        user: ActorId,
+        cursor: Option<u64>,
+        limit: u32,
+    ) -> Vec<AccountHistoryEntry> {
+        let Some(history) = self.get().account_history.get(&user) else {
+            return Vec::new();
+        };
+        let start = match cursor {
+            Some(id) => history.partition_point(|entry| entry.id <= id),
+            None => 0,
+        };
+        history
+            .range(start..)
+            .take(limit.clamp(1, MAX_PAGE_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_account_history(&mut self, enabled: bool) {
+        let ((), effects) = self.run(|pool| pool.set_account_history(enabled));
+        self.apply_effects(effects);
+    }
+
     // Public view function to get utilization rate
     pub fn get_utilization_rate(&self) -> u128 {
-        Self::utilization_rate(self.get())
+        self.get().utilization_rate()
     }
 
-    fn utilization_rate(storage: &LendingStorage) -> u128 {
-        // When calculating utilization, we should consider all borrowed TVARA,
-        // which includes principal debt + currently outstanding accrued interest.
-        let borrowed_principal: u128 = storage.total_principal_borrowed; // Use the sum
-        let borrowed_interest: u128 = storage.user_accrued_interest.values().sum();
-        let total_borrowed = borrowed_principal + borrowed_interest;
-
-        let total = storage.total_liquidity + total_borrowed; // Total TVARA in the system (available + borrowed)
+    pub fn get_asset_decimals(&self) -> AssetDecimals {
+        self.get().decimals
+    }
 
-        if total == 0 {
-            0
-        } else {
-            // Use WAD for percentage calculations
-            (total_borrowed * WAD) / total
-        }
+    pub fn get_collateral_price(&self) -> u128 {
+        self.get().collateral_price
     }
 
-    pub fn get_tvara_price(&self) -> u128 {
-        self.get().tvara_price
+    pub fn get_debt_price(&self) -> u128 {
+        self.get().debt_price
     }
 
     // Public view function to get borrow rate per year
     pub fn get_borrow_rate_per_year(&self) -> u128 {
-        Self::borrow_rate_per_year(self.get())
+        self.get().borrow_rate_per_year()
     }
 
-    fn borrow_rate_per_year(storage: &LendingStorage) -> u128 {
-        let u = Self::utilization_rate(storage);
-        let r0 = (6 * WAD) / 100; // Base rate is 6% per year (total interest rate)
-        let rmax = (10 * WAD) / 100; // Max rate is 10% per year (total interest rate)
-        let u_opt = (8 * WAD) / 10; // Optimal utilization at 80%
-
-        if u <= u_opt {
-            r0 + (u * (rmax - r0)) / u_opt
-        } else {
-            // Linear interpolation beyond u_opt
-            // r0_prime = r0 + (u_opt * (rmax - r0)) / u_opt (rate at u_opt)
-            // r_slope_high = (rmax - r0_prime) / (WAD - u_opt)
-            // r_current = r0_prime + (u - u_opt) * r_slope_high
-            r0 + (u_opt * (rmax - r0)) / u_opt + ((u - u_opt) * (rmax - r0)) / (WAD - u_opt)
-        }
+    pub fn get_supply_rate_per_year(&self) -> u128 {
+        self.get().supply_rate_per_year()
     }
 
-    fn accrue_interest(&mut self) {
-        let now = block_timestamp();
-        let dt = now - self.get().last_accrual_ts;
-        if dt == 0 {
-            return;
-        }
-        let storage = self.get_mut();
-        storage.last_accrual_ts = now;
-
-        let rate = Self::borrow_rate_per_year(storage);
-        let sec_per_year = 365u128 * 24 * 3600;
-
-        // Iterate over principal debts to accrue interest
-        let users_with_debt: Vec<ActorId> = storage.debt.keys().cloned().collect();
-
-        // Calculate total new interest generated in this period
-        // This is based on total principal borrowed
-        let total_new_interest_generated =
-            (storage.total_principal_borrowed * rate * (dt as u128)) / sec_per_year / WAD;
-
-        if total_new_interest_generated > 0 {
-            // Distribute interest to treasury and lenders
-            let treasury_cut = (total_new_interest_generated * TREASURY_INTEREST_SHARE) / 100;
-            storage.treasury += treasury_cut;
-
-            let lender_share_total = (total_new_interest_generated * LENDER_INTEREST_SHARE) / 100;
-            storage.total_interest_earned += total_new_interest_generated; // This now tracks total interest generated
-
-            // Distribute lender share proportionally
-            if storage.total_liquidity > 0 && lender_share_total > 0 {
-                let lenders: Vec<ActorId> = storage.lender_balances.keys().cloned().collect();
-                for lender in lenders {
-                    if let Some(&balance) = storage.lender_balances.get(&lender) {
-                        if balance > 0 {
-                            let lender_share =
-                                (balance * lender_share_total) / storage.total_liquidity;
-                            *storage.lender_interest_earned.entry(lender).or_default() +=
-                                lender_share;
-                        }
-                    }
-                }
-            }
+    // Rate checkpoints in time order, starting after the checkpoint at `start_after`
+    pub fn get_rate_history(&self, start_after: Option<u64>, limit: u32) -> Vec<RateCheckpoint> {
+        let history = &self.get().rate_history;
+        let start = match start_after {
+            Some(timestamp) => history.partition_point(|c| c.timestamp <= timestamp),
+            None => 0,
+        };
+        history
+            .range(start..)
+            .take(limit.clamp(1, MAX_PAGE_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
 
-            // The 'accrued interest' on the borrower's side is the total interest generated
-            // that is applied to their debt.
-            // This part needs to be adjusted. The `total_new_interest_generated` is the *pool* of interest.
-            // We need to apply the specific borrower's interest to their debt.
-            // The 6% total interest is applied to the borrower's debt.
-            for user in users_with_debt {
-                if let Some(&debt_amount) = storage.debt.get(&user) {
-                    if debt_amount == 0 {
-                        continue;
-                    }
-                    let borrower_interest =
-                        (debt_amount * rate * (dt as u128)) / sec_per_year / WAD;
-                    if borrower_interest > 0 {
-                        *storage.user_accrued_interest.entry(user).or_default() +=
-                            borrower_interest;
-                    }
-                }
-            }
+    // Average yearly interest actually charged to borrowers and paid to lenders over the last
+    // `window` seconds, as far back as the history reaches
+    pub fn get_realized_apy(&self, window: u64) -> RealizedApy {
+        let (borrow_apy, supply_apy, covered) = self.get().realized_apy(window);
+        RealizedApy {
+            window,
+            covered,
+            borrow_apy,
+            supply_apy,
         }
     }
 
-    fn guard<F, R>(&mut self, f: F) -> R
-    where
-        F: FnOnce(&mut LendingStorage) -> R,
-    {
-        self.accrue_interest();
-        let storage = self.get_mut();
-        assert!(!storage.paused, "Protocol is paused");
-        assert!(!storage.reentrancy, "Reentrant call");
-        storage.reentrancy = true;
-        let res = f(storage);
-        storage.reentrancy = false;
-        res
+    pub fn set_rate_checkpoint_period(&mut self, period: u64) {
+        let ((), effects) = self.run(|pool| pool.set_rate_checkpoint_period(period));
+        self.apply_effects(effects);
     }
 
     pub fn deposit_collateral(&mut self) {
-        let amount = msg::value();
-        assert!(amount > 0, "Must send VARA tokens as collateral");
-        let user = msg::source();
-
-        self.guard(|storage| {
-            *storage.collateral.entry(user).or_default() += amount;
-        });
-
-        let _ = self.emit_event(LendingEvent::CollateralDeposited(CollateralDeposited {
-            user,
-            amount,
-        }));
+        let ((), effects) = self.run(|pool| pool.deposit_collateral());
+        self.apply_effects(effects);
     }
 
     pub async fn borrow(&mut self) {
-        let user = msg::source();
-        let (vft_address, mint_amount) = self.guard(|storage| {
-            let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
-            assert!(collateral_amount > 0, "No collateral deposited");
-
-            let price = Self::get_price(storage);
-            // Convert collateral to value for LTV calculations (18 decimal precision)
-            let collateral_value = (collateral_amount * price) / TVARA_UNIT;
-
-            let max_borrowable_value = (collateral_value * 100) / 150; // 150% LTV cap
-            let borrow_value = (collateral_value * 66) / 100; // ~66% safe LTV
-
-            // Convert borrow value back to TVARA units for debt tracking
-            let borrow_amount = (borrow_value * TVARA_UNIT) / price;
-
-            // When checking against max_borrowable, we need to sum principal debt AND accrued interest
-            let current_principal_debt = *storage.debt.get(&user).unwrap_or(&0);
-            let current_accrued_interest = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
-            let total_current_debt = current_principal_debt + current_accrued_interest;
-
-            let max_borrowable = (max_borrowable_value * TVARA_UNIT) / price;
-
-            assert!(
-                total_current_debt + borrow_amount <= max_borrowable,
-                "Exceeds maximum LTV ratio"
-            );
-            assert!(
-                borrow_amount <= storage.total_liquidity,
-                "Insufficient liquidity"
-            );
-
-            // Store new debt as principal
-            *storage.debt.entry(user).or_default() += borrow_amount;
-            storage.total_principal_borrowed += borrow_amount; // Update total principal borrowed
-            storage.total_liquidity -= borrow_amount;
-
-            (storage.vft_address, borrow_amount)
-        });
-
-        let mint_call = vft_io::Mint::encode_call(user, mint_amount.into());
-        msg::send_bytes_with_gas_for_reply(vft_address, mint_call, 5_000_000_000, 0, 0)
-            .expect("Mint call failed")
-            .await
-            .expect("Mint failed");
-
-        let _ = self.emit_event(LendingEvent::Borrowed(Borrowed {
-            user,
-            amount: mint_amount,
-        }));
+        let (_, effects) = self.run(|pool| pool.borrow());
+        self.apply_effects_async(effects).await;
     }
 
     // Repay function: 'amount' repays principal. Interest is deducted from collateral when principal is 0.
@@ -443,273 +1639,265 @@ impl LendingService {
         let vft_address = self.get().vft_address;
         let burn_call = vft_io::Burn::encode_call(user, amount.into());
 
+        // Burn before touching state so a failed burn leaves the position untouched
         msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
             .expect("Burn call failed")
             .await
             .expect("VFT burn failed - insufficient VFT balance");
 
-        let (collateral_to_return, debt_fully_paid, interest_deducted) = self.guard(|storage| {
-            let principal_debt_entry = storage.debt.entry(user).or_default();
-            let accrued_interest = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
-
-            let amount_repaid_principal = core::cmp::min(amount, *principal_debt_entry);
-
-            *principal_debt_entry -= amount_repaid_principal;
-            storage.total_principal_borrowed -= amount_repaid_principal; // Update total principal borrowed
-            storage.total_liquidity += amount_repaid_principal; // Principal repaid returns to liquidity
-
-            let mut collateral_to_return_val = 0;
-            let mut interest_deducted_val = 0;
-            let principal_debt_is_zero = *principal_debt_entry == 0;
-
-            // Only if principal debt is fully paid, handle interest deduction from collateral
-            if principal_debt_is_zero {
-                let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
-
-                if collateral_amount >= accrued_interest {
-                    // Collateral is sufficient to cover all accrued interest
-                    collateral_to_return_val = collateral_amount - accrued_interest;
-                    interest_deducted_val = accrued_interest;
-                    // Note: Treasury and lenders already got their share via accrue_interest.
-                    // This is simply the transfer from collateral to cover the accrued interest.
-                } else {
-                    // Collateral is less than accrued interest, deduct all available collateral
-                    interest_deducted_val = collateral_amount;
-                    collateral_to_return_val = 0;
-                }
-
-                // Clean up user's entries after full principal repayment and interest settlement
-                storage.collateral.remove(&user);
-                storage.debt.remove(&user); // Principal debt is zero, so remove entry
-                storage.user_accrued_interest.remove(&user); // Accrued interest has been settled
-            }
+        let ((), effects) = self.run(|pool| pool.repay(user, amount));
+        self.apply_effects(effects);
+    }
 
-            (
-                collateral_to_return_val,
-                principal_debt_is_zero,
-                interest_deducted_val,
-            )
-        });
+    // Borrows a fixed `amount` for `term_days` at the current rate, locked for the loan's life.
+    // Returns the loan ID.
+    pub async fn borrow_term(&mut self, amount: u128, term_days: u32) -> u64 {
+        let (loan_id, effects) = self.run(|pool| pool.borrow_term(amount, term_days));
+        self.apply_effects_async(effects).await;
+        loan_id
+    }
 
-        if debt_fully_paid && collateral_to_return > 0 {
-            let sent = msg::send(user, (), collateral_to_return);
-            assert!(sent.is_ok(), "Collateral return failed");
-        }
+    pub async fn repay_term_loan(&mut self, loan_id: u64, amount: u128) {
+        let vft_address = self.get().vft_address;
+        let loan = self
+            .get()
+            .term_loans
+            .get(&loan_id)
+            .expect("Term loan not found");
+        let borrower = loan.borrower;
+        // Only burn what the loan can absorb; interest is settled from collateral, not tokens
+        let amount = amount.min(loan.principal);
+        let burn_call = vft_io::Burn::encode_call(borrower, amount.into());
+
+        // Burn before touching state so a failed burn leaves the loan untouched
+        msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
+            .expect("Burn call failed")
+            .await
+            .expect("VFT burn failed - insufficient VFT balance");
 
-        let _ = self.emit_event(LendingEvent::Repaid(Repaid {
-            user,
-            amount, // This is the total VFT amount sent by user
-            collateral_to_return,
-            interest_deducted,
-            debt_fully_paid, // Indicates if principal debt reached zero
-        }));
+        let ((), effects) = self.run(|pool| pool.repay_term_loan(loan_id, amount));
+        self.apply_effects(effects);
     }
 
     // Additional function for partial collateral withdrawal
     pub fn withdraw_collateral(&mut self, user: ActorId, amount: u128) {
-        let collateral_to_return = self.guard(|storage| {
-            let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
-            let principal_debt_amount = *storage.debt.get(&user).unwrap_or(&0);
-            let accrued_interest_amount = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
-
-            assert!(collateral_amount >= amount, "Insufficient collateral");
-
-            let remaining_collateral = collateral_amount - amount;
+        let ((), effects) = self.run(|pool| pool.withdraw_collateral(user, amount));
+        self.apply_effects(effects);
+    }
 
-            // If there's any outstanding debt (principal or interest), check LTV
-            if principal_debt_amount > 0 || accrued_interest_amount > 0 {
-                let price = Self::get_price(storage);
-                let remaining_collateral_value = (remaining_collateral * price) / TVARA_UNIT;
-                let total_current_debt_value =
-                    ((principal_debt_amount + accrued_interest_amount) * price) / TVARA_UNIT;
+    pub async fn lend(&mut self) {
+        let ((), effects) = self.run(|pool| pool.lend());
+        self.apply_effects_async(effects).await;
+    }
 
-                let max_allowed_debt_value = (remaining_collateral_value * 100) / 150;
+    pub async fn withdraw(&mut self, amount: u128) {
+        let ((), effects) = self.run(|pool| pool.withdraw(amount));
+        self.apply_effects_async(effects).await;
+    }
 
-                assert!(
-                    total_current_debt_value <= max_allowed_debt_value,
-                    "Withdrawal would exceed LTV ratio"
-                );
-            }
+    // Queues a withdrawal that is paid out as liquidity comes back, oldest request first.
+    // The VFT for the whole amount is burned up front.
+    pub async fn request_withdrawal(&mut self, amount: u128) -> u64 {
+        // Check the request will be accepted before burning anything
+        let ((), effects) = self.run(|pool| pool.prepare_request_withdrawal(amount));
+        self.apply_effects(effects);
+        let vft_address = self.get().vft_address;
+        let burn_call = vft_io::Burn::encode_call(msg::source(), amount.into());
 
-            if remaining_collateral == 0 {
-                storage.collateral.remove(&user);
-            } else {
-                *storage.collateral.get_mut(&user).unwrap() = remaining_collateral;
-            }
+        // Burn before touching state so a failed burn leaves nothing queued or paid out
+        msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
+            .expect("Burn call failed")
+            .await
+            .expect("VFT burn failed - insufficient VFT balance");
 
-            amount
-        });
+        let (request_id, effects) = self.run(|pool| pool.request_withdrawal(amount));
+        self.apply_effects(effects);
+        request_id
+    }
 
-        let sent = msg::send(user, (), collateral_to_return);
-        assert!(sent.is_ok(), "Collateral withdrawal failed");
+    // Locks the attached VARA for the tier's lockup in exchange for a boosted share of lender
+    // interest. Returns the deposit ID.
+    pub async fn deposit_term(&mut self, tier: DepositTier) -> u64 {
+        let (deposit_id, effects) = self.run(|pool| pool.deposit_term(tier));
+        self.apply_effects_async(effects).await;
+        deposit_id
     }
 
-    pub async fn lend(&mut self) {
-        let lender = msg::source();
-        // accrue_interest is called by guard, no need to call it here explicitly
+    // Returns a term deposit's principal; before it unlocks a penalty is kept for other lenders
+    pub async fn withdraw_term_deposit(&mut self, deposit_id: u64) {
+        // Check the withdrawal can go through before burning anything
+        let (amount, effects) = self.run(|pool| pool.prepare_withdraw_term_deposit(deposit_id));
+        self.apply_effects(effects);
+        let vft_address = self.get().vft_address;
+        let burn_call = vft_io::Burn::encode_call(msg::source(), amount.into());
 
-        let amount = msg::value();
-        assert!(amount > 0, "Lend amount must be > 0");
+        // Burn before touching state so a failed burn leaves the deposit locked in place
+        msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
+            .expect("Burn call failed")
+            .await
+            .expect("VFT burn failed - insufficient VFT balance");
 
-        let (vft, amount_to_mint) = self.guard(|storage| {
-            *storage.lender_balances.entry(lender).or_default() += amount;
-            storage.total_liquidity += amount;
-            (storage.vft_address, amount)
-        });
+        let ((), effects) = self.run(|pool| pool.withdraw_term_deposit(deposit_id));
+        self.apply_effects(effects);
+    }
 
-        // Mint VFT tokens equivalent to `amount`
-        let mint_call = vft_io::Mint::encode_call(lender, amount_to_mint.into());
-        msg::send_bytes_with_gas_for_reply(vft, mint_call, 5_000_000_000, 0, 0)
-            .expect("Mint call failed")
-            .await
-            .expect("Mint failed");
+    // Serves queued withdrawals from idle liquidity; returns the number of fills made
+    pub fn process_withdrawal_queue(&mut self) -> u32 {
+        let (fills, effects) = self.run(|pool| pool.process_withdrawal_queue());
+        self.apply_effects(effects);
+        fills
+    }
 
-        let _ = self.emit_event(LendingEvent::LiquidityProvided(LiquidityProvided {
-            lender,
-            amount,
-        }));
+    // New function for lenders to claim earned interest separately
+    pub fn claim_interest(&mut self) {
+        let ((), effects) = self.run(|pool| pool.claim_interest());
+        self.apply_effects(effects);
     }
 
-    pub async fn withdraw(&mut self, amount: u128) {
-        let lender = msg::source();
-        // accrue_interest is called by guard, no need to call it here explicitly
+    // Pays out the caller's accrued liquidity mining rewards; returns the amount paid
+    pub async fn claim_rewards(&mut self) -> u128 {
+        let (claimed, effects) = self.run(|pool| pool.claim_rewards());
+        self.apply_effects_async(effects).await;
+        claimed
+    }
 
-        let (vft, amount_to_burn, earned_interest_to_withdraw) = self.guard(|storage| {
-            let bal = storage.lender_balances.entry(lender).or_default();
-            let earned_interest_bal = storage.lender_interest_earned.entry(lender).or_default();
+    pub async fn liquidate(&mut self, user: ActorId) {
+        if self.get().liquidation_mode != LiquidationMode::Dex {
+            let ((), effects) = self.run(|pool| pool.liquidate(user));
+            self.apply_effects(effects);
+            return;
+        }
 
-            assert!(*bal >= amount, "Insufficient principal balance to withdraw");
-            assert!(
-                storage.total_liquidity >= amount,
-                "Insufficient total liquidity for principal withdrawal"
-            );
+        // Seize the position before the swap so nothing can touch it while the DEX replies
+        let (swap, effects) = self.run(|pool| pool.start_dex_liquidation(user));
+        self.apply_effects(effects);
+        let dex = self.get().dex_address.expect("DEX is not configured");
+        let swap_call = dex_swap_payload(swap.min_out, exec::program_id());
+        let reply =
+            msg::send_bytes_with_gas_for_reply(dex, swap_call, DEX_SWAP_GAS, swap.collateral, 0)
+                .expect("DEX call failed")
+                .await;
+
+        // A failed swap, e.g. one that could not meet `min_out`, refunds the VARA it was sent.
+        // A successful one that can't be decoded has spent the VARA for an unknown amount, so
+        // it's neither settled nor auctioned and stays pending until `resolve_dex_swap`.
+        let (_, effects) = self.run(|pool| match reply {
+            Ok(reply) => {
+                let proceeds = decode_dex_swap_reply(&reply).expect("Invalid DEX swap reply");
+                pool.settle_dex_swap(swap.id, proceeds);
+                None
+            }
+            Err(_) => Some(pool.fail_dex_swap(swap.id)),
+        });
+        self.apply_effects_async(effects).await;
+    }
 
-            *bal -= amount;
-            storage.total_liquidity -= amount;
+    // Admin: closes a swap that stayed pending because its reply could not be booked, with the
+    // TVARA the program actually received for it (0 if none)
+    pub async fn resolve_dex_swap(&mut self, swap_id: u64, proceeds: u128) {
+        let ((), effects) = self.run(|pool| pool.resolve_dex_swap(swap_id, proceeds));
+        self.apply_effects_async(effects).await;
+    }
 
-            let earned_to_return = *earned_interest_bal;
-            *earned_interest_bal = 0; // Clear earned interest after withdrawal
+    // Moves an unhealthy position into a Dutch auction; returns the auction ID
+    pub fn kick_auction(&mut self, user: ActorId) -> u64 {
+        let (auction_id, effects) = self.run(|pool| pool.kick_auction(user));
+        self.apply_effects(effects);
+        auction_id
+    }
 
-            (storage.vft_address, amount, earned_to_return)
-        });
+    // Bids up to `amount` TVARA for an auction's collateral at the current discount, failing if
+    // it would buy less than `min_collateral` VARA. If another bid lands first and leaves it
+    // short of that, the burned TVARA is minted back. Returns the VARA bought.
+    pub async fn take(&mut self, auction_id: u64, amount: u128, min_collateral: u128) -> u128 {
+        let (pay, effects) = self.run(|pool| pool.prepare_take(auction_id, amount, min_collateral));
+        self.apply_effects(effects);
 
-        // Burn VFT tokens for the principal amount being withdrawn
-        let burn_call = vft_io::Burn::encode_call(lender, amount_to_burn.into());
-        msg::send_bytes_with_gas_for_reply(vft, burn_call, 5_000_000_000, 0, 0)
+        // Burn before touching state; anything the auction can no longer absorb is minted back
+        let vft_address = self.get().vft_address;
+        let burn_call = vft_io::Burn::encode_call(msg::source(), pay.into());
+        msg::send_bytes_with_gas_for_reply(vft_address, burn_call, 5_000_000_000, 0, 0)
             .expect("Burn call failed")
             .await
-            .expect("Burn failed");
+            .expect("VFT burn failed - insufficient VFT balance");
 
-        let total_amount_to_send = amount_to_burn + earned_interest_to_withdraw;
+        let (bought, effects) = self.run(|pool| pool.take(auction_id, pay, min_collateral));
+        self.apply_effects_async(effects).await;
+        bought
+    }
 
-        let sent = msg::send(lender, (), total_amount_to_send);
-        assert!(sent.is_ok(), "VARA transfer failed");
+    // Restarts an auction that reached its max discount without selling out, or writes it off
+    // once it has been restarted too often
+    pub fn settle_auction(&mut self, auction_id: u64) {
+        let ((), effects) = self.run(|pool| pool.settle_auction(auction_id));
+        self.apply_effects(effects);
+    }
 
-        let _ = self.emit_event(LendingEvent::LiquidityWithdrawn(LiquidityWithdrawn {
-            lender,
-            amount: amount_to_burn, // This event still refers to principal withdrawn
-        }));
+    pub fn set_liquidation_mode(&mut self, mode: LiquidationMode) {
+        let ((), effects) = self.run(|pool| pool.set_liquidation_mode(mode));
+        self.apply_effects(effects);
+    }
 
-        // Optionally, emit a separate event for interest withdrawal if desired
-        if earned_interest_to_withdraw > 0 {
-            let _ = self.emit_event(LendingEvent::InterestClaimed(InterestClaimed {
-                lender,
-                amount: earned_interest_to_withdraw,
-            }));
-        }
+    pub fn set_twap_window(&mut self, window: u64) {
+        let ((), effects) = self.run(|pool| pool.set_twap_window(window));
+        self.apply_effects(effects);
     }
 
-    // New function for lenders to claim earned interest separately
-    pub fn claim_interest(&mut self) {
-        let lender = msg::source();
-        let earned_interest_to_claim = self.guard(|storage| {
-            let earned_interest_bal = storage.lender_interest_earned.entry(lender).or_default();
-            let amount = *earned_interest_bal;
-            assert!(amount > 0, "No interest to claim");
-            *earned_interest_bal = 0; // Reset balance after claiming
-            amount
-        });
+    // Sets the DEX program liquidations swap through and how far below the oracle price a swap
+    // may fill
+    pub fn configure_dex(&mut self, dex: ActorId, max_slippage_bps: u128) {
+        let ((), effects) = self.run(|pool| pool.configure_dex(dex, max_slippage_bps));
+        self.apply_effects(effects);
+    }
 
-        let sent = msg::send(lender, (), earned_interest_to_claim);
-        assert!(sent.is_ok(), "Interest transfer failed");
-
-        let _ = self.emit_event(LendingEvent::InterestClaimed(InterestClaimed {
-            lender,
-            amount: earned_interest_to_claim,
-        }));
-    }
-
-    pub fn liquidate(&mut self, user: ActorId) {
-        let (collateral_cleared_amount, debt_cleared_amount) = self.guard(|storage| {
-            // 1. Get raw amounts from storage
-            let collateral_amount_vara = *storage.collateral.get(&user).unwrap_or(&0); // Collateral is VARA (12 decimals)
-            let principal_debt_amount_tvara = *storage.debt.get(&user).unwrap_or(&0); // Principal debt is TVARA (12 decimals)
-            let accrued_interest_amount_tvara =
-                *storage.user_accrued_interest.get(&user).unwrap_or(&0); // Accrued interest is TVARA (12 decimals)
-
-            // 2. Calculate total TVARA debt first
-            let total_debt_tvara = principal_debt_amount_tvara + accrued_interest_amount_tvara;
-
-            // 3. Assertions for valid state (before price calculations)
-            assert!(collateral_amount_vara > 0, "No collateral to liquidate");
-            assert!(total_debt_tvara > 0, "No debt to liquidate");
-
-            // 4. Get VARA's price from storage (assuming `storage.tvara_price` is now VARA's price in WAD)
-            let vara_price_in_wad = storage.tvara_price; // Access directly from storage within the guard
-
-            // 5. Convert amounts to a common 18-decimal USD value for health factor calculation
-            //    a. Collateral value in USD: (VARA amount * VARA price in WAD) / TVARA_UNIT (scales 12-decimal VARA to 18-decimal USD)
-            let collateral_value_usd = (collateral_amount_vara * vara_price_in_wad) / TVARA_UNIT;
-
-            //    b. Total debt value in USD: (TVARA amount * 1 USD/TVARA fixed price) / TVARA_UNIT (scales 12-decimal TVARA to 18-decimal USD)
-            //       Assuming 1 TVARA is fixed at 1 USD (or 1.0 * WAD).
-            let total_debt_value_usd = total_debt_tvara * (WAD / TVARA_UNIT);
-
-            // 6. Defensive check to prevent division by zero for health factor
-            assert!(
-                total_debt_value_usd > 0,
-                "Calculated total debt value is zero. Cannot liquidate if debt is zero."
-            );
-
-            // 7. Calculate Health Factor based on USD values
-            let health = (collateral_value_usd * 100) / total_debt_value_usd;
-
-            // 8. Assert liquidation condition
-            assert!(
-                health < 120,
-                "Position not eligible for liquidation: Health factor is >= 120"
-            );
-
-            // --- All checks passed, perform the actual state changes for liquidation ---
-            storage.collateral.remove(&user);
-            storage.debt.remove(&user);
-            storage.user_accrued_interest.remove(&user);
-            storage.total_principal_borrowed -= principal_debt_amount_tvara; // Update total principal borrowed
-            storage.total_liquidity += collateral_amount_vara; // Return VARA collateral to total liquidity
-
-            // 9. Return the raw amounts that need to be used in the event emission outside the closure
-            (collateral_amount_vara, total_debt_tvara) // Return VARA cleared and total TVARA debt cleared
-        });
+    // Sets the reward token (fixed once set) and the per-second emissions for each side
+    pub fn configure_rewards(
+        &mut self,
+        token: ActorId,
+        payout: RewardPayout,
+        supply_rate: u128,
+        borrow_rate: u128,
+    ) {
+        let ((), effects) =
+            self.run(|pool| pool.configure_rewards(token, payout, supply_rate, borrow_rate));
+        self.apply_effects(effects);
+    }
 
-        // 10. Emit the event using the values returned by the closure
-        let _ = self.emit_event(LendingEvent::Liquidated(Liquidated {
-            user,
-            collateral_sold: collateral_cleared_amount, // The amount of VARA collateral that was cleared
-            debt_cleared: debt_cleared_amount, // The total TVARA debt (principal + accrued interest) that was cleared
-        }));
+    pub fn set_fees(
+        &mut self,
+        origination_fee_bps: u128,
+        liquidation_fee_bps: u128,
+        withdrawal_fee_bps: u128,
+    ) {
+        let ((), effects) = self.run(|pool| {
+            pool.set_fees(origination_fee_bps, liquidation_fee_bps, withdrawal_fee_bps)
+        });
+        self.apply_effects(effects);
     }
 
     // Admin functions
     pub fn pause(&mut self) {
-        let storage = self.get_mut();
-        assert_eq!(msg::source(), storage.admin, "Only admin can pause");
-        storage.paused = true;
+        let ((), effects) = self.run(|pool| pool.pause());
+        self.apply_effects(effects);
     }
 
     pub fn resume(&mut self) {
-        let storage = self.get_mut();
-        assert_eq!(msg::source(), storage.admin, "Only admin can resume");
-        storage.paused = false;
+        let ((), effects) = self.run(|pool| pool.resume());
+        self.apply_effects(effects);
+    }
+
+    // Irreversible: settles every position at the given final prices and stops all operations
+    // except `claim_shutdown`
+    pub fn shutdown(&mut self, collateral_price: u128, debt_price: u128) {
+        let ((), effects) = self.run(|pool| pool.shutdown(collateral_price, debt_price));
+        self.apply_effects(effects);
+    }
+
+    // Pays the caller's excess collateral and lender share after a shutdown
+    pub fn claim_shutdown(&mut self) -> ShutdownClaim {
+        let (claim, effects) = self.run(|pool| pool.claim_shutdown());
+        self.apply_effects(effects);
+        claim
     }
 
     // View functions
@@ -722,12 +1910,9 @@ impl LendingService {
         *self.get().debt.get(&user).unwrap_or(&0)
     }
 
-    // New function to get the total outstanding debt (principal + accrued interest)
+    // Total outstanding debt: variable-rate principal and interest plus every open term loan
     pub fn get_total_outstanding_debt(&self, user: ActorId) -> u128 {
-        let storage = self.get();
-        let principal_debt = *storage.debt.get(&user).unwrap_or(&0);
-        let accrued_interest = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
-        principal_debt + accrued_interest
+        self.get().total_debt_of(&user)
     }
 
     pub fn get_liquidity(&self) -> u128 {
@@ -747,33 +1932,7 @@ impl LendingService {
     }
 
     pub fn get_health_factor(&self, user: ActorId) -> u128 {
-        let storage = self.get();
-        let collateral_amount_vara = *storage.collateral.get(&user).unwrap_or(&0); // Collateral is VARA (12 decimals)
-        let principal_debt_amount_tvara = *storage.debt.get(&user).unwrap_or(&0); // Debt is TVARA (12 decimals)
-        let accrued_interest_amount_tvara = *storage.user_accrued_interest.get(&user).unwrap_or(&0); // Accrued interest is TVARA (12 decimals)
-
-        let total_debt_tvara = principal_debt_amount_tvara + accrued_interest_amount_tvara;
-
-        if total_debt_tvara == 0 {
-            return u128::MAX; // Loan is perfectly healthy if no debt
-        }
-
-        let vara_price_in_wad = storage.tvara_price; // *** THIS IS NOW VARA's PRICE IN WAD (e.g., USD) ***
-
-        // Calculate the USD value of collateral:
-        // (VARA amount in 12 decimals * VARA price in 18 decimals) / TVARA_UNIT (to scale down to 18 decimals)
-        let collateral_value_usd = (collateral_amount_vara * vara_price_in_wad) / TVARA_UNIT;
-
-        // Calculate the USD value of total TVARA debt:
-        // Assuming 1 TVARA = 1 USD (or 1.0 * WAD) for its fixed price
-        let total_debt_value_usd = total_debt_tvara * (WAD / TVARA_UNIT); // e.g. 6.6 * 10^12 * 10^6 = 6.6 * 10^18
-
-        if total_debt_value_usd == 0 {
-            return u128::MAX;
-        }
-
-        // Health factor = (Collateral Value in USD * 100) / (Total Debt Value in USD)
-        (collateral_value_usd * 100) / total_debt_value_usd
+        self.get().health_factor(&user)
     }
 
     // Helper function for user's currently accrued interest
@@ -786,6 +1945,39 @@ impl LendingService {
         *self.get().lender_interest_earned.get(&user).unwrap_or(&0)
     }
 
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        let storage = self.get();
+        FeeSchedule {
+            origination_fee_bps: storage.origination_fee_bps,
+            liquidation_fee_bps: storage.liquidation_fee_bps,
+            withdrawal_fee_bps: storage.withdrawal_fee_bps,
+            max_fee_bps: MAX_FEE_BPS,
+            total_fees_collected: storage.total_fees_collected,
+        }
+    }
+
+    pub fn get_shutdown_status(&self) -> Option<ShutdownStatus> {
+        self.get().shutdown.clone()
+    }
+
+    // What `user` has left to claim after a shutdown
+    pub fn get_shutdown_claim(&self, user: ActorId) -> ShutdownClaim {
+        self.get().shutdown_claim_of(&user)
+    }
+
+    pub fn get_treasury_beneficiaries(&self) -> Vec<TreasuryBeneficiary> {
+        self.get().treasury_beneficiaries.clone()
+    }
+
+    // Total paid to each account by `distribute_treasury`, including former beneficiaries
+    pub fn get_treasury_distributions(&self) -> Vec<(ActorId, u128)> {
+        self.get()
+            .treasury_distributed
+            .iter()
+            .map(|(account, amount)| (*account, *amount))
+            .collect()
+    }
+
     pub fn get_treasury_balance(&self) -> u128 {
         self.get().treasury
     }
@@ -798,6 +1990,164 @@ impl LendingService {
         self.get().total_principal_borrowed
     }
 
+    pub fn get_user_term_loans(&self, user: ActorId) -> Vec<TermLoanSchedule> {
+        let storage = self.get();
+        let Some(ids) = storage.user_term_loans.get(&user) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .filter_map(|id| storage.term_loans.get(id))
+            .map(|loan| storage.term_loan_schedule(loan, current_timestamp()))
+            .collect()
+    }
+
+    pub fn get_term_loan(&self, loan_id: u64) -> Option<TermLoanSchedule> {
+        let storage = self.get();
+        storage
+            .term_loans
+            .get(&loan_id)
+            .map(|loan| storage.term_loan_schedule(loan, current_timestamp()))
+    }
+
+    pub fn get_term_deposits(&self, lender: ActorId) -> Vec<TermDepositInfo> {
+        let storage = self.get();
+        let Some(ids) = storage.lender_term_deposits.get(&lender) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .filter_map(|id| storage.term_deposits.get(id))
+            .map(|deposit| storage.term_deposit_info(deposit, current_timestamp()))
+            .collect()
+    }
+
+    pub fn get_term_deposit(&self, deposit_id: u64) -> Option<TermDepositInfo> {
+        let storage = self.get();
+        storage
+            .term_deposits
+            .get(&deposit_id)
+            .map(|deposit| storage.term_deposit_info(deposit, current_timestamp()))
+    }
+
+    pub fn get_total_term_deposits(&self) -> u128 {
+        self.get().total_term_deposits
+    }
+
+    pub fn get_deposit_tiers(&self) -> Vec<DepositTierInfo> {
+        DepositTier::ALL
+            .iter()
+            .map(|&tier| DepositTierInfo {
+                tier,
+                lockup_days: tier.lockup_days(),
+                boost: tier.boost(),
+            })
+            .collect()
+    }
+
+    pub fn get_auction(&self, auction_id: u64) -> Option<AuctionStatus> {
+        let storage = self.get();
+        storage
+            .auctions
+            .get(&auction_id)
+            .map(|auction| storage.auction_status(auction, current_timestamp()))
+    }
+
+    // Open auctions in ID order, starting after `start_after`
+    pub fn get_auctions(&self, start_after: Option<u64>, limit: u32) -> Vec<AuctionStatus> {
+        let storage = self.get();
+        let lower = match start_after {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+        storage
+            .auctions
+            .range((lower, Bound::Unbounded))
+            .take(limit.clamp(1, MAX_PAGE_LIMIT) as usize)
+            .map(|(_, auction)| storage.auction_status(auction, current_timestamp()))
+            .collect()
+    }
+
+    pub fn get_bad_debt(&self) -> u128 {
+        self.get().bad_debt
+    }
+
+    pub fn get_liquidation_mode(&self) -> LiquidationMode {
+        self.get().liquidation_mode
+    }
+
+    // Time-weighted average prices over the last `window` seconds, as far as the kept history
+    // reaches
+    pub fn get_twap(&self, window: u64) -> Twap {
+        let storage = self.get();
+        let (collateral_price, debt_price, covered) = storage.twap(window, current_timestamp());
+        Twap {
+            window,
+            collateral_price,
+            debt_price,
+            covered,
+            spot_collateral_price: storage.collateral_price,
+            spot_debt_price: storage.debt_price,
+        }
+    }
+
+    pub fn get_price_observations(&self) -> Vec<PriceObservation> {
+        self.get().price_observations.iter().copied().collect()
+    }
+
+    pub fn get_dex_config(&self) -> DexConfig {
+        let storage = self.get();
+        DexConfig {
+            dex: storage.dex_address,
+            max_slippage_bps: storage.max_slippage_bps,
+            pending_swaps: storage.dex_swaps.len() as u32,
+        }
+    }
+
+    // Rewards `user` could claim now, including what accrued since their last settlement
+    pub fn get_pending_rewards(&self, user: ActorId) -> u128 {
+        self.get().pending_rewards(&user, current_timestamp())
+    }
+
+    pub fn get_reward_schedule(&self) -> RewardSchedule {
+        let storage = self.get();
+        RewardSchedule {
+            token: storage.reward_token,
+            payout: storage.reward_payout,
+            supply_rate: storage.supply_reward_rate,
+            borrow_rate: storage.borrow_reward_rate,
+            total_supply: storage.total_lender_balances,
+            total_borrow: storage.total_principal_borrowed,
+            total_claimed: storage.total_rewards_claimed,
+        }
+    }
+
+    pub fn get_dex_swaps(&self) -> Vec<DexSwap> {
+        self.get().dex_swaps.values().cloned().collect()
+    }
+
+    pub fn get_withdrawal_requests(&self, lender: ActorId) -> Vec<WithdrawalQueuePosition> {
+        let mut amount_ahead = 0;
+        let mut requests = Vec::new();
+        for (position, request) in self.get().withdrawal_queue.iter().enumerate() {
+            if request.lender == lender {
+                requests.push(WithdrawalQueuePosition {
+                    request: request.clone(),
+                    position: position as u32,
+                    amount_ahead,
+                });
+            }
+            amount_ahead += request.remaining;
+        }
+        requests
+    }
+
+    pub fn get_pending_withdrawal(&self, lender: ActorId) -> u128 {
+        *self.get().pending_withdrawals.get(&lender).unwrap_or(&0)
+    }
+
+    pub fn get_total_pending_withdrawals(&self) -> u128 {
+        self.get().pending_withdrawals.values().sum()
+    }
+
     pub fn get_lender_interest_earned(&self, lender: ActorId) -> u128 {
         *self.get().lender_interest_earned.get(&lender).unwrap_or(&0)
     }
@@ -815,79 +2165,590 @@ impl LendingService {
         borrowers_info
     }
 
+    // Paginated views: `start_after` is the last ActorId of the previous page (None for the first page)
+    pub fn get_borrowers_page(
+        &self,
+        start_after: Option<ActorId>,
+        limit: u32,
+        filter: PositionFilter,
+    ) -> UserInfoPage {
+        self.positions_page(PositionKind::Borrowers, start_after, limit, &filter)
+    }
+
+    pub fn get_lenders_page(
+        &self,
+        start_after: Option<ActorId>,
+        limit: u32,
+        filter: PositionFilter,
+    ) -> UserInfoPage {
+        self.positions_page(PositionKind::Lenders, start_after, limit, &filter)
+    }
+
+    pub fn get_collateral_holders_page(
+        &self,
+        start_after: Option<ActorId>,
+        limit: u32,
+        filter: PositionFilter,
+    ) -> UserInfoPage {
+        self.positions_page(PositionKind::CollateralHolders, start_after, limit, &filter)
+    }
+
+    fn positions_page(
+        &self,
+        kind: PositionKind,
+        start_after: Option<ActorId>,
+        limit: u32,
+        filter: &PositionFilter,
+    ) -> UserInfoPage {
+        let storage = self.get();
+        let map = match kind {
+            PositionKind::Borrowers => &storage.debt,
+            PositionKind::Lenders => &storage.lender_balances,
+            PositionKind::CollateralHolders => &storage.collateral,
+        };
+        let lower = match start_after {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+
+        let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+        let mut keys = map.range((lower, Bound::Unbounded)).map(|(user, _)| *user);
+        let mut items = Vec::new();
+        let mut last_scanned = None;
+        let mut scanned = 0;
+
+        while items.len() < limit && scanned < MAX_PAGE_SCAN {
+            let Some(user) = keys.next() else {
+                // Reached the end of the key set, nothing left to page through
+                return UserInfoPage {
+                    items,
+                    next_cursor: None,
+                };
+            };
+            scanned += 1;
+            last_scanned = Some(user);
+
+            let info = self.get_user_info(user);
+            if Self::matches_filter(&info, filter) {
+                items.push((user, info));
+            }
+        }
+
+        // Stopped early: only hand out a cursor if more entries remain
+        let next_cursor = if keys.next().is_some() {
+            last_scanned
+        } else {
+            None
+        };
+
+        UserInfoPage { items, next_cursor }
+    }
+
+    fn matches_filter(info: &UserInfo, filter: &PositionFilter) -> bool {
+        let total_debt = info.debt + info.accrued_interest;
+        !(filter.min_debt.is_some_and(|min| total_debt < min)
+            || filter
+                .min_health_factor
+                .is_some_and(|min| info.health_factor < min)
+            || filter
+                .max_health_factor
+                .is_some_and(|max| info.health_factor > max)
+            || filter
+                .has_accrued_interest
+                .is_some_and(|has| (info.accrued_interest > 0) != has))
+    }
+
+    // Positions whose health factor, judged on spot or TWAP prices as `liquidate` does, is below
+    // `threshold`, worst first, followed by healthier ones with a term loan past its grace period.
+    // Candidates are read from the spot-sorted health index.
+    pub fn get_liquidation_opportunities(
+        &self,
+        threshold: u128,
+        limit: u32,
+    ) -> Vec<LiquidationOpportunity> {
+        let storage = self.get();
+        let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+        let now = current_timestamp();
+
+        let mut candidates = BTreeSet::new();
+        for (_, user) in storage.health_index.iter() {
+            // The liquidation health factor is never below spot, so every remaining position is
+            // out of reach too
+            if storage.health_factor(user) >= threshold {
+                break;
+            }
+            candidates.insert(*user);
+        }
+        candidates.extend(
+            storage
+                .term_loans
+                .values()
+                .filter(|loan| now > loan.grace_ends_at)
+                .map(|loan| loan.borrower),
+        );
+
+        let mut opportunities = Vec::new();
+        for user in candidates {
+            let collateral = *storage.collateral.get(&user).unwrap_or(&0);
+            let total_debt = storage.total_debt_of(&user);
+            let health_factor = storage.liquidation_health_of(collateral, total_debt, now);
+            let overdue_term_loan = storage.has_overdue_term_loan(&user, now);
+            // A recent price drop the TWAP doesn't reflect yet isn't liquidatable
+            if collateral == 0 || (health_factor >= threshold && !overdue_term_loan) {
+                continue;
+            }
+            // `liquidate` clears the whole debt and seizes all collateral
+            opportunities.push(LiquidationOpportunity {
+                user,
+                health_factor,
+                collateral,
+                total_debt,
+                max_repayable_debt: total_debt,
+                expected_collateral_seized: collateral,
+                overdue_term_loan,
+            });
+        }
+        opportunities.sort_by_key(|opportunity| opportunity.health_factor);
+        opportunities.truncate(limit);
+        opportunities
+    }
+
+    // Preview functions: simulate an action against current state without changing it
+
+    pub fn preview_deposit(&self, user: ActorId, amount: u128) -> ActionPreview {
+        let (collateral, debt, interest) = self.simulated_position(user);
+        let violation = if amount == 0 {
+            Some("Must send VARA tokens as collateral")
+        } else {
+            None
+        };
+        let liquidity = self.get().total_liquidity;
+        self.preview(
+            amount,
+            (collateral + amount, debt, interest),
+            0,
+            liquidity,
+            violation,
+        )
+    }
+
+    // `borrow` always mints ~66% of collateral value; pass `amount` 0 to preview exactly that
+    pub fn preview_borrow(&self, user: ActorId, amount: u128) -> ActionPreview {
+        let storage = self.get();
+        let (collateral, debt, interest) = self.simulated_position(user);
+        let (default_amount, max_borrowable) = pool::borrow_limits(collateral, storage.pricing());
+        let amount = if amount == 0 { default_amount } else { amount };
+
+        let violation = if collateral == 0 {
+            Some("No collateral deposited")
+        } else if debt + interest + amount > max_borrowable {
+            Some("Exceeds maximum LTV ratio")
+        } else if amount > storage.total_liquidity {
+            Some("Insufficient liquidity")
+        } else {
+            None
+        };
+        if violation.is_some() {
+            let position = (collateral, debt, interest);
+            return self.preview(amount, position, 0, storage.total_liquidity, violation);
+        }
+        self.preview(
+            amount,
+            (collateral, debt + amount, interest),
+            0,
+            storage.total_liquidity - amount,
+            None,
+        )
+    }
+
+    pub fn preview_repay(&self, user: ActorId, amount: u128) -> ActionPreview {
+        let storage = self.get();
+        let now = current_timestamp();
+        // `repay` only touches the variable-rate position, term loans are repaid separately
+        let (collateral, debt, interest) = storage.simulated_position(&user, now);
+        let (term_principal, term_interest) = storage.simulated_term_debt(&user, now);
+        let repaid = amount.min(debt);
+        let remaining_debt = debt - repaid;
+        let liquidity = storage.total_liquidity + repaid;
+
+        // Mirrors `repay`: once principal hits zero, interest is taken from collateral
+        // and the rest of the collateral is returned unless term loans still need it
+        if remaining_debt == 0 {
+            let interest_in_collateral =
+                storage.decimals.debt_to_collateral(interest, Rounding::Up);
+            let left = collateral.saturating_sub(interest_in_collateral);
+            if term_principal > 0 {
+                let position = (left, term_principal, term_interest);
+                return self.preview(repaid, position, 0, liquidity, None);
+            }
+            return self.preview(repaid, (0, 0, 0), left, liquidity, None);
+        }
+        self.preview(
+            repaid,
+            (
+                collateral,
+                remaining_debt + term_principal,
+                interest + term_interest,
+            ),
+            0,
+            liquidity,
+            None,
+        )
+    }
+
+    pub fn preview_withdraw_collateral(&self, user: ActorId, amount: u128) -> ActionPreview {
+        let storage = self.get();
+        let (collateral, debt, interest) = self.simulated_position(user);
+        let violation = if amount > collateral {
+            Some("Insufficient collateral")
+        } else if debt + interest > 0
+            && !pool::within_withdrawal_ltv(collateral - amount, debt + interest, storage.pricing())
+        {
+            Some("Withdrawal would exceed LTV ratio")
+        } else {
+            None
+        };
+        if violation.is_some() {
+            let position = (collateral, debt, interest);
+            return self.preview(amount, position, 0, storage.total_liquidity, violation);
+        }
+        self.preview(
+            amount,
+            (collateral - amount, debt, interest),
+            amount,
+            storage.total_liquidity,
+            None,
+        )
+    }
+
+    pub fn preview_liquidate(&self, user: ActorId) -> ActionPreview {
+        let storage = self.get();
+        let (collateral, debt, interest) = self.simulated_position(user);
+        let now = current_timestamp();
+        let health = storage.liquidation_health_of(collateral, debt + interest, now);
+        let overdue = storage.has_overdue_term_loan(&user, now);
+        let violation = if storage.liquidation_mode == LiquidationMode::Auction {
+            Some("Liquidations go through auctions")
+        } else if collateral == 0 {
+            Some("No collateral to liquidate")
+        } else if debt + interest == 0 {
+            Some("No debt to liquidate")
+        } else if health >= LIQUIDATION_THRESHOLD && !overdue {
+            Some("Position not eligible for liquidation: Health factor is >= 120")
+        } else {
+            None
+        };
+        if violation.is_some() {
+            let position = (collateral, debt, interest);
+            return self.preview(0, position, 0, storage.total_liquidity, violation);
+        }
+        // Seized collateral is added to liquidity and the whole position is cleared
+        let liquidity = storage.total_liquidity
+            + storage
+                .decimals
+                .collateral_to_debt(collateral, Rounding::Down);
+        self.preview(collateral, (0, 0, 0), 0, liquidity, None)
+    }
+
+    // Position (collateral, principal, accrued interest) as if `accrue_interest` ran now,
+    // with open term loans folded into principal and interest
+    fn simulated_position(&self, user: ActorId) -> (u128, u128, u128) {
+        let storage = self.get();
+        let now = current_timestamp();
+        let (collateral, debt, interest) = storage.simulated_position(&user, now);
+        let (term_principal, term_interest) = storage.simulated_term_debt(&user, now);
+        (collateral, debt + term_principal, interest + term_interest)
+    }
+
+    fn preview(
+        &self,
+        amount: u128,
+        (collateral, debt, accrued_interest): (u128, u128, u128),
+        collateral_returned: u128,
+        liquidity: u128,
+        violation: Option<&str>,
+    ) -> ActionPreview {
+        let storage = self.get();
+        let violation = if storage.paused {
+            Some("Protocol is paused")
+        } else {
+            violation
+        };
+        ActionPreview {
+            amount,
+            collateral,
+            debt,
+            accrued_interest,
+            health_factor: pool::health_factor_of(
+                collateral,
+                debt + accrued_interest,
+                storage.pricing(),
+            ),
+            collateral_returned,
+            liquidity,
+            violation: violation.map(String::from),
+        }
+    }
+
     // --- Modified Function: Admin withdraw funds (from total_liquidity) ---
     pub fn admin_withdraw_funds(&mut self, amount_tvara: u128) {
-        let recipient = msg::source();
-        self.guard(|storage| {
-            assert_eq!(
-                msg::source(),
-                storage.admin,
-                "Only admin can withdraw funds"
-            );
-            assert!(
-                amount_tvara > 0,
-                "Withdrawal amount must be greater than zero"
-            );
-            assert!(
-                storage.total_liquidity >= amount_tvara,
-                "Insufficient total liquidity for withdrawal"
-            );
-
-            // Convert the TVARA amount to VARA using the current TVARA price
-            // (amount_tvara * price_in_wad) / WAD -- this converts 12-decimal TVARA to 18-decimal VARA value
-            // then we convert 18-decimal VARA value to 12-decimal VARA amount (since VARA_UNIT is 12 decimals)
-            let vara_to_send = (amount_tvara * storage.tvara_price) / WAD; // Result is in VARA (12 decimals, matching VARA_UNIT)
-
-            storage.total_liquidity -= amount_tvara;
-
-            let sent = msg::send(recipient, (), vara_to_send);
-            assert!(
-                sent.is_ok(),
-                "Admin withdrawal failed or insufficient balance"
-            );
-        });
+        let ((), effects) = self.run(|pool| pool.admin_withdraw_funds(amount_tvara));
+        self.apply_effects(effects);
     }
 
     // --- New Function: Admin withdraw treasury funds ---
     pub fn admin_withdraw_treasury(&mut self, amount_tvara: u128) {
-        let recipient = msg::source();
-        self.guard(|storage| {
-            assert_eq!(
-                msg::source(),
-                storage.admin,
-                "Only admin can withdraw treasury funds"
-            );
-            assert!(
-                amount_tvara > 0,
-                "Withdrawal amount must be greater than zero"
-            );
-            assert!(
-                storage.treasury >= amount_tvara,
-                "Insufficient treasury balance for withdrawal"
-            );
-
-            // Convert the TVARA amount to VARA using the current TVARA price
-            let vara_to_send = (amount_tvara * storage.tvara_price) / WAD; // Result is in VARA (12 decimals)
-
-            storage.treasury -= amount_tvara;
-
-            let sent = msg::send(recipient, (), vara_to_send);
-            assert!(
-                sent.is_ok(),
-                "Admin treasury withdrawal failed or insufficient balance"
-            );
+        let ((), effects) = self.run(|pool| pool.admin_withdraw_treasury(amount_tvara));
+        self.apply_effects(effects);
+    }
+
+    // Replaces the beneficiaries `distribute_treasury` pays; an empty list disables it
+    pub fn set_treasury_beneficiaries(&mut self, beneficiaries: Vec<TreasuryBeneficiary>) {
+        let ((), effects) = self.run(|pool| pool.set_treasury_beneficiaries(beneficiaries));
+        self.apply_effects(effects);
+    }
+
+    // Splits the treasury between the beneficiaries by weight; open to anyone. Returns the
+    // TVARA amount distributed.
+    pub fn distribute_treasury(&mut self) -> u128 {
+        let (distributed, effects) = self.run(|pool| pool.distribute_treasury());
+        self.apply_effects(effects);
+        distributed
+    }
+
+    // Solvency self-audit against the program's actual VARA balance
+    pub fn check_invariants(&self) -> InvariantReport {
+        self.get().check_invariants(spendable_balance())
+    }
+
+    // Accrues interest for everyone; pays the caller a bounty from the treasury when accrual had
+    // gone stale. Returns the bounty in TVARA units.
+    pub fn accrue(&mut self) -> u128 {
+        let (bounty, effects) = self.run(|pool| pool.accrue());
+        self.apply_effects(effects);
+        bounty
+    }
+
+    // `accrue` plus a refresh of the given positions; returns their health factors
+    pub fn poke(&mut self, users: Vec<ActorId>) -> Vec<(ActorId, u128)> {
+        let (positions, effects) = self.run(|pool| pool.poke(users));
+        self.apply_effects(effects);
+        positions
+    }
+
+    // What `accrue` would pay right now, before counting the treasury cut of pending interest
+    pub fn get_keeper_bounty(&self) -> u128 {
+        let storage = self.get();
+        if current_timestamp().saturating_sub(storage.last_accrual_ts) < KEEPER_ACCRUAL_DELAY {
+            return 0;
+        }
+        storage.keeper_bounty()
+    }
+
+    // Admin: run an accrual checkpoint every `interval_blocks` blocks (0 stops them) with
+    // `gas_per_checkpoint` gas, flagging positions whose health is below `warning_threshold`.
+    // The first checkpoint is paid from this message's gas; later ones need reserved gas, and
+    // `CheckpointsStopped` is emitted once the chain runs out of it.
+    pub fn configure_checkpoints(
+        &mut self,
+        interval_blocks: u32,
+        gas_per_checkpoint: u64,
+        warning_threshold: u128,
+    ) {
+        let ((), effects) = self.run(|pool| {
+            pool.configure_checkpoints(interval_blocks, gas_per_checkpoint, warning_threshold)
         });
+        self.apply_effects(effects);
+    }
+
+    // Admin: prepays `count` future checkpoints by reserving their gas from this message
+    pub fn reserve_checkpoint_gas(&mut self, count: u32) {
+        let ((), effects) = self.run(|pool| pool.reserve_checkpoint_gas(count));
+        self.apply_effects(effects);
+    }
+
+    // Target of the program's own delayed messages; checkpoints from an earlier configuration
+    // are ignored. Returns the number of positions flagged.
+    pub fn checkpoint(&mut self, epoch: u64) -> u32 {
+        assert_eq!(
+            msg::source(),
+            exec::program_id(),
+            "Checkpoints are run by the program itself"
+        );
+        let (warnings, effects) = self.run(|pool| pool.checkpoint(epoch));
+        self.apply_effects(effects);
+        warnings
+    }
+
+    pub fn get_checkpoint_config(&self) -> CheckpointConfig {
+        let storage = self.get();
+        CheckpointConfig {
+            interval_blocks: storage.checkpoint_interval,
+            gas_per_checkpoint: storage.checkpoint_gas,
+            warning_threshold: storage.health_warning_threshold,
+            epoch: storage.checkpoint_epoch,
+            reservations: storage
+                .checkpoint_reservations
+                .iter()
+                .filter(|reservation| reservation.expires_at > exec::block_height())
+                .count() as u32,
+            last_checkpoint: storage.last_checkpoint_ts,
+        }
+    }
+
+    pub fn set_strict_invariants(&mut self, enabled: bool) {
+        let ((), effects) = self.run(|pool| pool.set_strict_invariants(enabled));
+        self.apply_effects(effects);
     }
 
     pub fn get_contract_state(&self) -> ContractState {
         ContractState::from(self.get())
     }
 
-    pub fn handle_action(&mut self, action: crate::io::LendingAction) -> crate::io::LendingReply {
+    pub fn get_version(&self) -> VersionInfo {
+        VersionInfo {
+            code_version: CODE_VERSION,
+            storage_version: self.get().storage_version,
+        }
+    }
+
+    // Migration: export from the old program, deploy the new code, then `import_state` there
+    pub fn export_state(&self) -> VersionedState {
+        assert!(self.get().shutdown.is_none(), "Protocol is shut down");
+        // Queued requests already burned their VFT and aren't part of the exported layout
+        assert!(
+            self.get().withdrawal_queue.is_empty(),
+            "Withdrawal queue must be drained before exporting"
+        );
+        assert!(
+            self.get().term_loans.is_empty(),
+            "Term loans must be closed before exporting"
+        );
+        assert!(
+            self.get().term_deposits.is_empty(),
+            "Term deposits must be withdrawn before exporting"
+        );
+        assert!(
+            self.get().auctions.is_empty() && self.get().dex_swaps.is_empty(),
+            "Auctions and DEX swaps must be settled before exporting"
+        );
+        VersionedState::V4(self.get_contract_state())
+    }
+
+    // Replaces the state of a freshly deployed program with an exported one, upgrading
+    // older layouts. The VARA backing the imported positions must be moved separately.
+    pub fn import_state(&mut self, state: VersionedState) {
+        let storage = self.get_mut();
+        assert_eq!(msg::source(), storage.admin, "Only admin can import state");
+        assert!(
+            storage.collateral.is_empty()
+                && storage.debt.is_empty()
+                && storage.lender_balances.is_empty()
+                && storage.total_liquidity == 0
+                && storage.treasury == 0,
+            "State can only be imported into an empty pool"
+        );
+
+        // Decimals come from this deployment's VFT, not from the exported state
+        let decimals = storage.decimals;
+        *storage = LendingStorage::from(state.upgrade());
+        storage.decimals = decimals;
+    }
+
+    pub async fn handle_action(
+        &mut self,
+        action: crate::io::LendingAction,
+    ) -> crate::io::LendingReply {
         match action {
+            crate::io::LendingAction::DepositCollateral => {
+                self.deposit_collateral();
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::Borrow => {
+                self.borrow().await;
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::Lend => {
+                self.lend().await;
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::Liquidate(user) => {
+                self.liquidate(user).await;
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::UpdateCollateralPrice(price) => {
+                self.update_collateral_price(price);
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::ConfigureDex {
+                dex,
+                max_slippage_bps,
+            } => {
+                self.configure_dex(dex, max_slippage_bps);
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::SetLiquidationMode(mode) => {
+                self.set_liquidation_mode(mode);
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::GetDexSwaps => {
+                crate::io::LendingReply::DexSwaps(self.get_dex_swaps())
+            }
+            crate::io::LendingAction::GetAuctions { start_after, limit } => {
+                crate::io::LendingReply::Auctions(self.get_auctions(start_after, limit))
+            }
             crate::io::LendingAction::GetContractState => {
                 crate::io::LendingReply::ContractState(self.get_contract_state())
             }
+            crate::io::LendingAction::GetBorrowersPage {
+                start_after,
+                limit,
+                filter,
+            } => crate::io::LendingReply::UserInfoPage(self.get_borrowers_page(
+                start_after,
+                limit,
+                filter,
+            )),
+            crate::io::LendingAction::GetLiquidationOpportunities { threshold, limit } => {
+                crate::io::LendingReply::LiquidationOpportunities(
+                    self.get_liquidation_opportunities(threshold, limit),
+                )
+            }
+            crate::io::LendingAction::PreviewDeposit { user, amount } => {
+                crate::io::LendingReply::Preview(self.preview_deposit(user, amount))
+            }
+            crate::io::LendingAction::PreviewBorrow { user, amount } => {
+                crate::io::LendingReply::Preview(self.preview_borrow(user, amount))
+            }
+            crate::io::LendingAction::PreviewRepay { user, amount } => {
+                crate::io::LendingReply::Preview(self.preview_repay(user, amount))
+            }
+            crate::io::LendingAction::PreviewWithdrawCollateral { user, amount } => {
+                crate::io::LendingReply::Preview(self.preview_withdraw_collateral(user, amount))
+            }
+            crate::io::LendingAction::PreviewLiquidate(user) => {
+                crate::io::LendingReply::Preview(self.preview_liquidate(user))
+            }
+            crate::io::LendingAction::ImportState(state) => {
+                self.import_state(*state);
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::DepositTerm(tier) => {
+                crate::io::LendingReply::TermDepositId(self.deposit_term(tier).await)
+            }
+            crate::io::LendingAction::WithdrawTermDeposit(deposit_id) => {
+                self.withdraw_term_deposit(deposit_id).await;
+                crate::io::LendingReply::Success
+            }
+            crate::io::LendingAction::GetTermDeposit(deposit_id) => {
+                crate::io::LendingReply::TermDeposit(self.get_term_deposit(deposit_id))
+            }
+            crate::io::LendingAction::GetVersion => {
+                crate::io::LendingReply::Version(self.get_version())
+            }
+            crate::io::LendingAction::CheckInvariants => {
+                crate::io::LendingReply::InvariantReport(self.check_invariants())
+            }
             _ => crate::io::LendingReply::Success, // fallback for other actions
         }
     }
@@ -895,10 +2756,39 @@ impl LendingService {
 
 pub struct BlockchainProgram(());
 
+// Block time in seconds, the unit of every stored timestamp and duration. `block_timestamp` is in
+// milliseconds.
+fn current_timestamp() -> u64 {
+    block_timestamp() / 1000
+}
+
+// VARA the program can pay out: its balance less the existential deposit it has to keep
+fn spendable_balance() -> u128 {
+    exec::value_available().saturating_sub(exec::env_vars().existential_deposit)
+}
+
+// Encoded `LendingService::checkpoint(epoch)` call, for the delayed messages the program sends
+// itself
+fn checkpoint_payload(epoch: u64) -> Vec<u8> {
+    ("LendingService", "Checkpoint", epoch).encode()
+}
+
+// The DEX's `SwapNativeForTokens` call; the VARA to sell is attached as the message value
+fn dex_swap_payload(min_out: u128, recipient: ActorId) -> Vec<u8> {
+    ("Dex", "SwapNativeForTokens", min_out, recipient).encode()
+}
+
+// TVARA amount out from the DEX's reply
+fn decode_dex_swap_reply(reply: &[u8]) -> Option<u128> {
+    <(String, String, u128)>::decode(&mut &reply[..])
+        .ok()
+        .map(|(_, _, amount_out)| amount_out)
+}
+
 #[program]
 impl BlockchainProgram {
-    pub async fn new(vft_address: ActorId) -> Self {
-        LendingService::init(vft_address).await;
+    pub async fn new(vft_address: ActorId, debt_decimals: Option<u8>) -> Self {
+        LendingService::init(vft_address, debt_decimals).await;
         Self(())
     }
 
@@ -908,3 +2798,7 @@ impl BlockchainProgram {
 }
 
 pub mod io;
+pub mod math;
+pub mod migration;
+pub mod oracle;
+pub mod pool;
@@ -0,0 +1,199 @@
+// Fixed-point arithmetic shared by the pool. Products are computed in 256 bits, so multiplying two
+// u128 amounts can never overflow halfway through a calculation, and every division states which
+// way it rounds. Rounding always favours the protocol: debt and fees round up, while amounts paid
+// out (minted loans, withdrawals, interest shares) round down.
+
+use sails_rs::U256;
+
+pub const WAD: u128 = 1_000_000_000_000_000_000; // 18 decimals
+pub const RAY: u128 = 1_000_000_000_000_000_000_000_000_000; // 27 decimals
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+// `a * b / denominator` with a 256-bit intermediate; `None` on division by zero or if the
+// result doesn't fit in u128
+pub fn checked_mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+    let product = U256::from(a) * U256::from(b); // 128 x 128 bits always fits in 256
+    let denominator = U256::from(denominator);
+    let mut quotient = product / denominator;
+    if rounding == Rounding::Up && !(product % denominator).is_zero() {
+        quotient += U256::one();
+    }
+    if quotient > U256::from(u128::MAX) {
+        None
+    } else {
+        Some(quotient.as_u128())
+    }
+}
+
+// Panics on overflow or division by zero, aborting the message before any state is committed
+pub fn mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> u128 {
+    checked_mul_div(a, b, denominator, rounding).expect("Math overflow")
+}
+
+// Clamps to u128::MAX instead of failing; used where the result is only compared or ordered
+pub fn saturating_mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> u128 {
+    checked_mul_div(a, b, denominator, rounding).unwrap_or(u128::MAX)
+}
+
+pub fn mul_div_down(a: u128, b: u128, denominator: u128) -> u128 {
+    mul_div(a, b, denominator, Rounding::Down)
+}
+
+pub fn mul_div_up(a: u128, b: u128, denominator: u128) -> u128 {
+    mul_div(a, b, denominator, Rounding::Up)
+}
+
+// 18-decimal fixed-point number (prices, rates, utilization)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Wad(pub u128);
+
+impl Wad {
+    pub const ZERO: Wad = Wad(0);
+    pub const ONE: Wad = Wad(WAD);
+
+    pub fn from_percent(percent: u128) -> Self {
+        Wad(mul_div_down(percent, WAD, 100))
+    }
+
+    pub fn from_ratio(numerator: u128, denominator: u128, rounding: Rounding) -> Self {
+        Wad(mul_div(numerator, WAD, denominator, rounding))
+    }
+
+    pub fn checked_add(self, other: Wad) -> Option<Wad> {
+        self.0.checked_add(other.0).map(Wad)
+    }
+
+    pub fn checked_sub(self, other: Wad) -> Option<Wad> {
+        self.0.checked_sub(other.0).map(Wad)
+    }
+
+    pub fn saturating_add(self, other: Wad) -> Wad {
+        Wad(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Wad) -> Wad {
+        Wad(self.0.saturating_sub(other.0))
+    }
+
+    pub fn checked_mul(self, other: Wad, rounding: Rounding) -> Option<Wad> {
+        checked_mul_div(self.0, other.0, WAD, rounding).map(Wad)
+    }
+
+    pub fn checked_div(self, other: Wad, rounding: Rounding) -> Option<Wad> {
+        checked_mul_div(self.0, WAD, other.0, rounding).map(Wad)
+    }
+
+    pub fn mul(self, other: Wad, rounding: Rounding) -> Wad {
+        Wad(mul_div(self.0, other.0, WAD, rounding))
+    }
+
+    pub fn div(self, other: Wad, rounding: Rounding) -> Wad {
+        Wad(mul_div(self.0, WAD, other.0, rounding))
+    }
+
+    // Scales a plain token amount by this factor
+    pub fn apply(self, amount: u128, rounding: Rounding) -> u128 {
+        mul_div(amount, self.0, WAD, rounding)
+    }
+
+    pub fn to_ray(self) -> Ray {
+        Ray(self.0.checked_mul(RAY / WAD).expect("Math overflow"))
+    }
+}
+
+// 27-decimal fixed-point number, for values where WAD precision loses too much (per-second rates)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ray(pub u128);
+
+impl Ray {
+    pub const ZERO: Ray = Ray(0);
+    pub const ONE: Ray = Ray(RAY);
+
+    pub fn from_ratio(numerator: u128, denominator: u128, rounding: Rounding) -> Self {
+        Ray(mul_div(numerator, RAY, denominator, rounding))
+    }
+
+    pub fn checked_add(self, other: Ray) -> Option<Ray> {
+        self.0.checked_add(other.0).map(Ray)
+    }
+
+    pub fn checked_sub(self, other: Ray) -> Option<Ray> {
+        self.0.checked_sub(other.0).map(Ray)
+    }
+
+    pub fn checked_mul(self, other: Ray, rounding: Rounding) -> Option<Ray> {
+        checked_mul_div(self.0, other.0, RAY, rounding).map(Ray)
+    }
+
+    pub fn mul(self, other: Ray, rounding: Rounding) -> Ray {
+        Ray(mul_div(self.0, other.0, RAY, rounding))
+    }
+
+    pub fn div(self, other: Ray, rounding: Rounding) -> Ray {
+        Ray(mul_div(self.0, RAY, other.0, rounding))
+    }
+
+    pub fn apply(self, amount: u128, rounding: Rounding) -> u128 {
+        mul_div(amount, self.0, RAY, rounding)
+    }
+
+    pub fn to_wad(self, rounding: Rounding) -> Wad {
+        Wad(mul_div(self.0, 1, RAY / WAD, rounding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounding_direction_is_explicit() {
+        assert_eq!(mul_div_down(10, 1, 3), 3);
+        assert_eq!(mul_div_up(10, 1, 3), 4);
+        assert_eq!(mul_div_up(9, 1, 3), 3);
+    }
+
+    #[test]
+    fn products_beyond_u128_do_not_overflow() {
+        // u128::MAX * u128::MAX / u128::MAX would overflow any u128 intermediate
+        assert_eq!(mul_div_down(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(checked_mul_div(u128::MAX, 2, 1, Rounding::Down), None);
+        assert_eq!(
+            saturating_mul_div(u128::MAX, 2, 1, Rounding::Down),
+            u128::MAX
+        );
+        assert_eq!(checked_mul_div(1, 1, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Math overflow")]
+    fn mul_div_panics_on_overflow() {
+        mul_div_down(u128::MAX, 2, 1);
+    }
+
+    #[test]
+    fn wad_and_ray_conversions() {
+        let half = Wad::from_ratio(1, 2, Rounding::Down);
+        assert_eq!(half, Wad(WAD / 2));
+        assert_eq!(Wad::from_percent(6), Wad(6 * WAD / 100));
+        assert_eq!(half.mul(half, Rounding::Down), Wad(WAD / 4));
+        assert_eq!(half.div(half, Rounding::Down), Wad::ONE);
+        assert_eq!(half.to_ray().to_wad(Rounding::Down), half);
+        assert_eq!(
+            Ray::from_ratio(1, 3, Rounding::Up).to_wad(Rounding::Up),
+            Wad(WAD / 3 + 1)
+        );
+        assert_eq!(
+            Wad::from_ratio(1, 3, Rounding::Up).apply(3, Rounding::Down),
+            1
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use crate::math::WAD;
+use crate::{
+    ContractState, DEFAULT_HEALTH_WARNING_THRESHOLD, DEFAULT_MAX_SLIPPAGE_BPS,
+    DEFAULT_RATE_CHECKPOINT_PERIOD, DEFAULT_TWAP_WINDOW, LiquidationMode, RewardPayout,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use parity_scale_codec::{Decode, Encode};
+use sails_rs::prelude::ActorId;
+use scale_info::TypeInfo;
+
+// Version of the program code, bumped on every release
+pub const CODE_VERSION: u32 = 4;
+// Version of the exported state layout (`ContractState`), bumped whenever it changes
+pub const STORAGE_VERSION: u32 = 4;
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug)]
+pub struct VersionInfo {
+    pub code_version: u32,
+    pub storage_version: u32,
+}
+
+// v1 layout: the state exported by programs deployed before storage versioning
+#[derive(Clone, Debug, Encode, Decode, TypeInfo)]
+pub struct ContractStateV1 {
+    pub vft_address: ActorId,
+    pub collateral: BTreeMap<ActorId, u128>,
+    pub tvara_price: u128,
+    pub debt: BTreeMap<ActorId, u128>,
+    pub lender_balances: BTreeMap<ActorId, u128>,
+    pub lender_interest_earned: BTreeMap<ActorId, u128>,
+    pub total_liquidity: u128,
+    pub treasury: u128,
+    pub paused: bool,
+    pub admin: ActorId,
+    pub last_accrual_ts: u64,
+    pub total_interest_earned: u128,
+    pub user_accrued_interest: BTreeMap<ActorId, u128>,
+    pub total_principal_borrowed: u128,
+}
+
+// v2 layout: a single `tvara_price`, which was really VARA's price with TVARA pinned to 1 USD
+#[derive(Clone, Debug, Encode, Decode, TypeInfo)]
+pub struct ContractStateV2 {
+    pub storage_version: u32,
+    pub vft_address: ActorId,
+    pub collateral: BTreeMap<ActorId, u128>,
+    pub tvara_price: u128,
+    pub debt: BTreeMap<ActorId, u128>,
+    pub lender_balances: BTreeMap<ActorId, u128>,
+    pub lender_interest_earned: BTreeMap<ActorId, u128>,
+    pub total_liquidity: u128,
+    pub treasury: u128,
+    pub paused: bool,
+    pub admin: ActorId,
+    pub last_accrual_ts: u64,
+    pub total_interest_earned: u128,
+    pub user_accrued_interest: BTreeMap<ActorId, u128>,
+    pub total_principal_borrowed: u128,
+}
+
+// v3 layout: separate collateral and debt prices, without bad debt, oracle publishers, rewards,
+// fees or treasury beneficiaries
+#[derive(Clone, Debug, Encode, Decode, TypeInfo)]
+pub struct ContractStateV3 {
+    pub storage_version: u32,
+    pub vft_address: ActorId,
+    pub collateral: BTreeMap<ActorId, u128>,
+    pub collateral_price: u128,
+    pub debt_price: u128,
+    pub debt: BTreeMap<ActorId, u128>,
+    pub lender_balances: BTreeMap<ActorId, u128>,
+    pub lender_interest_earned: BTreeMap<ActorId, u128>,
+    pub total_liquidity: u128,
+    pub treasury: u128,
+    pub paused: bool,
+    pub admin: ActorId,
+    pub last_accrual_ts: u64,
+    pub total_interest_earned: u128,
+    pub user_accrued_interest: BTreeMap<ActorId, u128>,
+    pub total_principal_borrowed: u128,
+}
+
+// Exported state tagged with its layout version
+#[derive(Clone, Debug, Encode, Decode, TypeInfo)]
+pub enum VersionedState {
+    V1(ContractStateV1),
+    V2(ContractStateV2),
+    V3(ContractStateV3),
+    V4(ContractState),
+}
+
+impl VersionedState {
+    pub fn version(&self) -> u32 {
+        match self {
+            VersionedState::V1(_) => 1,
+            VersionedState::V2(_) => 2,
+            VersionedState::V3(_) => 3,
+            VersionedState::V4(_) => 4,
+        }
+    }
+
+    // Upgrade step by step to the current layout
+    pub fn upgrade(self) -> ContractState {
+        match self {
+            VersionedState::V1(state) => ContractStateV3::from(ContractStateV2::from(state)).into(),
+            VersionedState::V2(state) => ContractStateV3::from(state).into(),
+            VersionedState::V3(state) => state.into(),
+            VersionedState::V4(state) => state,
+        }
+    }
+}
+
+impl From<ContractStateV3> for ContractState {
+    fn from(state: ContractStateV3) -> Self {
+        Self {
+            storage_version: 4,
+            vft_address: state.vft_address,
+            collateral: state.collateral,
+            collateral_price: state.collateral_price,
+            debt_price: state.debt_price,
+            debt: state.debt,
+            lender_balances: state.lender_balances,
+            lender_interest_earned: state.lender_interest_earned,
+            total_liquidity: state.total_liquidity,
+            treasury: state.treasury,
+            paused: state.paused,
+            admin: state.admin,
+            last_accrual_ts: state.last_accrual_ts / 1000, // v3 and earlier stored milliseconds
+            total_interest_earned: state.total_interest_earned,
+            user_accrued_interest: state.user_accrued_interest,
+            total_principal_borrowed: state.total_principal_borrowed,
+            // v3 had none of these, so they start out empty and unconfigured
+            bad_debt: 0,
+            oracle_publishers: BTreeMap::new(),
+            oracle_threshold: 0,
+            reward_token: None,
+            reward_payout: RewardPayout::Mint,
+            supply_reward_rate: 0,
+            borrow_reward_rate: 0,
+            supply_reward_index: 0,
+            borrow_reward_index: 0,
+            rewards_updated_at: state.last_accrual_ts / 1000,
+            reward_snapshots: BTreeMap::new(),
+            unclaimed_rewards: BTreeMap::new(),
+            total_rewards_claimed: 0,
+            origination_fee_bps: 0,
+            liquidation_fee_bps: 0,
+            withdrawal_fee_bps: 0,
+            total_fees_collected: 0,
+            treasury_beneficiaries: Vec::new(),
+            treasury_distributed: BTreeMap::new(),
+            liquidation_mode: LiquidationMode::Instant,
+            dex_address: None,
+            max_slippage_bps: DEFAULT_MAX_SLIPPAGE_BPS,
+            twap_window: DEFAULT_TWAP_WINDOW,
+            checkpoint_interval: 0,
+            checkpoint_gas: 0,
+            health_warning_threshold: DEFAULT_HEALTH_WARNING_THRESHOLD,
+            rate_checkpoint_period: DEFAULT_RATE_CHECKPOINT_PERIOD,
+            account_history_enabled: false,
+            strict_invariants: false,
+        }
+    }
+}
+
+impl From<ContractStateV2> for ContractStateV3 {
+    fn from(state: ContractStateV2) -> Self {
+        Self {
+            storage_version: 3,
+            vft_address: state.vft_address,
+            collateral: state.collateral,
+            collateral_price: state.tvara_price,
+            debt_price: WAD, // v2 pinned TVARA to 1 USD
+            debt: state.debt,
+            lender_balances: state.lender_balances,
+            lender_interest_earned: state.lender_interest_earned,
+            total_liquidity: state.total_liquidity,
+            treasury: state.treasury,
+            paused: state.paused,
+            admin: state.admin,
+            last_accrual_ts: state.last_accrual_ts,
+            total_interest_earned: state.total_interest_earned,
+            user_accrued_interest: state.user_accrued_interest,
+            total_principal_borrowed: state.total_principal_borrowed,
+        }
+    }
+}
+
+impl From<ContractStateV1> for ContractStateV2 {
+    fn from(state: ContractStateV1) -> Self {
+        Self {
+            storage_version: 2,
+            vft_address: state.vft_address,
+            collateral: state.collateral,
+            tvara_price: state.tvara_price,
+            debt: state.debt,
+            lender_balances: state.lender_balances,
+            lender_interest_earned: state.lender_interest_earned,
+            total_liquidity: state.total_liquidity,
+            treasury: state.treasury,
+            paused: state.paused,
+            admin: state.admin,
+            last_accrual_ts: state.last_accrual_ts,
+            total_interest_earned: state.total_interest_earned,
+            user_accrued_interest: state.user_accrued_interest,
+            total_principal_borrowed: state.total_principal_borrowed,
+        }
+    }
+}
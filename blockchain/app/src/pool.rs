@@ -0,0 +1,4789 @@
+// Pure lending core. `Pool` applies the protocol rules to `LendingStorage` using an explicit
+// `Context` instead of reading `msg`/`exec`, and records the side effects (token calls, VARA
+// transfers, events) for the caller to execute. `LendingService` is a thin adapter over it.
+
+use crate::math::{Rounding, WAD, Wad, mul_div, mul_div_down, mul_div_up, saturating_mul_div};
+use crate::migration::STORAGE_VERSION;
+use crate::oracle;
+use crate::{
+    AUCTION_DURATION, AUCTION_MAX_DISCOUNT, AUCTION_MAX_RESTARTS, AUCTION_START_DISCOUNT,
+    AccountAction, AccountHistoryChanged, AccountHistoryEntry, AdminFundsWithdrawn, Auction,
+    AuctionKicked, AuctionOutcome, AuctionSettled, AuctionStatus, AuctionTaken, BPS_DENOMINATOR,
+    Borrowed, CheckpointGasReserved, CheckpointReservation, CheckpointRun, CheckpointsConfigured,
+    CheckpointsStopped, CollateralDeposited, CollateralWithdrawn, DEX_SWAP_RESOLVE_DELAY,
+    DepositTier, DexConfigured, DexLiquidationFailed, DexLiquidationSettled, DexLiquidationStarted,
+    DexSwap, EARLY_WITHDRAWAL_PENALTY, FeeCharged, FeeKind, FeesChanged, HealthWarning,
+    InterestAccrued, InterestClaimed, InvariantCheck, InvariantChecksChanged, InvariantReport,
+    KEEPER_ACCRUAL_DELAY, KEEPER_BOUNTY_BPS, KeeperRewarded, LENDER_INTEREST_SHARE,
+    LIQUIDATION_THRESHOLD, LendingEvent, LendingStorage, Liquidated, LiquidationMode,
+    LiquidationModeChanged, LiquidityProvided, LiquidityWithdrawn, MAX_ACCOUNT_HISTORY,
+    MAX_ASSET_DECIMALS, MAX_FEE_BPS, MAX_HEALTH_WARNINGS, MAX_ORACLE_PUBLISHERS, MAX_POKE_USERS,
+    MAX_PRICE_OBSERVATIONS, MAX_RATE_CHECKPOINTS, MAX_SLIPPAGE_BPS, MAX_TERM_DAYS,
+    MAX_TREASURY_BENEFICIARIES, MAX_TWAP_WINDOW, MAX_WITHDRAWAL_FILLS, OraclePublisher,
+    OraclePublishersChanged, PauseChanged, PositionsPoked, PriceObservation, PriceUpdated,
+    PublisherSignature, RateCheckpoint, RateCheckpointPeriodChanged, Repaid, RewardPayout,
+    RewardsClaimed, RewardsConfigured, SECONDS_PER_DAY, SECONDS_PER_YEAR, ShutdownClaim,
+    ShutdownClaimed, ShutdownStatus, ShutdownTriggered, SignatureScheme, SignedPriceAccepted,
+    TERM_LOAN_GRACE_PERIOD, TREASURY_INTEREST_SHARE, TermDeposit, TermDepositCreated,
+    TermDepositInfo, TermDepositWithdrawn, TermLoan, TermLoanOriginated, TermLoanRepaid,
+    TermLoanSchedule, TermLoanStatus, TreasuryBeneficiariesChanged, TreasuryBeneficiary,
+    TreasuryPaidOut, TreasuryWithdrawn, TwapWindowChanged, VARA_DECIMALS, WithdrawalFilled,
+    WithdrawalQueued, WithdrawalRequest,
+};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec;
+use gstd::ReservationId;
+use sails_rs::U256;
+use sails_rs::prelude::*;
+
+// Decimals of the collateral asset (native VARA) and the debt token (the VFT). Debt, liquidity,
+// lender balances, interest and the treasury are kept in debt-token units; collateral in VARA units.
+// Debt-token units are backed 1:1 by VARA the program holds, so funds moving between the two
+// (deposits, payouts, fees, interest settled from collateral, seized collateral) convert here by
+// decimals alone, never at market prices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
+pub struct AssetDecimals {
+    pub collateral: u8,
+    pub debt: u8,
+}
+
+impl Default for AssetDecimals {
+    fn default() -> Self {
+        Self {
+            collateral: VARA_DECIMALS,
+            debt: VARA_DECIMALS,
+        }
+    }
+}
+
+impl AssetDecimals {
+    pub fn new(collateral: u8, debt: u8) -> Self {
+        assert!(
+            collateral <= MAX_ASSET_DECIMALS && debt <= MAX_ASSET_DECIMALS,
+            "Unsupported token decimals"
+        );
+        Self { collateral, debt }
+    }
+
+    pub fn collateral_unit(self) -> u128 {
+        10u128.pow(self.collateral.into())
+    }
+
+    pub fn debt_unit(self) -> u128 {
+        10u128.pow(self.debt.into())
+    }
+
+    // The same nominal amount re-expressed in the other asset's decimals
+    pub fn debt_to_collateral(self, amount: u128, rounding: Rounding) -> u128 {
+        mul_div(amount, self.collateral_unit(), self.debt_unit(), rounding)
+    }
+
+    pub fn collateral_to_debt(self, amount: u128, rounding: Rounding) -> u128 {
+        mul_div(amount, self.debt_unit(), self.collateral_unit(), rounding)
+    }
+}
+
+impl DepositTier {
+    pub const ALL: [DepositTier; 3] = [
+        DepositTier::Days30,
+        DepositTier::Days90,
+        DepositTier::Days180,
+    ];
+
+    pub fn lockup_days(self) -> u32 {
+        match self {
+            DepositTier::Days30 => 30,
+            DepositTier::Days90 => 90,
+            DepositTier::Days180 => 180,
+        }
+    }
+
+    // Multiplier (percent) on the deposit's weight when lender interest is shared out
+    pub fn boost(self) -> u128 {
+        match self {
+            DepositTier::Days30 => 110,
+            DepositTier::Days90 => 125,
+            DepositTier::Days180 => 150,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
+pub enum Asset {
+    Collateral, // Native VARA
+    Debt,       // The VFT debt token (TVARA)
+}
+
+// USD prices (WAD) of both assets with their decimals. Prices only value a borrower's collateral
+// against their debt: health, LTV, borrow limits, and the collateral an auction, DEX swap or
+// shutdown exchanges for debt all go through `value_of`, so a move in either price is reflected
+// everywhere. Moving the pool's own funds between the assets is 1:1, see `AssetDecimals`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pricing {
+    pub collateral_price: u128,
+    pub debt_price: u128,
+    pub decimals: AssetDecimals,
+}
+
+impl Pricing {
+    fn price_and_unit(self, asset: Asset) -> (u128, u128) {
+        match asset {
+            Asset::Collateral => (self.collateral_price, self.decimals.collateral_unit()),
+            Asset::Debt => (self.debt_price, self.decimals.debt_unit()),
+        }
+    }
+
+    // USD value (WAD) of `amount` of `asset`
+    pub fn value_of(self, asset: Asset, amount: u128, rounding: Rounding) -> u128 {
+        let (price, unit) = self.price_and_unit(asset);
+        mul_div(amount, price, unit, rounding)
+    }
+
+    // Amount of `asset` worth `value` USD (WAD); the inverse of `value_of`
+    pub fn amount_for(self, asset: Asset, value: u128, rounding: Rounding) -> u128 {
+        let (price, unit) = self.price_and_unit(asset);
+        mul_div(value, unit, price, rounding)
+    }
+
+    // Converts `amount` of `from` into the equally valued amount of `to`
+    pub fn convert(self, from: Asset, amount: u128, to: Asset, rounding: Rounding) -> u128 {
+        self.amount_for(to, self.value_of(from, amount, rounding), rounding)
+    }
+}
+
+// Who is calling, with how much VARA attached, and when
+#[derive(Clone, Copy, Debug)]
+pub struct Context {
+    pub caller: ActorId,
+    pub value: u128,
+    pub timestamp: u64,
+    pub block_height: u32,
+    pub balance: u128, // Spendable VARA when the message arrived: `value` in, existential deposit out
+    pub program: ActorId, // This program, which holds the TVARA a DEX swap returns
+}
+
+// Side effects produced by a pool operation, in the order they must be executed
+pub enum Effect {
+    Mint {
+        to: ActorId,
+        amount: u128,
+    }, // Mint debt tokens through the VFT program
+    Burn {
+        from: ActorId,
+        amount: u128,
+    }, // Burn debt tokens through the VFT program
+    Transfer {
+        to: ActorId,
+        amount: u128,
+    }, // Send native VARA
+    TokenTransfer {
+        to: ActorId,
+        amount: u128,
+    }, // Send debt tokens the program holds
+    ScheduleCheckpoint {
+        epoch: u64,
+        delay: u32,
+        reservation: Option<ReservationId>,
+    }, // Delayed `checkpoint` message to the program, paid from the message's gas without a reservation
+    ReserveCheckpointGas {
+        gas: u64,
+        duration: u32,
+    }, // Reserve gas from the current message for a future checkpoint
+    // Mint or transfer reward tokens
+    Reward {
+        token: ActorId,
+        to: ActorId,
+        amount: u128,
+        payout: RewardPayout,
+    },
+    Event(LendingEvent),
+}
+
+pub struct Pool<'a> {
+    storage: &'a mut LendingStorage,
+    ctx: Context,
+    effects: Vec<Effect>,
+    guarded: bool, // Whether a guarded operation ran, so strict invariant checks apply
+}
+
+impl LendingStorage {
+    pub fn new(vft_address: ActorId, admin: ActorId, now: u64, decimals: AssetDecimals) -> Self {
+        Self {
+            storage_version: STORAGE_VERSION,
+            vft_address,
+            decimals,
+            collateral_price: crate::DEFAULT_PRICE,
+            debt_price: crate::DEFAULT_PRICE,
+            collateral: BTreeMap::new(),
+            debt: BTreeMap::new(),
+            lender_balances: BTreeMap::new(),
+            total_lender_balances: 0,
+            lender_interest_earned: BTreeMap::new(), // Initialize new map
+            total_liquidity: 0,
+            treasury: 0,
+            paused: false,
+            reentrancy: false,
+            admin,
+            last_accrual_ts: now,
+            total_interest_earned: 0,
+            user_accrued_interest: BTreeMap::new(),
+            total_principal_borrowed: 0, // Initialize new field
+            health_index: BTreeSet::new(),
+            health_index_keys: BTreeMap::new(),
+            strict_invariants: false,
+            withdrawal_queue: VecDeque::new(),
+            pending_withdrawals: BTreeMap::new(),
+            next_withdrawal_id: 0,
+            term_loans: BTreeMap::new(),
+            user_term_loans: BTreeMap::new(),
+            total_term_principal: 0,
+            next_term_loan_id: 0,
+            term_deposits: BTreeMap::new(),
+            lender_term_deposits: BTreeMap::new(),
+            total_term_deposits: 0,
+            next_term_deposit_id: 0,
+            checkpoint_interval: 0,
+            checkpoint_gas: 0,
+            health_warning_threshold: crate::DEFAULT_HEALTH_WARNING_THRESHOLD,
+            checkpoint_epoch: 0,
+            checkpoint_reservations: VecDeque::new(),
+            last_checkpoint_ts: 0,
+            liquidation_mode: LiquidationMode::Instant,
+            auctions: BTreeMap::new(),
+            next_auction_id: 0,
+            bad_debt: 0,
+            dex_address: None,
+            max_slippage_bps: crate::DEFAULT_MAX_SLIPPAGE_BPS,
+            dex_swaps: BTreeMap::new(),
+            next_dex_swap_id: 0,
+            price_observations: VecDeque::from([PriceObservation {
+                timestamp: now,
+                collateral_price: crate::DEFAULT_PRICE,
+                debt_price: crate::DEFAULT_PRICE,
+            }]),
+            twap_window: crate::DEFAULT_TWAP_WINDOW,
+            oracle_publishers: BTreeMap::new(),
+            oracle_threshold: 0,
+            collateral_price_updated_at: now,
+            debt_price_updated_at: now,
+            rate_history: VecDeque::new(),
+            rate_checkpoint_period: crate::DEFAULT_RATE_CHECKPOINT_PERIOD,
+            borrow_yield_index: 0,
+            supply_yield_index: 0,
+            account_history_enabled: false,
+            account_history: BTreeMap::new(),
+            next_account_history_id: 0,
+            reward_token: None,
+            reward_payout: RewardPayout::Mint,
+            supply_reward_rate: 0,
+            borrow_reward_rate: 0,
+            supply_reward_index: 0,
+            borrow_reward_index: 0,
+            rewards_updated_at: now,
+            reward_snapshots: BTreeMap::new(),
+            unclaimed_rewards: BTreeMap::new(),
+            total_rewards_claimed: 0,
+            origination_fee_bps: 0,
+            liquidation_fee_bps: 0,
+            withdrawal_fee_bps: 0,
+            total_fees_collected: 0,
+            treasury_beneficiaries: Vec::new(),
+            treasury_distributed: BTreeMap::new(),
+            shutdown: None,
+            shutdown_collateral_claims: BTreeMap::new(),
+            shutdown_lender_claims: BTreeMap::new(),
+        }
+    }
+
+    pub fn pricing(&self) -> Pricing {
+        Pricing {
+            collateral_price: self.collateral_price,
+            debt_price: self.debt_price,
+            decimals: self.decimals,
+        }
+    }
+
+    pub fn utilization_rate(&self) -> u128 {
+        // When calculating utilization, we should consider all borrowed TVARA,
+        // which includes principal debt + currently outstanding accrued interest.
+        // Term loans draw on the same liquidity, so they count towards utilization too.
+        let borrowed_principal: u128 = self.total_principal_borrowed + self.total_term_principal;
+        let borrowed_interest: u128 =
+            self.user_accrued_interest.values().sum::<u128>() + self.total_term_interest();
+        let total_borrowed = borrowed_principal + borrowed_interest;
+
+        let total = self.total_liquidity + total_borrowed; // Total TVARA in the system (available + borrowed)
+
+        if total == 0 {
+            0
+        } else {
+            // Rounded up, which can only nudge the borrow rate up
+            Wad::from_ratio(total_borrowed, total, Rounding::Up).0
+        }
+    }
+
+    pub fn borrow_rate_per_year(&self) -> u128 {
+        let u = Wad(self.utilization_rate());
+        let r0 = Wad::from_percent(6); // Base rate is 6% per year (total interest rate)
+        let rmax = Wad::from_percent(10); // Max rate is 10% per year (total interest rate)
+        let u_opt = Wad::from_percent(80); // Optimal utilization at 80%
+        let slope = rmax.saturating_sub(r0);
+
+        if u <= u_opt {
+            r0.saturating_add(Wad(mul_div_up(u.0, slope.0, u_opt.0))).0
+        } else {
+            // Linear interpolation beyond u_opt
+            // rate at u_opt = r0 + (u_opt * (rmax - r0)) / u_opt = rmax
+            // r_current = rmax + (u - u_opt) * (rmax - r0) / (WAD - u_opt)
+            let excess = u.saturating_sub(u_opt);
+            let steep = mul_div_up(excess.0, slope.0, Wad::ONE.saturating_sub(u_opt).0);
+            rmax.saturating_add(Wad(steep)).0
+        }
+    }
+
+    // Yearly rate lenders earn on their deposits at current utilization (WAD)
+    pub fn supply_rate_per_year(&self) -> u128 {
+        let utilization = Wad(self.utilization_rate());
+        let paid_out = Wad(self.borrow_rate_per_year()).mul(utilization, Rounding::Down);
+        mul_div_down(paid_out.0, LENDER_INTEREST_SHARE, 100)
+    }
+
+    // Appends a checkpoint of current rates and totals unless the last one is within the period
+    pub(crate) fn record_rate_checkpoint(&mut self, now: u64) {
+        if self
+            .rate_history
+            .back()
+            .is_some_and(|last| now < last.timestamp + self.rate_checkpoint_period.max(1))
+        {
+            return;
+        }
+        if self.rate_history.len() == MAX_RATE_CHECKPOINTS {
+            self.rate_history.pop_front();
+        }
+        let total_borrowed = self.total_principal_borrowed
+            + self.total_term_principal
+            + self.user_accrued_interest.values().sum::<u128>()
+            + self.total_term_interest();
+        self.rate_history.push_back(RateCheckpoint {
+            timestamp: now,
+            utilization: self.utilization_rate(),
+            borrow_rate: self.borrow_rate_per_year(),
+            supply_rate: self.supply_rate_per_year(),
+            total_liquidity: self.total_liquidity,
+            total_borrowed,
+            collateral_price: self.collateral_price,
+            debt_price: self.debt_price,
+            borrow_yield_index: self.borrow_yield_index,
+            supply_yield_index: self.supply_yield_index,
+        });
+    }
+
+    // (borrow APY, supply APY, seconds covered) from yield index growth between the last
+    // checkpoint at least `window` old (or the oldest one) and the last accrual
+    pub fn realized_apy(&self, window: u64) -> (u128, u128, u64) {
+        let now = self.last_accrual_ts;
+        let start = now.saturating_sub(window);
+        let before = self.rate_history.partition_point(|c| c.timestamp <= start);
+        let Some(from) = self.rate_history.get(before.saturating_sub(1)) else {
+            return (0, 0, 0);
+        };
+        let covered = now.saturating_sub(from.timestamp);
+        if covered == 0 {
+            return (0, 0, 0);
+        }
+        let annualize = |growth: u128| mul_div_down(growth, SECONDS_PER_YEAR, covered as u128);
+        (
+            annualize(self.borrow_yield_index - from.borrow_yield_index),
+            annualize(self.supply_yield_index - from.supply_yield_index),
+            covered,
+        )
+    }
+
+    // Appends `action` to the user's history with the position it left, when history is enabled
+    pub(crate) fn record_account_action(
+        &mut self,
+        user: ActorId,
+        action: AccountAction,
+        amount: u128,
+        now: u64,
+    ) {
+        if !self.account_history_enabled {
+            return;
+        }
+        let entry = AccountHistoryEntry {
+            id: self.next_account_history_id,
+            action,
+            amount,
+            timestamp: now,
+            collateral: *self.collateral.get(&user).unwrap_or(&0),
+            debt: self.total_debt_of(&user),
+        };
+        self.next_account_history_id += 1;
+        let history = self.account_history.entry(user).or_default();
+        if history.len() == MAX_ACCOUNT_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(entry);
+    }
+
+    // (supply, borrow) reward indexes brought forward to `now`. Emissions while a side has no
+    // balance go to nobody.
+    pub fn reward_indexes_at(&self, now: u64) -> (u128, u128) {
+        let dt = now.saturating_sub(self.rewards_updated_at) as u128;
+        let grow = |index: u128, rate: u128, total: u128| {
+            if total == 0 {
+                return index;
+            }
+            let emitted = rate.checked_mul(dt).expect("Reward emission overflow");
+            index
+                .checked_add(mul_div_down(emitted, WAD, total))
+                .expect("Reward index overflow")
+        };
+        (
+            grow(
+                self.supply_reward_index,
+                self.supply_reward_rate,
+                self.total_lender_balances,
+            ),
+            grow(
+                self.borrow_reward_index,
+                self.borrow_reward_rate,
+                self.total_principal_borrowed,
+            ),
+        )
+    }
+
+    // Unclaimed rewards plus what `user`'s balances earned since they were last settled
+    pub fn pending_rewards(&self, user: &ActorId, now: u64) -> u128 {
+        let (supply_index, borrow_index) = self.reward_indexes_at(now);
+        let (supply_snapshot, borrow_snapshot) =
+            self.reward_snapshots.get(user).copied().unwrap_or_default();
+        let supplied = *self.lender_balances.get(user).unwrap_or(&0);
+        let borrowed = *self.debt.get(user).unwrap_or(&0);
+        *self.unclaimed_rewards.get(user).unwrap_or(&0)
+            + mul_div_down(supplied, supply_index - supply_snapshot, WAD)
+            + mul_div_down(borrowed, borrow_index - borrow_snapshot, WAD)
+    }
+
+    // Credits `user` with rewards earned at their current balances. Must run before their lender
+    // balance or principal debt changes.
+    pub(crate) fn settle_rewards(&mut self, user: ActorId, now: u64) {
+        let pending = self.pending_rewards(&user, now);
+        (self.supply_reward_index, self.borrow_reward_index) = self.reward_indexes_at(now);
+        self.rewards_updated_at = now;
+        self.reward_snapshots
+            .insert(user, (self.supply_reward_index, self.borrow_reward_index));
+        if pending > 0 {
+            self.unclaimed_rewards.insert(user, pending);
+        }
+    }
+
+    pub fn shutdown_claim_of(&self, user: &ActorId) -> ShutdownClaim {
+        ShutdownClaim {
+            collateral: *self.shutdown_collateral_claims.get(user).unwrap_or(&0),
+            lender_share: *self.shutdown_lender_claims.get(user).unwrap_or(&0),
+        }
+    }
+
+    // Principal + accrued interest, across the variable-rate position and all term loans
+    pub fn total_debt_of(&self, user: &ActorId) -> u128 {
+        *self.debt.get(user).unwrap_or(&0)
+            + *self.user_accrued_interest.get(user).unwrap_or(&0)
+            + self.term_debt_of(user)
+    }
+
+    pub fn total_term_interest(&self) -> u128 {
+        self.term_loans
+            .values()
+            .map(|loan| loan.accrued_interest)
+            .sum()
+    }
+
+    pub fn term_debt_of(&self, user: &ActorId) -> u128 {
+        self.user_term_loans
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.term_loans.get(id))
+            .map(|loan| loan.principal + loan.accrued_interest)
+            .sum()
+    }
+
+    // Whether any of the user's term loans is past its grace period
+    pub fn has_overdue_term_loan(&self, user: &ActorId, now: u64) -> bool {
+        self.user_term_loans
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.term_loans.get(id))
+            .any(|loan| now > loan.grace_ends_at)
+    }
+
+    // Status of a term loan at `now` and what it will owe if held to maturity at its locked rate
+    pub fn term_loan_schedule(&self, loan: &TermLoan, now: u64) -> TermLoanSchedule {
+        let status = if now <= loan.maturity {
+            TermLoanStatus::Active
+        } else if now <= loan.grace_ends_at {
+            TermLoanStatus::InGracePeriod
+        } else {
+            TermLoanStatus::Overdue
+        };
+        let remaining = loan.maturity.saturating_sub(self.last_accrual_ts);
+        let interest_at_maturity = loan.accrued_interest
+            + interest_for(loan.principal, loan.rate, remaining, Rounding::Up);
+
+        TermLoanSchedule {
+            loan: loan.clone(),
+            status,
+            interest_at_maturity,
+            amount_due_at_maturity: loan.principal + interest_at_maturity,
+        }
+    }
+
+    pub fn health_factor(&self, user: &ActorId) -> u128 {
+        let collateral = *self.collateral.get(user).unwrap_or(&0);
+        health_factor_of(collateral, self.total_debt_of(user), self.pricing())
+    }
+
+    // Health factor liquidations are judged on: the higher of the spot and TWAP readings, so a
+    // position has to stay under the threshold for a while, not just after one price update
+    pub fn liquidation_health_of(&self, collateral: u128, total_debt: u128, now: u64) -> u128 {
+        let spot = health_factor_of(collateral, total_debt, self.pricing());
+        if self.twap_window == 0 {
+            return spot;
+        }
+        let (collateral_price, debt_price, _) = self.twap(self.twap_window, now);
+        let twap_pricing = Pricing {
+            collateral_price,
+            debt_price,
+            decimals: self.decimals,
+        };
+        spot.max(health_factor_of(collateral, total_debt, twap_pricing))
+    }
+
+    // Time-weighted average (collateral, debt) prices over the `window` seconds before `now`,
+    // each observation holding until the next, and the seconds of history covered. History
+    // shorter than the window is averaged as far as it goes; with none, spot prices are returned.
+    pub fn twap(&self, window: u64, now: u64) -> (u128, u128, u64) {
+        let start = now.saturating_sub(window);
+        // Summed in 256 bits: a price times the seconds it held can exceed u128
+        let (mut collateral_sum, mut debt_sum, mut covered) = (U256::zero(), U256::zero(), 0u64);
+        let mut end = now;
+        for observation in self.price_observations.iter().rev() {
+            let from = observation.timestamp.clamp(start, end);
+            let held = U256::from(end - from);
+            collateral_sum += U256::from(observation.collateral_price) * held;
+            debt_sum += U256::from(observation.debt_price) * held;
+            covered += end - from;
+            end = from;
+            if observation.timestamp <= start {
+                break;
+            }
+        }
+        if covered == 0 {
+            return (self.collateral_price, self.debt_price, 0);
+        }
+        // An average of u128 prices fits back in u128
+        (
+            (collateral_sum / U256::from(covered)).as_u128(),
+            (debt_sum / U256::from(covered)).as_u128(),
+            covered,
+        )
+    }
+
+    // Appends the current prices to the history, replacing an observation from the same second
+    // and dropping the oldest once the buffer is full
+    pub(crate) fn record_price_observation(&mut self, now: u64) {
+        if self
+            .price_observations
+            .back()
+            .is_some_and(|last| last.timestamp == now)
+        {
+            self.price_observations.pop_back();
+        } else if self.price_observations.len() == MAX_PRICE_OBSERVATIONS {
+            self.price_observations.pop_front();
+        }
+        self.price_observations.push_back(PriceObservation {
+            timestamp: now,
+            collateral_price: self.collateral_price,
+            debt_price: self.debt_price,
+        });
+    }
+
+    // Position (collateral, principal, accrued interest) as if interest were accrued at `now`
+    pub fn simulated_position(&self, user: &ActorId, now: u64) -> (u128, u128, u128) {
+        let collateral = *self.collateral.get(user).unwrap_or(&0);
+        let debt = *self.debt.get(user).unwrap_or(&0);
+        let mut interest = *self.user_accrued_interest.get(user).unwrap_or(&0);
+
+        let dt = now.saturating_sub(self.last_accrual_ts);
+        let rate = self.borrow_rate_per_year();
+        // Accrual only charges borrowers when the pool generated interest overall
+        if interest_for(self.total_principal_borrowed, rate, dt, Rounding::Down) > 0 {
+            interest += interest_for(debt, rate, dt, Rounding::Up);
+        }
+        (collateral, debt, interest)
+    }
+
+    // Open term loan (principal, accrued interest) as if interest were accrued at `now`
+    pub fn simulated_term_debt(&self, user: &ActorId, now: u64) -> (u128, u128) {
+        let dt = now.saturating_sub(self.last_accrual_ts);
+        self.user_term_loans
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.term_loans.get(id))
+            .fold((0, 0), |(principal, interest), loan| {
+                let pending = interest_for(loan.principal, loan.rate, dt, Rounding::Up);
+                (
+                    principal + loan.principal,
+                    interest + loan.accrued_interest + pending,
+                )
+            })
+    }
+
+    // Checks that `user` can be liquidated at `now` and takes their whole position off them,
+    // returning (collateral, total debt, health factor, term loans cleared). The caller decides
+    // what happens to the collateral.
+    pub(crate) fn seize_position(
+        &mut self,
+        user: ActorId,
+        now: u64,
+    ) -> (u128, u128, u128, Vec<u64>) {
+        // 1. Get raw amounts from storage
+        let collateral_amount_vara = *self.collateral.get(&user).unwrap_or(&0); // Collateral is VARA (12 decimals)
+        let principal_debt_amount_tvara = *self.debt.get(&user).unwrap_or(&0); // Principal debt is TVARA (12 decimals)
+
+        // 2. Calculate total TVARA debt (principal + accrued interest)
+        let total_debt_tvara = self.total_debt_of(&user);
+
+        // 3. Assertions for valid state (before price calculations)
+        assert!(collateral_amount_vara > 0, "No collateral to liquidate");
+        assert!(total_debt_tvara > 0, "No debt to liquidate");
+
+        // 4. Health factor from USD values of collateral and debt, at spot or TWAP prices,
+        // whichever reads healthier
+        let health = self.liquidation_health_of(collateral_amount_vara, total_debt_tvara, now);
+
+        // 5. Assert liquidation condition; a term loan past its grace period is liquidatable
+        // whatever the position's health
+        assert!(
+            health < LIQUIDATION_THRESHOLD || self.has_overdue_term_loan(&user, now),
+            "Position not eligible for liquidation: Health factor is >= 120"
+        );
+
+        // --- All checks passed, clear the position ---
+        self.settle_rewards(user, now);
+        self.collateral.remove(&user);
+        self.debt.remove(&user);
+        self.user_accrued_interest.remove(&user);
+        let term_loans_cleared = self.user_term_loans.remove(&user).unwrap_or_default();
+        for id in &term_loans_cleared {
+            if let Some(loan) = self.term_loans.remove(id) {
+                self.total_term_principal -= loan.principal;
+            }
+        }
+        self.reindex_health(user);
+        self.total_principal_borrowed -= principal_debt_amount_tvara; // Update total principal borrowed
+        self.record_account_action(user, AccountAction::Liquidated, collateral_amount_vara, now);
+
+        (
+            collateral_amount_vara,
+            total_debt_tvara,
+            health,
+            term_loans_cleared.into_iter().collect(),
+        )
+    }
+
+    // Collateral discount (WAD) of `auction` at `now`, rising linearly from the start discount to
+    // the max discount over `AUCTION_DURATION`
+    pub fn auction_discount(&self, auction: &Auction, now: u64) -> u128 {
+        let start = Wad::from_percent(AUCTION_START_DISCOUNT);
+        let max = Wad::from_percent(AUCTION_MAX_DISCOUNT);
+        let elapsed = now.saturating_sub(auction.started_at).min(AUCTION_DURATION);
+        start.0 + mul_div_down(max.0 - start.0, elapsed as u128, AUCTION_DURATION as u128)
+    }
+
+    // (TVARA paid, VARA bought) for a bid of up to `amount` TVARA. Never pays more than the
+    // auction's remaining debt; when the collateral runs out first, all of it is sold for less.
+    pub fn auction_quote(&self, auction: &Auction, amount: u128, now: u64) -> (u128, u128) {
+        let discount = self.auction_discount(auction, now);
+        let pricing = self.pricing();
+        let pay = amount.min(auction.debt);
+
+        // Collateral worth `pay`, grossed up by the discount; the bidder's side rounds down
+        let value = pricing.convert(Asset::Debt, pay, Asset::Collateral, Rounding::Down);
+        let bought = mul_div_down(value, WAD, WAD - discount);
+        if bought <= auction.collateral {
+            return (pay, bought);
+        }
+        let full_value = pricing.convert(
+            Asset::Collateral,
+            auction.collateral,
+            Asset::Debt,
+            Rounding::Up,
+        );
+        let pay = mul_div_up(full_value, WAD - discount, WAD).min(pay);
+        (pay, auction.collateral)
+    }
+
+    pub fn auction_status(&self, auction: &Auction, now: u64) -> AuctionStatus {
+        let (full_take_cost, full_take_collateral) = self.auction_quote(auction, u128::MAX, now);
+        AuctionStatus {
+            auction: auction.clone(),
+            discount: self.auction_discount(auction, now),
+            expired: now >= auction.started_at + AUCTION_DURATION,
+            full_take_cost,
+            full_take_collateral,
+        }
+    }
+
+    // Least TVARA a DEX swap of `collateral` VARA must return: its oracle value less the max
+    // slippage, rounded in the pool's favour
+    pub fn dex_min_out(&self, collateral: u128) -> u128 {
+        let value =
+            self.pricing()
+                .convert(Asset::Collateral, collateral, Asset::Debt, Rounding::Up);
+        mul_div_up(
+            value,
+            BPS_DENOMINATOR - self.max_slippage_bps,
+            BPS_DENOMINATOR,
+        )
+    }
+
+    // Bounty paid from the treasury for a public accrual
+    pub fn keeper_bounty(&self) -> u128 {
+        mul_div_down(self.treasury, KEEPER_BOUNTY_BPS, BPS_DENOMINATOR)
+    }
+
+    pub fn term_deposit_info(&self, deposit: &TermDeposit, now: u64) -> TermDepositInfo {
+        let matured = now >= deposit.unlocks_at;
+        TermDepositInfo {
+            deposit: deposit.clone(),
+            matured,
+            early_withdrawal_penalty: if matured {
+                0
+            } else {
+                mul_div_up(deposit.amount, EARLY_WITHDRAWAL_PENALTY, 100)
+            },
+        }
+    }
+
+    // Asserts `lender` has `amount` of principal not already queued for withdrawal
+    fn check_withdrawal_request(&self, lender: ActorId, amount: u128) {
+        assert!(amount > 0, "Withdrawal amount must be greater than zero");
+        let balance = *self.lender_balances.get(&lender).unwrap_or(&0);
+        let pending = *self.pending_withdrawals.get(&lender).unwrap_or(&0);
+        assert!(
+            balance >= pending + amount,
+            "Insufficient principal balance to withdraw"
+        );
+    }
+
+    // The term deposit `lender` would withdraw at `now`, after checking it can be withdrawn
+    fn term_deposit_withdrawal(
+        &self,
+        deposit_id: u64,
+        lender: ActorId,
+        now: u64,
+    ) -> TermDepositInfo {
+        let deposit = self
+            .term_deposits
+            .get(&deposit_id)
+            .expect("Term deposit not found");
+        assert!(deposit.lender == lender, "Not the deposit owner");
+        let info = self.term_deposit_info(deposit, now);
+        assert!(
+            self.total_liquidity >= info.deposit.amount,
+            "Insufficient total liquidity for principal withdrawal"
+        );
+        info
+    }
+
+    // Each lender's weight in the lender interest share: instant-access balances count at face
+    // value, term deposits scaled by their boost
+    pub fn lender_weights(&self) -> BTreeMap<ActorId, u128> {
+        let mut weights: BTreeMap<ActorId, u128> = self
+            .lender_balances
+            .iter()
+            .filter(|(_, balance)| **balance > 0)
+            .map(|(lender, balance)| (*lender, balance * 100))
+            .collect();
+        for deposit in self.term_deposits.values() {
+            *weights.entry(deposit.lender).or_default() += deposit.amount * deposit.boost;
+        }
+        weights
+    }
+
+    // Credits `amount` to lenders' earned interest pro rata to their weights and returns each
+    // lender's cut; rounding dust is not handed out
+    pub(crate) fn credit_lenders(&mut self, amount: u128) -> Vec<(ActorId, u128)> {
+        let weights = self.lender_weights();
+        let total_weight: u128 = weights.values().sum();
+        let mut credited = Vec::new();
+        if total_weight == 0 || amount == 0 {
+            return credited;
+        }
+        for (lender, weight) in weights {
+            let share = mul_div_down(weight, amount, total_weight);
+            if share > 0 {
+                *self.lender_interest_earned.entry(lender).or_default() += share;
+                credited.push((lender, share));
+            }
+        }
+        credited
+    }
+
+    // Checks accounting invariants; `native_balance` is the VARA the program actually holds
+    pub fn check_invariants(&self, native_balance: u128) -> InvariantReport {
+        let sum_debt: u128 = self.debt.values().sum();
+        // Collateral still held by the program, whether in positions or up for auction
+        let sum_collateral: u128 = self.collateral.values().sum::<u128>()
+            + self.auctions.values().map(|a| a.collateral).sum::<u128>();
+        // Where lender deposits are: idle, lent out, being recovered by auctions or swaps, or lost
+        let deposits_backing = self.total_liquidity
+            + self.total_principal_borrowed
+            + self.total_term_principal
+            + self.auctions.values().map(|a| a.debt).sum::<u128>()
+            + self.dex_swaps.values().map(|s| s.debt).sum::<u128>()
+            + self.bad_debt;
+        let sum_lender_balances: u128 = self.lender_balances.values().sum();
+        let sum_deposits = sum_lender_balances + self.total_term_deposits;
+        let sum_term_deposits: u128 = self.term_deposits.values().map(|d| d.amount).sum();
+        let sum_term_principal: u128 = self.term_loans.values().map(|l| l.principal).sum();
+        let sum_user_interest: u128 =
+            self.user_accrued_interest.values().sum::<u128>() + self.total_term_interest();
+        let sum_pending: u128 = self.pending_withdrawals.values().sum();
+        let sum_queued: u128 = self.withdrawal_queue.iter().map(|r| r.remaining).sum();
+        let mut borrowers: BTreeSet<&ActorId> = self.debt.keys().collect();
+        borrowers.extend(self.user_term_loans.keys());
+        borrowers.retain(|user| self.total_debt_of(user) > 0);
+        // Liquidity and treasury are debt-token units backed by VARA held 1:1
+        let pool_funds = self
+            .decimals
+            .debt_to_collateral(self.total_liquidity + self.treasury, Rounding::Up);
+
+        let check = |name: &str, expected: u128, actual: u128, holds: bool| InvariantCheck {
+            name: String::from(name),
+            expected,
+            actual,
+            holds,
+        };
+        let checks = vec![
+            check(
+                "total_principal_borrowed == sum(debt)",
+                sum_debt,
+                self.total_principal_borrowed,
+                sum_debt == self.total_principal_borrowed,
+            ),
+            check(
+                "total_term_principal == sum(term_loans.principal)",
+                sum_term_principal,
+                self.total_term_principal,
+                sum_term_principal == self.total_term_principal,
+            ),
+            check(
+                "total_lender_balances == sum(lender_balances)",
+                sum_lender_balances,
+                self.total_lender_balances,
+                sum_lender_balances == self.total_lender_balances,
+            ),
+            check(
+                "total_term_deposits == sum(term_deposits.amount)",
+                sum_term_deposits,
+                self.total_term_deposits,
+                sum_term_deposits == self.total_term_deposits,
+            ),
+            // Liquidations add seized collateral to liquidity, so this is a lower bound
+            check(
+                "liquidity + borrowed + auctioned + bad debt >= lender deposits",
+                sum_deposits,
+                deposits_backing,
+                deposits_backing >= sum_deposits,
+            ),
+            check(
+                "sum(accrued interest) <= total_interest_earned",
+                self.total_interest_earned,
+                sum_user_interest,
+                sum_user_interest <= self.total_interest_earned,
+            ),
+            check(
+                "native balance >= collateral + liquidity + treasury",
+                sum_collateral + pool_funds,
+                native_balance,
+                native_balance >= sum_collateral + pool_funds,
+            ),
+            check(
+                "health_index covers every borrower",
+                borrowers.len() as u128,
+                self.health_index.len() as u128,
+                self.health_index.len() == self.health_index_keys.len()
+                    && borrowers
+                        .iter()
+                        .all(|user| self.health_index_keys.contains_key(*user)),
+            ),
+            check(
+                "sum(pending_withdrawals) == sum(withdrawal_queue.remaining)",
+                sum_queued,
+                sum_pending,
+                sum_pending == sum_queued,
+            ),
+        ];
+
+        let all_hold = checks.iter().all(|check| check.holds);
+        InvariantReport { checks, all_hold }
+    }
+
+    // Refresh a user's position in the health index after their collateral or debt changed
+    pub(crate) fn reindex_health(&mut self, user: ActorId) {
+        if let Some(old_key) = self.health_index_keys.remove(&user) {
+            self.health_index.remove(&(old_key, user));
+        }
+
+        let collateral = *self.collateral.get(&user).unwrap_or(&0);
+        let total_debt = self.total_debt_of(&user);
+        if total_debt == 0 {
+            return; // Positions without debt can never be liquidated
+        }
+
+        let key = saturating_mul_div(
+            collateral,
+            self.decimals.debt_unit(),
+            total_debt,
+            Rounding::Down,
+        );
+        self.health_index.insert((key, user));
+        self.health_index_keys.insert(user, key);
+    }
+}
+
+// Simple interest on `principal` at the yearly `rate` (WAD) over `dt` seconds. Interest charged
+// to a borrower rounds up, interest the pool hands out rounds down.
+pub fn interest_for(principal: u128, rate: u128, dt: u64, rounding: Rounding) -> u128 {
+    let rate_over_period = rate.checked_mul(dt as u128).expect("Math overflow");
+    mul_div(
+        principal,
+        rate_over_period,
+        SECONDS_PER_YEAR * WAD,
+        rounding,
+    )
+}
+
+pub fn health_factor_of(
+    collateral_amount_vara: u128,
+    total_debt_tvara: u128,
+    pricing: Pricing,
+) -> u128 {
+    if total_debt_tvara == 0 {
+        return u128::MAX; // Loan is perfectly healthy if no debt
+    }
+
+    // Calculate the USD value of collateral, rounded down
+    let collateral_value_usd =
+        pricing.value_of(Asset::Collateral, collateral_amount_vara, Rounding::Down);
+
+    // Calculate the USD value of total TVARA debt at the debt token's own price, rounded up
+    let total_debt_value_usd = pricing.value_of(Asset::Debt, total_debt_tvara, Rounding::Up);
+
+    if total_debt_value_usd == 0 {
+        return u128::MAX;
+    }
+
+    // Health factor = (Collateral Value in USD * 100) / (Total Debt Value in USD)
+    mul_div_down(collateral_value_usd, 100, total_debt_value_usd)
+}
+
+// Amount `borrow` mints for the given collateral, and the cap on total debt (TVARA units)
+pub fn borrow_limits(collateral_amount: u128, pricing: Pricing) -> (u128, u128) {
+    // Convert collateral to value for LTV calculations (18 decimal precision)
+    let collateral_value = pricing.value_of(Asset::Collateral, collateral_amount, Rounding::Down);
+
+    let max_borrowable_value = mul_div_down(collateral_value, 100, 150); // 150% LTV cap
+    let borrow_value = mul_div_down(collateral_value, 66, 100); // ~66% safe LTV
+
+    // Convert borrow value back to TVARA units for debt tracking, rounding the loan down
+    let borrow_amount = pricing.amount_for(Asset::Debt, borrow_value, Rounding::Down);
+    let max_borrowable = pricing.amount_for(Asset::Debt, max_borrowable_value, Rounding::Down);
+
+    (borrow_amount, max_borrowable)
+}
+
+// Whether `total_debt` stays within the 150% LTV cap after collateral drops to `remaining_collateral`
+pub fn within_withdrawal_ltv(
+    remaining_collateral: u128,
+    total_debt: u128,
+    pricing: Pricing,
+) -> bool {
+    let remaining_collateral_value =
+        pricing.value_of(Asset::Collateral, remaining_collateral, Rounding::Down);
+    let total_current_debt_value = pricing.value_of(Asset::Debt, total_debt, Rounding::Up);
+
+    let max_allowed_debt_value = mul_div_down(remaining_collateral_value, 100, 150);
+
+    total_current_debt_value <= max_allowed_debt_value
+}
+
+impl<'a> Pool<'a> {
+    pub fn new(storage: &'a mut LendingStorage, ctx: Context) -> Self {
+        Self {
+            storage,
+            ctx,
+            effects: Vec::new(),
+            guarded: false,
+        }
+    }
+
+    pub fn storage(&self) -> &LendingStorage {
+        self.storage
+    }
+
+    // Finishes the operation. In strict mode a guarded operation that leaves any invariant
+    // broken panics here, so the whole message is rejected.
+    pub fn into_effects(self) -> Vec<Effect> {
+        if self.guarded && self.storage.strict_invariants {
+            let outgoing: u128 = self
+                .effects
+                .iter()
+                .map(|effect| match effect {
+                    Effect::Transfer { amount, .. } => *amount,
+                    _ => 0,
+                })
+                .sum();
+            let report = self
+                .storage
+                .check_invariants(self.ctx.balance.saturating_sub(outgoing));
+            if let Some(broken) = report.checks.iter().find(|check| !check.holds) {
+                panic!("Invariant violated: {}", broken.name);
+            }
+        }
+        self.effects
+    }
+
+    fn emit(&mut self, event: LendingEvent) {
+        self.effects.push(Effect::Event(event));
+    }
+
+    fn assert_admin(&self, message: &str) {
+        assert_eq!(self.ctx.caller, self.storage.admin, "{}", message);
+    }
+
+    // Credits a protocol fee in debt-token units to the treasury
+    fn charge_fee(&mut self, kind: FeeKind, user: ActorId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        self.storage.treasury += amount;
+        self.storage.total_fees_collected += amount;
+        self.emit(LendingEvent::FeeCharged(FeeCharged {
+            kind,
+            user,
+            amount,
+            new_treasury: self.storage.treasury,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Seizes `user`'s position and keeps the liquidation fee out of its collateral. Returns the
+    // collateral left to liquidate, the debt, the health factor and the term loans cleared.
+    fn seize_for_liquidation(&mut self, user: ActorId) -> (u128, u128, u128, Vec<u64>) {
+        let storage = &mut *self.storage;
+        let (collateral, debt, health, term_loans_cleared) =
+            storage.seize_position(user, self.ctx.timestamp);
+        let fee = mul_div_down(collateral, storage.liquidation_fee_bps, BPS_DENOMINATOR);
+        let fee_credit = storage.decimals.collateral_to_debt(fee, Rounding::Down);
+        self.charge_fee(FeeKind::Liquidation, user, fee_credit);
+        (collateral - fee, debt, health, term_loans_cleared)
+    }
+
+    pub fn accrue_interest(&mut self) {
+        let now = self.ctx.timestamp;
+        let dt = now.saturating_sub(self.storage.last_accrual_ts);
+        // Settled positions no longer accrue
+        if dt == 0 || self.storage.shutdown.is_some() {
+            return;
+        }
+        let storage = &mut *self.storage;
+        storage.last_accrual_ts = now;
+
+        let rate = storage.borrow_rate_per_year();
+        storage.borrow_yield_index += interest_for(WAD, rate, dt, Rounding::Down);
+
+        // Iterate over principal debts to accrue interest
+        let users_with_debt: Vec<ActorId> = storage.debt.keys().cloned().collect();
+
+        // Term loans accrue at the rate locked when they were originated
+        let mut term_loan_interest = Vec::new();
+        for loan in storage.term_loans.values_mut() {
+            let interest = interest_for(loan.principal, loan.rate, dt, Rounding::Up);
+            if interest > 0 {
+                loan.accrued_interest += interest;
+                term_loan_interest.push((loan.id, interest));
+            }
+        }
+        let term_interest_total: u128 = term_loan_interest.iter().map(|(_, i)| i).sum();
+        let term_borrowers: BTreeSet<ActorId> = term_loan_interest
+            .iter()
+            .filter_map(|(id, _)| storage.term_loans.get(id))
+            .map(|loan| loan.borrower)
+            .collect();
+        for user in term_borrowers {
+            storage.reindex_health(user);
+        }
+
+        // Calculate total new interest generated in this period
+        // This is based on total principal borrowed, plus what term loans were charged
+        let variable_interest =
+            interest_for(storage.total_principal_borrowed, rate, dt, Rounding::Down);
+        let total_new_interest_generated = variable_interest + term_interest_total;
+
+        if total_new_interest_generated > 0 {
+            // Distribute interest to treasury and lenders
+            let treasury_cut =
+                mul_div_down(total_new_interest_generated, TREASURY_INTEREST_SHARE, 100);
+            storage.treasury += treasury_cut;
+
+            let lender_share_total =
+                mul_div_down(total_new_interest_generated, LENDER_INTEREST_SHARE, 100);
+
+            let mut borrower_interest_list = Vec::new();
+
+            // Distribute lender share proportionally, term deposits weighted by their boost
+            let deposits = storage.total_lender_balances + storage.total_term_deposits;
+            let lender_interest = storage.credit_lenders(lender_share_total);
+            if deposits > 0 {
+                storage.supply_yield_index += mul_div_down(lender_share_total, WAD, deposits);
+            }
+
+            // The 6% total interest is applied to each borrower's debt.
+            for user in users_with_debt {
+                if let Some(&debt_amount) = storage.debt.get(&user) {
+                    if debt_amount == 0 || variable_interest == 0 {
+                        continue;
+                    }
+                    let borrower_interest = interest_for(debt_amount, rate, dt, Rounding::Up);
+                    if borrower_interest > 0 {
+                        *storage.user_accrued_interest.entry(user).or_default() +=
+                            borrower_interest;
+                        storage.reindex_health(user);
+                        borrower_interest_list.push((user, borrower_interest));
+                    }
+                }
+            }
+
+            // Borrower interest rounds up, so track what was actually charged rather than the
+            // pool-wide figure, which keeps sum(user_accrued_interest) <= total_interest_earned
+            let charged: u128 = borrower_interest_list.iter().map(|(_, i)| i).sum();
+            storage.total_interest_earned += charged + term_interest_total; // Total interest generated
+
+            let new_treasury = storage.treasury;
+            self.emit(LendingEvent::InterestAccrued(InterestAccrued {
+                borrow_rate: rate,
+                elapsed: dt,
+                total_interest: total_new_interest_generated,
+                treasury_cut,
+                new_treasury,
+                borrower_interest: charged,
+                term_loan_interest: term_interest_total,
+                lender_interest: lender_interest.iter().map(|(_, share)| share).sum(),
+                timestamp: now,
+            }));
+        }
+
+        self.storage.record_rate_checkpoint(now);
+    }
+
+    fn guard<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        assert!(self.storage.shutdown.is_none(), "Protocol is shut down");
+        self.accrue_interest();
+        self.guarded = true;
+        assert!(!self.storage.paused, "Protocol is paused");
+        assert!(!self.storage.reentrancy, "Reentrant call");
+        self.storage.reentrancy = true;
+        let res = f(self);
+        self.storage.reentrancy = false;
+        res
+    }
+
+    pub fn update_price(&mut self, asset: Asset, new_price: u128) {
+        self.assert_admin("Only admin can update price");
+        self.set_price(asset, new_price, self.ctx.timestamp);
+    }
+
+    // Accepts a price signed by at least `oracle_threshold` distinct publishers, for `timestamp`
+    // newer than the asset's last update. Every signature given must be valid.
+    pub fn submit_signed_price(
+        &mut self,
+        asset: Asset,
+        price: u128,
+        timestamp: u64,
+        signatures: Vec<PublisherSignature>,
+    ) {
+        let storage = &*self.storage;
+        assert!(
+            storage.oracle_threshold > 0,
+            "Signed price updates are disabled"
+        );
+        assert!(
+            timestamp <= self.ctx.timestamp,
+            "Price update is from the future"
+        );
+        let updated_at = match asset {
+            Asset::Collateral => storage.collateral_price_updated_at,
+            Asset::Debt => storage.debt_price_updated_at,
+        };
+        assert!(
+            timestamp > updated_at,
+            "Price update is older than the current price"
+        );
+
+        let payload = oracle::signing_payload(self.ctx.program, asset, price, timestamp);
+        let mut signers = BTreeSet::new();
+        for PublisherSignature {
+            public_key,
+            signature,
+        } in &signatures
+        {
+            let scheme = storage
+                .oracle_publishers
+                .get(public_key)
+                .expect("Unknown oracle publisher");
+            assert!(
+                oracle::verify(*scheme, public_key, &payload, signature),
+                "Invalid price signature"
+            );
+            assert!(signers.insert(public_key), "Duplicate publisher signature");
+        }
+        let signers = signers.len() as u32;
+        assert!(
+            signers >= storage.oracle_threshold,
+            "Not enough publisher signatures"
+        );
+
+        self.set_price(asset, price, timestamp);
+        self.emit(LendingEvent::SignedPriceAccepted(SignedPriceAccepted {
+            relayer: self.ctx.caller,
+            asset,
+            price,
+            signed_at: timestamp,
+            signers,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn set_oracle_publishers(&mut self, publishers: Vec<OraclePublisher>, threshold: u32) {
+        self.assert_admin("Only admin can change oracle publishers");
+        assert!(
+            publishers.len() <= MAX_ORACLE_PUBLISHERS,
+            "Too many oracle publishers"
+        );
+        let publishers: BTreeMap<[u8; 32], SignatureScheme> = publishers
+            .into_iter()
+            .map(|publisher| (publisher.public_key, publisher.scheme))
+            .collect();
+        assert!(
+            threshold as usize <= publishers.len(),
+            "Threshold exceeds the number of publishers"
+        );
+        assert!(
+            threshold > 0 || publishers.is_empty(),
+            "Threshold must be positive"
+        );
+        let count = publishers.len() as u32;
+        self.storage.oracle_publishers = publishers;
+        self.storage.oracle_threshold = threshold;
+
+        self.emit(LendingEvent::OraclePublishersChanged(
+            OraclePublishersChanged {
+                admin: self.ctx.caller,
+                publishers: count,
+                threshold,
+                timestamp: self.ctx.timestamp,
+            },
+        ));
+    }
+
+    // Sets `asset`'s price as of `updated_at` and records it for the TWAP
+    fn set_price(&mut self, asset: Asset, new_price: u128, updated_at: u64) {
+        assert!(new_price > 0, "Price must be positive");
+        let storage = &mut *self.storage;
+        let (price, price_updated_at) = match asset {
+            Asset::Collateral => (
+                &mut storage.collateral_price,
+                &mut storage.collateral_price_updated_at,
+            ),
+            Asset::Debt => (&mut storage.debt_price, &mut storage.debt_price_updated_at),
+        };
+        let old_price = core::mem::replace(price, new_price);
+        *price_updated_at = updated_at;
+        storage.record_price_observation(self.ctx.timestamp);
+
+        self.emit(LendingEvent::PriceUpdated(PriceUpdated {
+            asset,
+            old_price,
+            new_price,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn deposit_collateral(&mut self) {
+        let amount = self.ctx.value;
+        assert!(amount > 0, "Must send VARA tokens as collateral");
+        let user = self.ctx.caller;
+
+        let new_collateral = self.guard(|pool| {
+            let collateral = pool.storage.collateral.entry(user).or_default();
+            *collateral += amount;
+            let new_collateral = *collateral;
+            pool.storage.reindex_health(user);
+            pool.storage.record_account_action(
+                user,
+                AccountAction::CollateralDeposited,
+                amount,
+                pool.ctx.timestamp,
+            );
+            new_collateral
+        });
+
+        self.emit(LendingEvent::CollateralDeposited(CollateralDeposited {
+            user,
+            amount,
+            new_collateral,
+            new_health_factor: self.storage.health_factor(&user),
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Returns the amount of debt tokens minted to the caller
+    pub fn borrow(&mut self) -> u128 {
+        let user = self.ctx.caller;
+        let borrow_amount = self.guard(|pool| {
+            pool.serve_withdrawal_queue_first();
+            let storage = &mut *pool.storage;
+            let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
+            assert!(collateral_amount > 0, "No collateral deposited");
+
+            let (borrow_amount, max_borrowable) =
+                borrow_limits(collateral_amount, storage.pricing());
+
+            // When checking against max_borrowable, we need to sum principal debt AND accrued interest
+            let total_current_debt = storage.total_debt_of(&user);
+
+            assert!(
+                total_current_debt + borrow_amount <= max_borrowable,
+                "Exceeds maximum LTV ratio"
+            );
+            assert!(
+                borrow_amount <= storage.total_liquidity,
+                "Insufficient liquidity"
+            );
+
+            // Store new debt as principal
+            storage.settle_rewards(user, pool.ctx.timestamp);
+            *storage.debt.entry(user).or_default() += borrow_amount;
+            storage.total_principal_borrowed += borrow_amount; // Update total principal borrowed
+            storage.total_liquidity -= borrow_amount;
+            storage.reindex_health(user);
+            storage.record_account_action(
+                user,
+                AccountAction::Borrowed,
+                borrow_amount,
+                pool.ctx.timestamp,
+            );
+
+            borrow_amount
+        });
+        // The origination fee is owed like the rest of the borrow but never minted
+        let fee = mul_div_down(
+            borrow_amount,
+            self.storage.origination_fee_bps,
+            BPS_DENOMINATOR,
+        );
+        let mint_amount = borrow_amount - fee;
+
+        self.effects.push(Effect::Mint {
+            to: user,
+            amount: mint_amount,
+        });
+        self.charge_fee(FeeKind::Origination, user, fee);
+        self.emit(LendingEvent::Borrowed(Borrowed {
+            user,
+            amount: borrow_amount,
+            new_debt: *self.storage.debt.get(&user).unwrap_or(&0),
+            new_health_factor: self.storage.health_factor(&user),
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        mint_amount
+    }
+
+    // 'amount' repays principal. Interest is deducted from collateral when principal is 0.
+    // The caller must have burned `amount` debt tokens from `user` beforehand.
+    pub fn repay(&mut self, user: ActorId, amount: u128) {
+        let (collateral_to_return, debt_fully_paid, interest_deducted) = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            storage.settle_rewards(user, pool.ctx.timestamp);
+            let principal_debt_entry = storage.debt.entry(user).or_default();
+            let accrued_interest = *storage.user_accrued_interest.get(&user).unwrap_or(&0);
+            // Interest is settled from VARA collateral, rounded up in the pool's favour
+            let accrued_interest = storage
+                .decimals
+                .debt_to_collateral(accrued_interest, Rounding::Up);
+
+            let amount_repaid_principal = core::cmp::min(amount, *principal_debt_entry);
+
+            *principal_debt_entry -= amount_repaid_principal;
+            storage.total_principal_borrowed -= amount_repaid_principal; // Update total principal borrowed
+            storage.total_liquidity += amount_repaid_principal; // Principal repaid returns to liquidity
+
+            let mut collateral_to_return_val = 0;
+            let mut interest_deducted_val = 0;
+            let principal_debt_is_zero = *principal_debt_entry == 0;
+
+            // Only if principal debt is fully paid, handle interest deduction from collateral
+            if principal_debt_is_zero {
+                let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
+
+                if collateral_amount >= accrued_interest {
+                    // Collateral is sufficient to cover all accrued interest
+                    collateral_to_return_val = collateral_amount - accrued_interest;
+                    interest_deducted_val = accrued_interest;
+                    // Note: Treasury and lenders already got their share via accrue_interest.
+                    // This is simply the transfer from collateral to cover the accrued interest.
+                } else {
+                    // Collateral is less than accrued interest, deduct all available collateral
+                    interest_deducted_val = collateral_amount;
+                    collateral_to_return_val = 0;
+                }
+
+                // Clean up user's entries after full principal repayment and interest settlement
+                storage.collateral.remove(&user);
+                storage.debt.remove(&user); // Principal debt is zero, so remove entry
+                storage.user_accrued_interest.remove(&user); // Accrued interest has been settled
+
+                // Open term loans are still backed by the collateral, so it stays locked
+                if storage.user_term_loans.contains_key(&user) {
+                    if collateral_to_return_val > 0 {
+                        storage.collateral.insert(user, collateral_to_return_val);
+                    }
+                    collateral_to_return_val = 0;
+                }
+            }
+            storage.reindex_health(user);
+            let now = pool.ctx.timestamp;
+            storage.record_account_action(
+                user,
+                AccountAction::Repaid,
+                amount_repaid_principal,
+                now,
+            );
+            if interest_deducted_val > 0 {
+                storage.record_account_action(
+                    user,
+                    AccountAction::InterestSettled,
+                    interest_deducted_val,
+                    now,
+                );
+            }
+
+            (
+                collateral_to_return_val,
+                principal_debt_is_zero,
+                interest_deducted_val,
+            )
+        });
+
+        if debt_fully_paid && collateral_to_return > 0 {
+            self.effects.push(Effect::Transfer {
+                to: user,
+                amount: collateral_to_return,
+            });
+        }
+
+        self.emit(LendingEvent::Repaid(Repaid {
+            user,
+            amount, // This is the total VFT amount sent by user
+            collateral_to_return,
+            interest_deducted,
+            debt_fully_paid, // Indicates if principal debt reached zero
+            new_collateral: *self.storage.collateral.get(&user).unwrap_or(&0),
+            new_debt: *self.storage.debt.get(&user).unwrap_or(&0),
+            new_health_factor: self.storage.health_factor(&user),
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    // Borrows exactly `amount` for `term_days` at the current borrow rate, which stays fixed for
+    // the life of the loan. Returns the loan ID.
+    pub fn borrow_term(&mut self, amount: u128, term_days: u32) -> u64 {
+        assert!(amount > 0, "Borrow amount must be positive");
+        assert!(
+            (1..=MAX_TERM_DAYS).contains(&term_days),
+            "Unsupported loan term"
+        );
+        let user = self.ctx.caller;
+        let now = self.ctx.timestamp;
+
+        let loan = self.guard(|pool| {
+            pool.serve_withdrawal_queue_first();
+            let storage = &mut *pool.storage;
+            let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
+            assert!(collateral_amount > 0, "No collateral deposited");
+
+            let (_, max_borrowable) = borrow_limits(collateral_amount, storage.pricing());
+            assert!(
+                storage.total_debt_of(&user) + amount <= max_borrowable,
+                "Exceeds maximum LTV ratio"
+            );
+            assert!(amount <= storage.total_liquidity, "Insufficient liquidity");
+
+            let maturity = now + term_days as u64 * SECONDS_PER_DAY;
+            let loan = TermLoan {
+                id: storage.next_term_loan_id,
+                borrower: user,
+                principal: amount,
+                rate: storage.borrow_rate_per_year(),
+                accrued_interest: 0,
+                originated_at: now,
+                maturity,
+                grace_ends_at: maturity + TERM_LOAN_GRACE_PERIOD,
+            };
+            storage.next_term_loan_id += 1;
+            storage.term_loans.insert(loan.id, loan.clone());
+            storage
+                .user_term_loans
+                .entry(user)
+                .or_default()
+                .insert(loan.id);
+            storage.total_term_principal += amount;
+            storage.total_liquidity -= amount;
+            storage.reindex_health(user);
+            storage.record_account_action(user, AccountAction::TermLoanBorrowed, amount, now);
+
+            loan
+        });
+
+        let fee = mul_div_down(amount, self.storage.origination_fee_bps, BPS_DENOMINATOR);
+        self.effects.push(Effect::Mint {
+            to: user,
+            amount: amount - fee,
+        });
+        self.charge_fee(FeeKind::Origination, user, fee);
+        self.emit(LendingEvent::TermLoanOriginated(TermLoanOriginated {
+            loan_id: loan.id,
+            borrower: user,
+            amount,
+            rate: loan.rate,
+            maturity: loan.maturity,
+            grace_ends_at: loan.grace_ends_at,
+            new_health_factor: self.storage.health_factor(&user),
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: now,
+        }));
+        loan.id
+    }
+
+    // `amount` repays the loan's principal, at any time before or after maturity. Once the
+    // principal is zero the loan closes and its interest is taken from collateral, like `repay`.
+    // The caller must have burned `amount` debt tokens from the borrower beforehand.
+    pub fn repay_term_loan(&mut self, loan_id: u64, amount: u128) {
+        let (loan, repaid, interest_deducted, collateral_to_return) = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let loan = storage
+                .term_loans
+                .get_mut(&loan_id)
+                .expect("Term loan not found");
+            let user = loan.borrower;
+            let repaid = core::cmp::min(amount, loan.principal);
+            loan.principal -= repaid;
+            storage.total_term_principal -= repaid;
+            storage.total_liquidity += repaid;
+
+            let loan = loan.clone();
+            let mut interest_deducted = 0;
+            let mut collateral_to_return = 0;
+            if loan.principal == 0 {
+                storage.term_loans.remove(&loan_id);
+                let ids = storage.user_term_loans.entry(user).or_default();
+                ids.remove(&loan_id);
+                if ids.is_empty() {
+                    storage.user_term_loans.remove(&user);
+                }
+
+                // Interest is settled from VARA collateral, rounded up in the pool's favour
+                let collateral = storage.collateral.entry(user).or_default();
+                let interest = storage
+                    .decimals
+                    .debt_to_collateral(loan.accrued_interest, Rounding::Up);
+                interest_deducted = core::cmp::min(interest, *collateral);
+                *collateral -= interest_deducted;
+
+                // Nothing else owed, so the rest of the collateral goes back as `repay` does
+                if storage.total_debt_of(&user) == 0 {
+                    collateral_to_return = storage.collateral.remove(&user).unwrap_or(0);
+                    storage.debt.remove(&user);
+                    storage.user_accrued_interest.remove(&user);
+                }
+            }
+            storage.reindex_health(user);
+            let now = pool.ctx.timestamp;
+            storage.record_account_action(user, AccountAction::TermLoanRepaid, repaid, now);
+            if interest_deducted > 0 {
+                storage.record_account_action(
+                    user,
+                    AccountAction::InterestSettled,
+                    interest_deducted,
+                    now,
+                );
+            }
+
+            (loan, repaid, interest_deducted, collateral_to_return)
+        });
+
+        if collateral_to_return > 0 {
+            self.effects.push(Effect::Transfer {
+                to: loan.borrower,
+                amount: collateral_to_return,
+            });
+        }
+        self.emit(LendingEvent::TermLoanRepaid(TermLoanRepaid {
+            loan_id,
+            borrower: loan.borrower,
+            amount: repaid,
+            interest_deducted,
+            remaining_principal: loan.principal,
+            closed: loan.principal == 0,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    pub fn withdraw_collateral(&mut self, user: ActorId, amount: u128) {
+        let collateral_to_return = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let collateral_amount = *storage.collateral.get(&user).unwrap_or(&0);
+            let total_debt = storage.total_debt_of(&user);
+
+            assert!(collateral_amount >= amount, "Insufficient collateral");
+
+            let remaining_collateral = collateral_amount - amount;
+
+            // If there's any outstanding debt (principal or interest), check LTV
+            if total_debt > 0 {
+                assert!(
+                    within_withdrawal_ltv(remaining_collateral, total_debt, storage.pricing(),),
+                    "Withdrawal would exceed LTV ratio"
+                );
+            }
+
+            if remaining_collateral == 0 {
+                storage.collateral.remove(&user);
+            } else {
+                *storage.collateral.get_mut(&user).unwrap() = remaining_collateral;
+            }
+            storage.reindex_health(user);
+            storage.record_account_action(
+                user,
+                AccountAction::CollateralWithdrawn,
+                amount,
+                pool.ctx.timestamp,
+            );
+
+            amount
+        });
+
+        self.effects.push(Effect::Transfer {
+            to: user,
+            amount: collateral_to_return,
+        });
+        self.emit(LendingEvent::CollateralWithdrawn(CollateralWithdrawn {
+            user,
+            amount: collateral_to_return,
+            new_collateral: *self.storage.collateral.get(&user).unwrap_or(&0),
+            new_health_factor: self.storage.health_factor(&user),
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn lend(&mut self) {
+        let lender = self.ctx.caller;
+        let amount = self.ctx.value;
+        assert!(amount > 0, "Lend amount must be > 0");
+
+        let credited = self.guard(|pool| {
+            // Deposited VARA is credited 1:1 in debt-token units
+            let credited = pool
+                .storage
+                .decimals
+                .collateral_to_debt(amount, Rounding::Down);
+            assert!(credited > 0, "Lend amount must be > 0");
+            pool.storage.settle_rewards(lender, pool.ctx.timestamp);
+            *pool.storage.lender_balances.entry(lender).or_default() += credited;
+            pool.storage.total_lender_balances += credited;
+            pool.storage.total_liquidity += credited;
+            credited
+        });
+
+        // Mint VFT tokens equivalent to `amount`
+        self.effects.push(Effect::Mint {
+            to: lender,
+            amount: credited,
+        });
+        self.emit(LendingEvent::LiquidityProvided(LiquidityProvided {
+            lender,
+            amount: credited,
+            new_lender_balance: *self.storage.lender_balances.get(&lender).unwrap_or(&0),
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    pub fn withdraw(&mut self, amount: u128) {
+        let lender = self.ctx.caller;
+
+        let earned_interest_to_withdraw = self.guard(|pool| {
+            let balance = *pool.storage.lender_balances.get(&lender).unwrap_or(&0);
+            let pending = *pool.storage.pending_withdrawals.get(&lender).unwrap_or(&0);
+
+            // Amounts already queued are reserved for the queue
+            assert!(
+                balance >= amount + pending,
+                "Insufficient principal balance to withdraw"
+            );
+            pool.serve_withdrawal_queue_first();
+
+            let storage = &mut *pool.storage;
+            storage.settle_rewards(lender, pool.ctx.timestamp);
+            let bal = storage.lender_balances.entry(lender).or_default();
+            let earned_interest_bal = storage.lender_interest_earned.entry(lender).or_default();
+            assert!(
+                storage.total_liquidity >= amount,
+                "Insufficient total liquidity for principal withdrawal"
+            );
+
+            *bal -= amount;
+            storage.total_lender_balances -= amount;
+            storage.total_liquidity -= amount;
+
+            let earned_to_return = *earned_interest_bal;
+            *earned_interest_bal = 0; // Clear earned interest after withdrawal
+            earned_to_return
+        });
+
+        // Burn VFT tokens for the principal amount being withdrawn, then pay out principal + interest
+        // less the withdrawal fee, which stays with the treasury
+        self.effects.push(Effect::Burn {
+            from: lender,
+            amount,
+        });
+        let fee = mul_div_down(amount, self.storage.withdrawal_fee_bps, BPS_DENOMINATOR);
+        self.charge_fee(FeeKind::Withdrawal, lender, fee);
+        let payout = self
+            .storage
+            .decimals
+            .debt_to_collateral(amount - fee + earned_interest_to_withdraw, Rounding::Down);
+        self.effects.push(Effect::Transfer {
+            to: lender,
+            amount: payout,
+        });
+
+        self.emit(LendingEvent::LiquidityWithdrawn(LiquidityWithdrawn {
+            lender,
+            amount, // This event still refers to principal withdrawn
+            new_lender_balance: *self.storage.lender_balances.get(&lender).unwrap_or(&0),
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+
+        if earned_interest_to_withdraw > 0 {
+            self.emit(LendingEvent::InterestClaimed(InterestClaimed {
+                lender,
+                amount: earned_interest_to_withdraw,
+                timestamp: self.ctx.timestamp,
+            }));
+        }
+    }
+
+    // Locks the attached VARA as a term deposit; it is credited and minted like `lend` but earns
+    // a boosted interest share. Returns the deposit ID.
+    pub fn deposit_term(&mut self, tier: DepositTier) -> u64 {
+        let lender = self.ctx.caller;
+        let amount = self.ctx.value;
+        let now = self.ctx.timestamp;
+        assert!(amount > 0, "Lend amount must be > 0");
+
+        let deposit = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let credited = storage.decimals.collateral_to_debt(amount, Rounding::Down);
+            assert!(credited > 0, "Lend amount must be > 0");
+
+            let deposit = TermDeposit {
+                id: storage.next_term_deposit_id,
+                lender,
+                amount: credited,
+                tier,
+                boost: tier.boost(),
+                deposited_at: now,
+                unlocks_at: now + tier.lockup_days() as u64 * SECONDS_PER_DAY,
+            };
+            storage.next_term_deposit_id += 1;
+            storage.term_deposits.insert(deposit.id, deposit.clone());
+            storage
+                .lender_term_deposits
+                .entry(lender)
+                .or_default()
+                .insert(deposit.id);
+            storage.total_term_deposits += credited;
+            storage.total_liquidity += credited;
+            deposit
+        });
+
+        self.effects.push(Effect::Mint {
+            to: lender,
+            amount: deposit.amount,
+        });
+        self.emit(LendingEvent::TermDepositCreated(TermDepositCreated {
+            deposit_id: deposit.id,
+            lender,
+            amount: deposit.amount,
+            tier,
+            boost: deposit.boost,
+            unlocks_at: deposit.unlocks_at,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: now,
+        }));
+        self.fill_withdrawals();
+        deposit.id
+    }
+
+    // Runs `withdraw_term_deposit`'s checks without withdrawing. Returns the amount to burn from
+    // the lender before calling it.
+    pub fn prepare_withdraw_term_deposit(&mut self, deposit_id: u64) -> u128 {
+        let lender = self.ctx.caller;
+        let now = self.ctx.timestamp;
+        self.guard(|pool| {
+            pool.serve_withdrawal_queue_first();
+            pool.storage
+                .term_deposit_withdrawal(deposit_id, lender, now)
+                .deposit
+                .amount
+        })
+    }
+
+    // Withdraws a whole term deposit. Before it unlocks, `EARLY_WITHDRAWAL_PENALTY` percent is
+    // kept and credited to the remaining lenders (or the treasury if there are none).
+    // The caller must have burned the deposit's amount from the lender beforehand.
+    pub fn withdraw_term_deposit(&mut self, deposit_id: u64) {
+        let lender = self.ctx.caller;
+        let now = self.ctx.timestamp;
+
+        let (info, penalty_distribution) = self.guard(|pool| {
+            pool.serve_withdrawal_queue_first();
+            let storage = &mut *pool.storage;
+            let info = storage.term_deposit_withdrawal(deposit_id, lender, now);
+            let amount = info.deposit.amount;
+
+            storage.term_deposits.remove(&deposit_id);
+            let ids = storage.lender_term_deposits.entry(lender).or_default();
+            ids.remove(&deposit_id);
+            if ids.is_empty() {
+                storage.lender_term_deposits.remove(&lender);
+            }
+            storage.total_term_deposits -= amount;
+            storage.total_liquidity -= amount;
+
+            // The penalty stays in the program as VARA backing the lenders' earned interest
+            let penalty = info.early_withdrawal_penalty;
+            let penalty_distribution = storage.credit_lenders(penalty);
+            let distributed: u128 = penalty_distribution.iter().map(|(_, share)| share).sum();
+            storage.treasury += penalty - distributed;
+
+            (info, penalty_distribution)
+        });
+
+        let amount = info.deposit.amount;
+        let penalty = info.early_withdrawal_penalty;
+        let vara_sent = self
+            .storage
+            .decimals
+            .debt_to_collateral(amount - penalty, Rounding::Down);
+        self.effects.push(Effect::Transfer {
+            to: lender,
+            amount: vara_sent,
+        });
+        self.emit(LendingEvent::TermDepositWithdrawn(TermDepositWithdrawn {
+            deposit_id,
+            lender,
+            amount,
+            early: !info.matured,
+            penalty,
+            penalty_distribution,
+            vara_sent,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: now,
+        }));
+    }
+
+    pub fn claim_interest(&mut self) {
+        let lender = self.ctx.caller;
+        let earned_interest_to_claim = self.guard(|pool| {
+            let earned_interest_bal = pool
+                .storage
+                .lender_interest_earned
+                .entry(lender)
+                .or_default();
+            let amount = *earned_interest_bal;
+            assert!(amount > 0, "No interest to claim");
+            *earned_interest_bal = 0; // Reset balance after claiming
+            amount
+        });
+
+        let payout = self
+            .storage
+            .decimals
+            .debt_to_collateral(earned_interest_to_claim, Rounding::Down);
+        self.effects.push(Effect::Transfer {
+            to: lender,
+            amount: payout,
+        });
+        self.emit(LendingEvent::InterestClaimed(InterestClaimed {
+            lender,
+            amount: earned_interest_to_claim,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn liquidate(&mut self, user: ActorId) {
+        let (collateral_cleared_amount, debt_cleared_amount, _, term_loans_cleared) =
+            self.guard(|pool| {
+                assert!(
+                    pool.storage.liquidation_mode == LiquidationMode::Instant,
+                    "Liquidations go through auctions"
+                );
+                let seized = pool.seize_for_liquidation(user);
+                // Return VARA collateral to total liquidity
+                let storage = &mut *pool.storage;
+                storage.total_liquidity += storage
+                    .decimals
+                    .collateral_to_debt(seized.0, Rounding::Down);
+                seized
+            });
+
+        self.emit(LendingEvent::Liquidated(Liquidated {
+            user,
+            liquidator: self.ctx.caller,
+            collateral_sold: collateral_cleared_amount, // The amount of VARA collateral that was cleared
+            debt_cleared: debt_cleared_amount, // The total TVARA debt (principal + accrued interest) that was cleared
+            term_loans_cleared,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    pub fn set_liquidation_mode(&mut self, mode: LiquidationMode) {
+        self.assert_admin("Only admin can change the liquidation mode");
+        assert!(
+            mode != LiquidationMode::Dex || self.storage.dex_address.is_some(),
+            "DEX is not configured"
+        );
+        self.storage.liquidation_mode = mode;
+
+        self.emit(LendingEvent::LiquidationModeChanged(
+            LiquidationModeChanged {
+                admin: self.ctx.caller,
+                mode,
+                timestamp: self.ctx.timestamp,
+            },
+        ));
+    }
+
+    // Takes an unhealthy position off the borrower and puts its collateral up for auction
+    // against its total debt. Returns the auction ID.
+    pub fn kick_auction(&mut self, user: ActorId) -> u64 {
+        let now = self.ctx.timestamp;
+        let kicker = self.ctx.caller;
+        let (auction, health, term_loans_cleared) = self.guard(|pool| {
+            assert!(
+                pool.storage.liquidation_mode == LiquidationMode::Auction,
+                "Auctions are disabled"
+            );
+            let (collateral, debt, health, term_loans_cleared) = pool.seize_for_liquidation(user);
+            let storage = &mut *pool.storage;
+
+            let auction = Auction {
+                id: storage.next_auction_id,
+                user,
+                kicker,
+                collateral,
+                debt,
+                initial_collateral: collateral,
+                initial_debt: debt,
+                started_at: now,
+                restarts: 0,
+            };
+            storage.next_auction_id += 1;
+            storage.auctions.insert(auction.id, auction.clone());
+            (auction, health, term_loans_cleared)
+        });
+
+        self.emit(LendingEvent::AuctionKicked(AuctionKicked {
+            auction_id: auction.id,
+            user,
+            kicker,
+            collateral: auction.collateral,
+            debt: auction.debt,
+            health_factor: health,
+            term_loans_cleared,
+            timestamp: now,
+        }));
+        auction.id
+    }
+
+    // Quotes a bid of up to `amount` TVARA for an auction's collateral after running `take`'s
+    // checks. Returns the TVARA to burn from the bidder before calling `take`.
+    pub fn prepare_take(&mut self, auction_id: u64, amount: u128, min_collateral: u128) -> u128 {
+        let now = self.ctx.timestamp;
+        self.guard(|pool| {
+            let storage = &*pool.storage;
+            let auction = storage
+                .auctions
+                .get(&auction_id)
+                .expect("Auction not found");
+            let (pay, bought) = storage.auction_quote(auction, amount, now);
+            assert!(pay > 0 && bought > 0, "Bid buys nothing");
+            assert!(bought >= min_collateral, "Auction price is above the limit");
+            pay
+        })
+    }
+
+    // Buys auctioned collateral with `paid` TVARA the caller has already burned, at the current
+    // discount. TVARA the auction can no longer absorb (another bid got there first) is minted
+    // back, and so is the whole bid if it would now buy less than `min_collateral` VARA.
+    // Returns the VARA bought.
+    pub fn take(&mut self, auction_id: u64, paid: u128, min_collateral: u128) -> u128 {
+        let bidder = self.ctx.caller;
+        let now = self.ctx.timestamp;
+        let taken = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let auction = storage.auctions.get(&auction_id)?;
+            let discount = storage.auction_discount(auction, now);
+            let (pay, bought) = storage.auction_quote(auction, paid, now);
+            if bought < min_collateral {
+                return None;
+            }
+
+            let auction = storage.auctions.get_mut(&auction_id)?;
+            auction.debt -= pay;
+            auction.collateral -= bought;
+            let auction = auction.clone();
+            storage.total_liquidity += pay; // Recovered debt returns to liquidity
+            Some((pay, bought, discount, auction))
+        });
+
+        let (pay, bought) = taken
+            .as_ref()
+            .map_or((0, 0), |(pay, bought, ..)| (*pay, *bought));
+        let refunded = paid - pay;
+        if refunded > 0 {
+            self.effects.push(Effect::Mint {
+                to: bidder,
+                amount: refunded,
+            });
+        }
+        let Some((_, _, discount, auction)) = taken else {
+            return 0;
+        };
+        if bought > 0 {
+            self.effects.push(Effect::Transfer {
+                to: bidder,
+                amount: bought,
+            });
+        }
+        self.emit(LendingEvent::AuctionTaken(AuctionTaken {
+            auction_id,
+            bidder,
+            paid: pay,
+            collateral_bought: bought,
+            discount,
+            refunded,
+            remaining_collateral: auction.collateral,
+            remaining_debt: auction.debt,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: now,
+        }));
+
+        if auction.debt == 0 || auction.collateral == 0 {
+            self.close_auction(auction_id);
+        }
+        self.fill_withdrawals();
+        bought
+    }
+
+    // Restarts an auction that reached its max discount without selling out. Once it has been
+    // restarted `AUCTION_MAX_RESTARTS` times it is closed instead: the unsold collateral joins
+    // the liquidity and the debt it doesn't cover is written off as bad debt.
+    pub fn settle_auction(&mut self, auction_id: u64) {
+        let now = self.ctx.timestamp;
+        let restarted = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let auction = storage
+                .auctions
+                .get_mut(&auction_id)
+                .expect("Auction not found");
+            assert!(
+                now >= auction.started_at + AUCTION_DURATION,
+                "Auction is still running"
+            );
+            if auction.restarts == AUCTION_MAX_RESTARTS {
+                return None;
+            }
+            auction.restarts += 1;
+            auction.started_at = now;
+            Some(auction.user)
+        });
+        let Some(user) = restarted else {
+            self.write_off_auction(auction_id);
+            return;
+        };
+
+        self.emit(LendingEvent::AuctionSettled(AuctionSettled {
+            auction_id,
+            user,
+            outcome: AuctionOutcome::Restarted,
+            collateral_returned: 0,
+            bad_debt: 0,
+            timestamp: now,
+        }));
+    }
+
+    pub fn set_twap_window(&mut self, window: u64) {
+        self.assert_admin("Only admin can change the TWAP window");
+        assert!(window <= MAX_TWAP_WINDOW, "TWAP window is too long");
+        let old_window = core::mem::replace(&mut self.storage.twap_window, window);
+
+        self.emit(LendingEvent::TwapWindowChanged(TwapWindowChanged {
+            admin: self.ctx.caller,
+            old_window,
+            new_window: window,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn set_rate_checkpoint_period(&mut self, period: u64) {
+        self.assert_admin("Only admin can change the rate checkpoint period");
+        let old_period = core::mem::replace(&mut self.storage.rate_checkpoint_period, period);
+
+        self.emit(LendingEvent::RateCheckpointPeriodChanged(
+            RateCheckpointPeriodChanged {
+                admin: self.ctx.caller,
+                old_period,
+                new_period: period,
+                timestamp: self.ctx.timestamp,
+            },
+        ));
+    }
+
+    pub fn configure_dex(&mut self, dex: ActorId, max_slippage_bps: u128) {
+        self.assert_admin("Only admin can configure the DEX");
+        assert!(
+            max_slippage_bps <= MAX_SLIPPAGE_BPS,
+            "Max slippage is too high"
+        );
+        self.storage.dex_address = Some(dex);
+        self.storage.max_slippage_bps = max_slippage_bps;
+
+        self.emit(LendingEvent::DexConfigured(DexConfigured {
+            admin: self.ctx.caller,
+            dex,
+            max_slippage_bps,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Seizes an unhealthy position for a DEX sale. The caller sends the swap with the
+    // collateral attached and reports the outcome through `settle_dex_swap` or `fail_dex_swap`.
+    pub fn start_dex_liquidation(&mut self, user: ActorId) -> DexSwap {
+        let now = self.ctx.timestamp;
+        let liquidator = self.ctx.caller;
+        let (swap, health, term_loans_cleared) = self.guard(|pool| {
+            assert!(
+                pool.storage.liquidation_mode == LiquidationMode::Dex,
+                "DEX liquidations are disabled"
+            );
+            let (collateral, debt, health, term_loans_cleared) = pool.seize_for_liquidation(user);
+            let storage = &mut *pool.storage;
+
+            let swap = DexSwap {
+                id: storage.next_dex_swap_id,
+                user,
+                liquidator,
+                collateral,
+                debt,
+                min_out: storage.dex_min_out(collateral),
+                started_at: now,
+            };
+            storage.next_dex_swap_id += 1;
+            storage.dex_swaps.insert(swap.id, swap.clone());
+            (swap, health, term_loans_cleared)
+        });
+
+        self.emit(LendingEvent::DexLiquidationStarted(DexLiquidationStarted {
+            swap_id: swap.id,
+            user,
+            liquidator,
+            collateral: swap.collateral,
+            debt: swap.debt,
+            min_out: swap.min_out,
+            health_factor: health,
+            term_loans_cleared,
+            timestamp: now,
+        }));
+        swap
+    }
+
+    // Books the TVARA a swap returned to the program: the debt is burned from the proceeds, any
+    // surplus goes to the borrower and a shortfall is written off as bad debt. Not guarded, the
+    // swap has to be closed even if the pool was paused meanwhile.
+    pub fn settle_dex_swap(&mut self, swap_id: u64, proceeds: u128) {
+        let swap = self
+            .storage
+            .dex_swaps
+            .remove(&swap_id)
+            .expect("DEX swap not found");
+        let debt_burned = proceeds.min(swap.debt);
+        let surplus = proceeds - debt_burned;
+        let bad_debt = swap.debt - debt_burned;
+        self.storage.total_liquidity += debt_burned; // Recovered debt returns to liquidity
+        self.storage.bad_debt += bad_debt;
+
+        if debt_burned > 0 {
+            self.effects.push(Effect::Burn {
+                from: self.ctx.program,
+                amount: debt_burned,
+            });
+        }
+        if surplus > 0 {
+            self.effects.push(Effect::TokenTransfer {
+                to: swap.user,
+                amount: surplus,
+            });
+        }
+        self.emit(LendingEvent::DexLiquidationSettled(DexLiquidationSettled {
+            swap_id,
+            user: swap.user,
+            proceeds,
+            debt_burned,
+            surplus,
+            bad_debt,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    // Admin: settles a swap left pending, e.g. by a reply that couldn't be decoded, with the
+    // `proceeds` the program actually received. Only swaps older than `DEX_SWAP_RESOLVE_DELAY`
+    // qualify, so one still waiting for its reply can't be closed under it.
+    pub fn resolve_dex_swap(&mut self, swap_id: u64, proceeds: u128) {
+        self.assert_admin("Only admin can resolve DEX swaps");
+        let swap = self
+            .storage
+            .dex_swaps
+            .get(&swap_id)
+            .expect("DEX swap not found");
+        assert!(
+            self.ctx.timestamp >= swap.started_at + DEX_SWAP_RESOLVE_DELAY,
+            "DEX swap may still be settling"
+        );
+        self.settle_dex_swap(swap_id, proceeds);
+    }
+
+    // Puts the collateral of a rejected swap, refunded by the DEX, up for auction instead.
+    // Returns the auction ID.
+    pub fn fail_dex_swap(&mut self, swap_id: u64) -> u64 {
+        let storage = &mut *self.storage;
+        let swap = storage
+            .dex_swaps
+            .remove(&swap_id)
+            .expect("DEX swap not found");
+        let auction_id = storage.next_auction_id;
+        storage.next_auction_id += 1;
+        storage.auctions.insert(
+            auction_id,
+            Auction {
+                id: auction_id,
+                user: swap.user,
+                kicker: swap.liquidator,
+                collateral: swap.collateral,
+                debt: swap.debt,
+                initial_collateral: swap.collateral,
+                initial_debt: swap.debt,
+                started_at: self.ctx.timestamp,
+                restarts: 0,
+            },
+        );
+
+        self.emit(LendingEvent::DexLiquidationFailed(DexLiquidationFailed {
+            swap_id,
+            user: swap.user,
+            auction_id,
+            timestamp: self.ctx.timestamp,
+        }));
+        auction_id
+    }
+
+    // Closes an auction that has either recovered its debt or run out of collateral
+    fn write_off_auction(&mut self, auction_id: u64) {
+        let storage = &mut *self.storage;
+        let auction = storage
+            .auctions
+            .remove(&auction_id)
+            .expect("Auction not found");
+        // The VARA stays in the program, backing liquidity 1:1 like an instant liquidation's
+        let recovered = storage
+            .decimals
+            .collateral_to_debt(auction.collateral, Rounding::Down);
+        let bad_debt = auction.debt.saturating_sub(recovered);
+        storage.total_liquidity += recovered;
+        storage.bad_debt += bad_debt;
+
+        self.emit(LendingEvent::AuctionSettled(AuctionSettled {
+            auction_id,
+            user: auction.user,
+            outcome: AuctionOutcome::WrittenOff,
+            collateral_returned: 0,
+            bad_debt,
+            timestamp: self.ctx.timestamp,
+        }));
+        self.fill_withdrawals();
+    }
+
+    fn close_auction(&mut self, auction_id: u64) {
+        let Some(auction) = self.storage.auctions.remove(&auction_id) else {
+            return;
+        };
+        let outcome = if auction.debt == 0 {
+            if auction.collateral > 0 {
+                self.effects.push(Effect::Transfer {
+                    to: auction.user,
+                    amount: auction.collateral,
+                });
+            }
+            AuctionOutcome::Covered
+        } else {
+            self.storage.bad_debt += auction.debt;
+            AuctionOutcome::BadDebt
+        };
+
+        self.emit(LendingEvent::AuctionSettled(AuctionSettled {
+            auction_id,
+            user: auction.user,
+            outcome,
+            collateral_returned: if auction.debt == 0 {
+                auction.collateral
+            } else {
+                0
+            },
+            bad_debt: auction.debt,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Runs `request_withdrawal`'s checks without queueing anything, so the caller can burn
+    // `amount` from the lender knowing the request will be accepted
+    pub fn prepare_request_withdrawal(&mut self, amount: u128) {
+        let lender = self.ctx.caller;
+        self.guard(|pool| pool.storage.check_withdrawal_request(lender, amount));
+    }
+
+    // Joins the withdrawal queue and is paid as liquidity returns. Returns the request ID.
+    // The caller must have burned `amount` debt tokens from the lender beforehand.
+    pub fn request_withdrawal(&mut self, amount: u128) -> u64 {
+        let lender = self.ctx.caller;
+
+        let (request_id, position, amount_ahead) = self.guard(|pool| {
+            pool.storage.check_withdrawal_request(lender, amount);
+            let storage = &mut *pool.storage;
+            *storage.pending_withdrawals.entry(lender).or_default() += amount;
+
+            let request_id = storage.next_withdrawal_id;
+            storage.next_withdrawal_id += 1;
+            let amount_ahead = storage.withdrawal_queue.iter().map(|r| r.remaining).sum();
+            storage.withdrawal_queue.push_back(WithdrawalRequest {
+                id: request_id,
+                lender,
+                amount,
+                remaining: amount,
+                queued_at: pool.ctx.timestamp,
+            });
+            let position = storage.withdrawal_queue.len() as u32 - 1;
+            (request_id, position, amount_ahead)
+        });
+
+        self.emit(LendingEvent::WithdrawalQueued(WithdrawalQueued {
+            lender,
+            request_id,
+            amount,
+            position,
+            amount_ahead,
+            timestamp: self.ctx.timestamp,
+        }));
+        // Idle liquidity serves the request straight away
+        self.fill_withdrawals();
+        request_id
+    }
+
+    pub fn process_withdrawal_queue(&mut self) -> u32 {
+        self.guard(|pool| pool.fill_withdrawals())
+    }
+
+    // Queued requests have first call on idle liquidity, so anything else that takes liquidity
+    // serves them before it and fails while some are still waiting
+    fn serve_withdrawal_queue_first(&mut self) {
+        self.fill_withdrawals();
+        assert!(
+            self.storage.withdrawal_queue.is_empty(),
+            "Queued withdrawals are served first"
+        );
+    }
+
+    // Pays queued requests, oldest first, out of available liquidity
+    fn fill_withdrawals(&mut self) -> u32 {
+        let mut fills = 0;
+        while fills < MAX_WITHDRAWAL_FILLS && self.storage.total_liquidity > 0 {
+            let storage = &mut *self.storage;
+            let Some(request) = storage.withdrawal_queue.front_mut() else {
+                break;
+            };
+            let filled = request.remaining.min(storage.total_liquidity);
+            request.remaining -= filled;
+            let (request_id, lender, remaining) = (request.id, request.lender, request.remaining);
+            if remaining == 0 {
+                storage.withdrawal_queue.pop_front();
+            }
+
+            storage.total_liquidity -= filled;
+            storage.settle_rewards(lender, self.ctx.timestamp);
+            *storage.lender_balances.entry(lender).or_default() -= filled;
+            storage.total_lender_balances -= filled;
+            let pending = storage.pending_withdrawals.entry(lender).or_default();
+            *pending -= filled;
+            if *pending == 0 {
+                storage.pending_withdrawals.remove(&lender);
+            }
+            let fee = mul_div_down(filled, storage.withdrawal_fee_bps, BPS_DENOMINATOR);
+            let vara_sent = storage
+                .decimals
+                .debt_to_collateral(filled - fee, Rounding::Down);
+            fills += 1;
+            self.charge_fee(FeeKind::Withdrawal, lender, fee);
+
+            self.effects.push(Effect::Transfer {
+                to: lender,
+                amount: vara_sent,
+            });
+            let fill = WithdrawalFilled {
+                lender,
+                request_id,
+                filled,
+                vara_sent,
+                remaining,
+                new_liquidity: self.storage.total_liquidity,
+                timestamp: self.ctx.timestamp,
+            };
+            self.emit(if remaining == 0 {
+                LendingEvent::WithdrawalCompleted(fill)
+            } else {
+                LendingEvent::WithdrawalPartiallyFilled(fill)
+            });
+        }
+        fills as u32
+    }
+
+    // Public accrual. When `KEEPER_ACCRUAL_DELAY` seconds have passed since the last accrual the
+    // caller earns the keeper bounty, taken from the treasury after this accrual's cut is added.
+    // Returns the bounty.
+    pub fn accrue(&mut self) -> u128 {
+        let elapsed = self
+            .ctx
+            .timestamp
+            .saturating_sub(self.storage.last_accrual_ts);
+        let bounty = self.guard(|pool| {
+            if elapsed < KEEPER_ACCRUAL_DELAY {
+                return 0;
+            }
+            let bounty = pool.storage.keeper_bounty();
+            pool.storage.treasury -= bounty;
+            bounty
+        });
+        if bounty == 0 {
+            return 0;
+        }
+
+        let vara_sent = self
+            .storage
+            .decimals
+            .debt_to_collateral(bounty, Rounding::Down);
+        self.effects.push(Effect::Transfer {
+            to: self.ctx.caller,
+            amount: vara_sent,
+        });
+        self.emit(LendingEvent::KeeperRewarded(KeeperRewarded {
+            keeper: self.ctx.caller,
+            elapsed,
+            bounty,
+            vara_sent,
+            new_treasury: self.storage.treasury,
+            timestamp: self.ctx.timestamp,
+        }));
+        bounty
+    }
+
+    // Accrues like `accrue` (bounty included) and re-ranks the given positions in the health
+    // index. Returns each position's health factor.
+    pub fn poke(&mut self, users: Vec<ActorId>) -> Vec<(ActorId, u128)> {
+        assert!(!users.is_empty(), "No positions to poke");
+        assert!(users.len() <= MAX_POKE_USERS, "Too many positions to poke");
+        self.accrue();
+
+        let positions: Vec<(ActorId, u128)> = users
+            .into_iter()
+            .map(|user| {
+                self.storage.reindex_health(user);
+                (user, self.storage.health_factor(&user))
+            })
+            .collect();
+        self.emit(LendingEvent::PositionsPoked(PositionsPoked {
+            keeper: self.ctx.caller,
+            positions: positions.clone(),
+            timestamp: self.ctx.timestamp,
+        }));
+        positions
+    }
+
+    pub fn configure_checkpoints(&mut self, interval: u32, gas: u64, warning_threshold: u128) {
+        self.assert_admin("Only admin can configure checkpoints");
+        assert!(interval == 0 || gas > 0, "Checkpoint gas must be positive");
+        assert!(
+            warning_threshold >= LIQUIDATION_THRESHOLD,
+            "Warning threshold is below the liquidation threshold"
+        );
+        let storage = &mut *self.storage;
+        storage.checkpoint_interval = interval;
+        storage.checkpoint_gas = gas;
+        storage.health_warning_threshold = warning_threshold;
+        storage.checkpoint_epoch += 1; // Any checkpoint already in flight is now stale
+        let epoch = storage.checkpoint_epoch;
+
+        if interval > 0 {
+            self.effects.push(Effect::ScheduleCheckpoint {
+                epoch,
+                delay: interval,
+                reservation: None,
+            });
+        }
+        self.emit(LendingEvent::CheckpointsConfigured(CheckpointsConfigured {
+            admin: self.ctx.caller,
+            interval_blocks: interval,
+            gas_per_checkpoint: gas,
+            warning_threshold,
+            epoch,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Prepays `count` future checkpoints; expired reservations are dropped first so the
+    // durations only cover checkpoints that can still use them
+    pub fn reserve_checkpoint_gas(&mut self, count: u32) {
+        self.assert_admin("Only admin can reserve checkpoint gas");
+        assert!(
+            self.storage.checkpoint_interval > 0,
+            "Checkpoints are disabled"
+        );
+        assert!(count > 0, "Reservation count must be positive");
+
+        let block_height = self.ctx.block_height;
+        let storage = &mut *self.storage;
+        storage
+            .checkpoint_reservations
+            .retain(|reservation| reservation.expires_at > block_height);
+        let queued = storage.checkpoint_reservations.len() as u32;
+        for i in 0..count {
+            // Each reservation must outlive the checkpoints that will use the ones before it
+            let duration = storage
+                .checkpoint_interval
+                .saturating_mul(queued.saturating_add(i).saturating_add(2));
+            self.effects.push(Effect::ReserveCheckpointGas {
+                gas: storage.checkpoint_gas,
+                duration,
+            });
+        }
+
+        self.emit(LendingEvent::CheckpointGasReserved(CheckpointGasReserved {
+            admin: self.ctx.caller,
+            count,
+            gas_per_checkpoint: self.storage.checkpoint_gas,
+            reservations: queued.saturating_add(count),
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Accrues interest so views stay current, flags the least healthy positions below the
+    // warning threshold and schedules the next checkpoint while reserved gas lasts. Returns the
+    // number of positions flagged; a checkpoint from an earlier configuration does nothing.
+    pub fn checkpoint(&mut self, epoch: u64) -> u32 {
+        if epoch != self.storage.checkpoint_epoch || self.storage.checkpoint_interval == 0 {
+            return 0;
+        }
+        self.accrue_interest();
+        let now = self.ctx.timestamp;
+        self.storage.last_checkpoint_ts = now;
+
+        // The index is ordered by health, so stop at the first position above the threshold
+        let threshold = self.storage.health_warning_threshold;
+        let mut flagged = Vec::new();
+        for &(_, user) in self.storage.health_index.iter() {
+            let health = self.storage.health_factor(&user);
+            if health >= threshold || flagged.len() == MAX_HEALTH_WARNINGS {
+                break;
+            }
+            flagged.push(HealthWarning {
+                user,
+                health_factor: health,
+                collateral: *self.storage.collateral.get(&user).unwrap_or(&0),
+                total_debt: self.storage.total_debt_of(&user),
+                liquidatable: self.storage.liquidation_health_of(
+                    *self.storage.collateral.get(&user).unwrap_or(&0),
+                    self.storage.total_debt_of(&user),
+                    now,
+                ) < LIQUIDATION_THRESHOLD,
+                timestamp: now,
+            });
+        }
+        let warnings = flagged.len() as u32;
+        for warning in flagged {
+            self.emit(LendingEvent::HealthWarning(warning));
+        }
+
+        // The next run is paid from the oldest reservation still alive; expired ones are dropped
+        let block_height = self.ctx.block_height;
+        let reservations = &mut self.storage.checkpoint_reservations;
+        reservations.retain(|reservation| reservation.expires_at > block_height);
+        let reservation = reservations.pop_front();
+        let reservations_left = reservations.len() as u32;
+        let next_scheduled = reservation.is_some();
+        if let Some(reservation) = reservation {
+            self.effects.push(Effect::ScheduleCheckpoint {
+                epoch,
+                delay: self.storage.checkpoint_interval,
+                reservation: Some(reservation.id),
+            });
+        }
+        self.emit(LendingEvent::CheckpointRun(CheckpointRun {
+            epoch,
+            warnings,
+            next_scheduled,
+            reservations_left,
+            timestamp: now,
+        }));
+        if !next_scheduled {
+            self.emit(LendingEvent::CheckpointsStopped(CheckpointsStopped {
+                epoch,
+                last_checkpoint: now,
+                timestamp: now,
+            }));
+        }
+        warnings
+    }
+
+    pub fn set_strict_invariants(&mut self, enabled: bool) {
+        self.assert_admin("Only admin can change invariant checks");
+        self.storage.strict_invariants = enabled;
+
+        self.emit(LendingEvent::InvariantChecksChanged(
+            InvariantChecksChanged {
+                enabled,
+                timestamp: self.ctx.timestamp,
+            },
+        ));
+    }
+
+    pub fn set_fees(
+        &mut self,
+        origination_fee_bps: u128,
+        liquidation_fee_bps: u128,
+        withdrawal_fee_bps: u128,
+    ) {
+        self.assert_admin("Only admin can change fees");
+        assert!(
+            [origination_fee_bps, liquidation_fee_bps, withdrawal_fee_bps]
+                .iter()
+                .all(|&bps| bps <= MAX_FEE_BPS),
+            "Fee is too high"
+        );
+        let storage = &mut *self.storage;
+        storage.origination_fee_bps = origination_fee_bps;
+        storage.liquidation_fee_bps = liquidation_fee_bps;
+        storage.withdrawal_fee_bps = withdrawal_fee_bps;
+
+        self.emit(LendingEvent::FeesChanged(FeesChanged {
+            admin: self.ctx.caller,
+            origination_fee_bps,
+            liquidation_fee_bps,
+            withdrawal_fee_bps,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn configure_rewards(
+        &mut self,
+        token: ActorId,
+        payout: RewardPayout,
+        supply_rate: u128,
+        borrow_rate: u128,
+    ) {
+        self.assert_admin("Only admin can configure rewards");
+        let storage = &mut *self.storage;
+        assert!(
+            storage.reward_token.is_none_or(|current| current == token),
+            "Reward token is already set"
+        );
+        // Emissions so far accrue at the old rates
+        let now = self.ctx.timestamp;
+        (storage.supply_reward_index, storage.borrow_reward_index) = storage.reward_indexes_at(now);
+        storage.rewards_updated_at = now;
+        storage.reward_token = Some(token);
+        storage.reward_payout = payout;
+        storage.supply_reward_rate = supply_rate;
+        storage.borrow_reward_rate = borrow_rate;
+
+        self.emit(LendingEvent::RewardsConfigured(RewardsConfigured {
+            admin: self.ctx.caller,
+            token,
+            payout,
+            supply_rate,
+            borrow_rate,
+            timestamp: now,
+        }));
+    }
+
+    // Pays the caller everything their lending and borrowing has earned. Returns the amount.
+    pub fn claim_rewards(&mut self) -> u128 {
+        let user = self.ctx.caller;
+        let (token, amount) = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            let token = storage.reward_token.expect("Rewards are not configured");
+            storage.settle_rewards(user, pool.ctx.timestamp);
+            let amount = storage.unclaimed_rewards.remove(&user).unwrap_or(0);
+            assert!(amount > 0, "No rewards to claim");
+            storage.total_rewards_claimed += amount;
+            (token, amount)
+        });
+
+        self.effects.push(Effect::Reward {
+            token,
+            to: user,
+            amount,
+            payout: self.storage.reward_payout,
+        });
+        self.emit(LendingEvent::RewardsClaimed(RewardsClaimed {
+            user,
+            token,
+            amount,
+            timestamp: self.ctx.timestamp,
+        }));
+        amount
+    }
+
+    pub fn set_account_history(&mut self, enabled: bool) {
+        self.assert_admin("Only admin can change account history");
+        self.storage.account_history_enabled = enabled;
+
+        self.emit(LendingEvent::AccountHistoryChanged(AccountHistoryChanged {
+            enabled,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn pause(&mut self) {
+        self.assert_admin("Only admin can pause");
+        self.storage.paused = true;
+
+        self.emit(LendingEvent::Paused(PauseChanged {
+            admin: self.ctx.caller,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn resume(&mut self) {
+        self.assert_admin("Only admin can resume");
+        self.storage.paused = false;
+
+        self.emit(LendingEvent::Resumed(PauseChanged {
+            admin: self.ctx.caller,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn admin_withdraw_funds(&mut self, amount_tvara: u128) {
+        let vara_to_send = self.guard(|pool| {
+            pool.assert_admin("Only admin can withdraw funds");
+            pool.serve_withdrawal_queue_first();
+            let storage = &mut *pool.storage;
+            assert!(
+                amount_tvara > 0,
+                "Withdrawal amount must be greater than zero"
+            );
+            assert!(
+                storage.total_liquidity >= amount_tvara,
+                "Insufficient total liquidity for withdrawal"
+            );
+
+            // Liquidity is backed 1:1 by VARA the program holds, whatever the prices
+            let vara_to_send = storage
+                .decimals
+                .debt_to_collateral(amount_tvara, Rounding::Down);
+
+            storage.total_liquidity -= amount_tvara;
+            vara_to_send
+        });
+
+        self.effects.push(Effect::Transfer {
+            to: self.ctx.caller,
+            amount: vara_to_send,
+        });
+        self.emit(LendingEvent::AdminFundsWithdrawn(AdminFundsWithdrawn {
+            admin: self.ctx.caller,
+            amount: amount_tvara,
+            vara_sent: vara_to_send,
+            new_liquidity: self.storage.total_liquidity,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    pub fn admin_withdraw_treasury(&mut self, amount_tvara: u128) {
+        let vara_to_send = self.guard(|pool| {
+            pool.assert_admin("Only admin can withdraw treasury funds");
+            let storage = &mut *pool.storage;
+            assert!(
+                amount_tvara > 0,
+                "Withdrawal amount must be greater than zero"
+            );
+            assert!(
+                storage.treasury >= amount_tvara,
+                "Insufficient treasury balance for withdrawal"
+            );
+
+            // The treasury is backed 1:1 by VARA the program holds, whatever the prices
+            let vara_to_send = storage
+                .decimals
+                .debt_to_collateral(amount_tvara, Rounding::Down);
+
+            storage.treasury -= amount_tvara;
+            vara_to_send
+        });
+
+        self.effects.push(Effect::Transfer {
+            to: self.ctx.caller,
+            amount: vara_to_send,
+        });
+        self.emit(LendingEvent::TreasuryWithdrawn(TreasuryWithdrawn {
+            admin: self.ctx.caller,
+            amount: amount_tvara,
+            vara_sent: vara_to_send,
+            new_treasury: self.storage.treasury,
+            timestamp: self.ctx.timestamp,
+        }));
+    }
+
+    // Winds the protocol down for good at the given final prices. Each borrower's debt is paid
+    // from their collateral, the rest becoming their claim; lenders then share everything else
+    // the program holds, treasury included, pro rata to their deposits and unclaimed interest.
+    pub fn shutdown(&mut self, collateral_price: u128, debt_price: u128) {
+        self.assert_admin("Only admin can shut down");
+        assert!(
+            self.storage.shutdown.is_none(),
+            "Protocol is already shut down"
+        );
+        assert!(!self.storage.reentrancy, "Reentrant call");
+        // Their VARA is in flight or owed to bidders, so it can't be shared out yet
+        assert!(
+            self.storage.auctions.is_empty() && self.storage.dex_swaps.is_empty(),
+            "Auctions and DEX swaps must be settled before shutdown"
+        );
+        // Interest up to now is owed, then positions are valued at the final prices
+        self.accrue_interest();
+        let now = self.ctx.timestamp;
+        self.set_price(Asset::Collateral, collateral_price, now);
+        self.set_price(Asset::Debt, debt_price, now);
+
+        let storage = &mut *self.storage;
+        let pricing = storage.pricing();
+        let mut borrowers: BTreeSet<ActorId> = storage.collateral.keys().copied().collect();
+        borrowers.extend(storage.debt.keys());
+        borrowers.extend(storage.user_term_loans.keys());
+        let (mut uncovered_debt, mut collateral_claims) = (0, 0);
+        let mut settled = Vec::new();
+        for &user in &borrowers {
+            let collateral = *storage.collateral.get(&user).unwrap_or(&0);
+            let debt = storage.total_debt_of(&user);
+            let owed = pricing.convert(Asset::Debt, debt, Asset::Collateral, Rounding::Up);
+            if owed > collateral {
+                uncovered_debt += debt.saturating_sub(pricing.convert(
+                    Asset::Collateral,
+                    collateral,
+                    Asset::Debt,
+                    Rounding::Down,
+                ));
+            }
+            let excess = collateral.saturating_sub(owed);
+            settled.push((user, collateral - excess));
+            if excess > 0 {
+                storage.shutdown_collateral_claims.insert(user, excess);
+                collateral_claims += excess;
+            }
+        }
+
+        // Every deposit becomes a claim on what the program holds beyond borrower claims
+        let mut deposits = storage.lender_balances.clone();
+        for (lender, interest) in &storage.lender_interest_earned {
+            *deposits.entry(*lender).or_default() += interest;
+        }
+        for deposit in storage.term_deposits.values() {
+            *deposits.entry(deposit.lender).or_default() += deposit.amount;
+        }
+        let lender_deposits: u128 = deposits.values().sum();
+        let lender_assets = self.ctx.balance.saturating_sub(collateral_claims);
+        for (lender, deposit) in deposits {
+            if deposit == 0 {
+                continue;
+            }
+            let share = mul_div_down(lender_assets, deposit, lender_deposits);
+            if share > 0 {
+                storage.shutdown_lender_claims.insert(lender, share);
+            }
+        }
+
+        // Positions are now claims
+        storage.collateral.clear();
+        storage.debt.clear();
+        storage.user_accrued_interest.clear();
+        storage.term_loans.clear();
+        storage.user_term_loans.clear();
+        storage.total_principal_borrowed = 0;
+        storage.total_term_principal = 0;
+        storage.health_index.clear();
+        storage.health_index_keys.clear();
+        storage.lender_balances.clear();
+        storage.total_lender_balances = 0;
+        storage.lender_interest_earned.clear();
+        storage.term_deposits.clear();
+        storage.lender_term_deposits.clear();
+        storage.total_term_deposits = 0;
+        storage.withdrawal_queue.clear();
+        storage.pending_withdrawals.clear();
+        storage.total_liquidity = 0;
+        storage.treasury = 0;
+        for (user, taken) in settled {
+            storage.record_account_action(user, AccountAction::ShutdownSettled, taken, now);
+        }
+
+        let status = ShutdownStatus {
+            timestamp: now,
+            collateral_price,
+            debt_price,
+            positions_settled: borrowers.len() as u32,
+            uncovered_debt,
+            collateral_claims,
+            lender_assets,
+            lender_deposits,
+            collateral_claimed: 0,
+            lender_assets_claimed: 0,
+        };
+        storage.shutdown = Some(status.clone());
+
+        self.emit(LendingEvent::ShutdownTriggered(ShutdownTriggered {
+            admin: self.ctx.caller,
+            status,
+        }));
+    }
+
+    // Pays out the caller's shutdown claims, collateral and lender share alike
+    pub fn claim_shutdown(&mut self) -> ShutdownClaim {
+        let user = self.ctx.caller;
+        let storage = &mut *self.storage;
+        let status = storage
+            .shutdown
+            .as_mut()
+            .expect("Protocol is not shut down");
+        let claim = ShutdownClaim {
+            collateral: storage
+                .shutdown_collateral_claims
+                .remove(&user)
+                .unwrap_or(0),
+            lender_share: storage.shutdown_lender_claims.remove(&user).unwrap_or(0),
+        };
+        assert!(
+            claim.collateral + claim.lender_share > 0,
+            "Nothing to claim"
+        );
+        status.collateral_claimed += claim.collateral;
+        status.lender_assets_claimed += claim.lender_share;
+
+        self.effects.push(Effect::Transfer {
+            to: user,
+            amount: claim.collateral + claim.lender_share,
+        });
+        self.emit(LendingEvent::ShutdownClaimed(ShutdownClaimed {
+            user,
+            collateral: claim.collateral,
+            lender_share: claim.lender_share,
+            timestamp: self.ctx.timestamp,
+        }));
+        claim
+    }
+
+    pub fn set_treasury_beneficiaries(&mut self, beneficiaries: Vec<TreasuryBeneficiary>) {
+        self.assert_admin("Only admin can change treasury beneficiaries");
+        assert!(
+            beneficiaries.len() <= MAX_TREASURY_BENEFICIARIES,
+            "Too many treasury beneficiaries"
+        );
+        let accounts: BTreeSet<ActorId> = beneficiaries.iter().map(|b| b.account).collect();
+        assert!(
+            accounts.len() == beneficiaries.len(),
+            "Duplicate treasury beneficiary"
+        );
+        assert!(
+            beneficiaries.iter().all(|b| b.weight_bps > 0),
+            "Beneficiary weight must be positive"
+        );
+        let total_weight: u128 = beneficiaries.iter().map(|b| b.weight_bps).sum();
+        assert!(
+            beneficiaries.is_empty() || total_weight == BPS_DENOMINATOR,
+            "Beneficiary weights must sum to 10000 bps"
+        );
+        self.storage.treasury_beneficiaries = beneficiaries.clone();
+
+        self.emit(LendingEvent::TreasuryBeneficiariesChanged(
+            TreasuryBeneficiariesChanged {
+                admin: self.ctx.caller,
+                beneficiaries,
+                timestamp: self.ctx.timestamp,
+            },
+        ));
+    }
+
+    // Pays each beneficiary its weight of the whole treasury; rounding dust stays behind
+    pub fn distribute_treasury(&mut self) -> u128 {
+        let payouts = self.guard(|pool| {
+            let storage = &mut *pool.storage;
+            assert!(
+                !storage.treasury_beneficiaries.is_empty(),
+                "No treasury beneficiaries"
+            );
+            assert!(storage.treasury > 0, "Treasury is empty");
+            let treasury = storage.treasury;
+            let mut payouts = Vec::new();
+            for beneficiary in &storage.treasury_beneficiaries {
+                let amount = mul_div_down(treasury, beneficiary.weight_bps, BPS_DENOMINATOR);
+                if amount == 0 {
+                    continue;
+                }
+                storage.treasury -= amount;
+                let total = storage
+                    .treasury_distributed
+                    .entry(beneficiary.account)
+                    .or_default();
+                *total += amount;
+                payouts.push((beneficiary.account, amount, *total));
+            }
+            payouts
+        });
+
+        let mut distributed = 0;
+        for (beneficiary, amount, total_distributed) in payouts {
+            // Paid in VARA 1:1, like `admin_withdraw_treasury`
+            let vara_sent = self
+                .storage
+                .decimals
+                .debt_to_collateral(amount, Rounding::Down);
+            self.effects.push(Effect::Transfer {
+                to: beneficiary,
+                amount: vara_sent,
+            });
+            self.emit(LendingEvent::TreasuryPaidOut(TreasuryPaidOut {
+                beneficiary,
+                amount,
+                vara_sent,
+                total_distributed,
+                timestamp: self.ctx.timestamp,
+            }));
+            distributed += amount;
+        }
+        distributed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const ADMIN: u64 = 1;
+    const LENDER: u64 = 2;
+    const BORROWER: u64 = 3;
+    const LIQUIDATOR: u64 = 4;
+    const DEPOSITOR: u64 = 5;
+    const DEX: u64 = 6;
+    const PROGRAM: u64 = 100;
+    const UNIT: u128 = 10u128.pow(VARA_DECIMALS as u32);
+
+    fn ctx(caller: u64, value: u128, timestamp: u64) -> Context {
+        Context {
+            caller: caller.into(),
+            value,
+            timestamp,
+            block_height: 0,
+            balance: u128::MAX,
+            program: PROGRAM.into(),
+        }
+    }
+
+    fn run<R>(
+        storage: &mut LendingStorage,
+        ctx: Context,
+        f: impl FnOnce(&mut Pool) -> R,
+    ) -> (R, Vec<Effect>) {
+        let mut pool = Pool::new(storage, ctx);
+        let res = f(&mut pool);
+        (res, pool.into_effects())
+    }
+
+    // Pool with `liquidity` lent and a borrower holding `collateral` and an open loan
+    fn pool_with_loan(liquidity: u128, collateral: u128) -> LendingStorage {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(LENDER, liquidity, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, collateral, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+        storage
+    }
+
+    fn transfers(effects: &[Effect]) -> Vec<(ActorId, u128)> {
+        effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::Transfer { to, amount } => Some((*to, *amount)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn borrow_mints_66_percent_of_collateral() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+
+        let (minted, effects) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+
+        assert_eq!(minted, UNIT * 66 / 100);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Mint { to, amount } if *to == BORROWER.into() && *amount == minted
+        )));
+        assert_eq!(storage.debt.get(&BORROWER.into()), Some(&minted));
+        assert_eq!(storage.total_liquidity, 10 * UNIT - minted);
+        assert_eq!(storage.total_principal_borrowed, minted);
+    }
+
+    #[test]
+    fn six_decimal_debt_token_is_valued_like_a_twelve_decimal_one() {
+        let debt_unit = 1_000_000;
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::new(12, 6));
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        assert_eq!(storage.total_liquidity, 10 * debt_unit);
+
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        let (minted, _) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+
+        assert_eq!(minted, debt_unit * 66 / 100);
+        assert_eq!(
+            storage.health_factor(&BORROWER.into()),
+            health_factor_of(
+                UNIT,
+                UNIT * 66 / 100,
+                Pricing {
+                    collateral_price: WAD,
+                    debt_price: WAD,
+                    decimals: AssetDecimals::default(),
+                }
+            )
+        );
+
+        // Withdrawing pays the lender back in VARA units
+        let (_, effects) = run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.withdraw(debt_unit)
+        });
+        assert_eq!(transfers(&effects), [(LENDER.into(), UNIT)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient liquidity")]
+    fn borrow_without_liquidity_fails() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol is paused")]
+    fn paused_pool_rejects_deposits() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| pool.pause());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+    }
+
+    #[test]
+    fn full_repay_deducts_interest_from_returned_collateral() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let debt = storage.debt[&BORROWER.into()];
+
+        // A year later the borrower repays the whole principal
+        let year = SECONDS_PER_YEAR as u64;
+        let (_, effects) = run(&mut storage, ctx(BORROWER, 0, year), |pool| {
+            pool.repay(BORROWER.into(), debt)
+        });
+
+        let interest = storage.total_interest_earned;
+        assert!(interest > 0, "A year of borrowing should accrue interest");
+        assert_eq!(transfers(&effects), [(BORROWER.into(), UNIT - interest)]);
+        assert!(!storage.debt.contains_key(&BORROWER.into()));
+        assert!(!storage.collateral.contains_key(&BORROWER.into()));
+        assert_eq!(storage.total_principal_borrowed, 0);
+    }
+
+    #[test]
+    fn liquidation_after_price_drop_clears_position() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 2)
+        });
+        assert!(storage.health_factor(&BORROWER.into()) < LIQUIDATION_THRESHOLD);
+
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+
+        assert!(!storage.debt.contains_key(&BORROWER.into()));
+        assert!(!storage.collateral.contains_key(&BORROWER.into()));
+        assert!(storage.health_index.is_empty());
+        assert_eq!(storage.total_principal_borrowed, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position not eligible for liquidation")]
+    fn healthy_position_cannot_be_liquidated() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+    }
+
+    #[test]
+    fn debt_token_depeg_lowers_health() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let healthy = storage.health_factor(&BORROWER.into());
+        assert!(healthy >= LIQUIDATION_THRESHOLD);
+
+        // TVARA trading at 2 USD doubles the value of every loan
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Debt, 2 * WAD)
+        });
+        let depegged = storage.health_factor(&BORROWER.into());
+        assert!(depegged.abs_diff(healthy / 2) <= 1);
+        assert!(depegged < LIQUIDATION_THRESHOLD);
+
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+        assert!(!storage.debt.contains_key(&BORROWER.into()));
+    }
+
+    #[test]
+    fn withdrawal_queue_is_filled_from_repayments_and_deposits() {
+        // The borrower takes every unit of liquidity
+        let mut storage = pool_with_loan(UNIT * 66 / 100, UNIT);
+        assert_eq!(storage.total_liquidity, 0);
+
+        let (request_id, effects) = run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.request_withdrawal(UNIT / 2)
+        });
+        assert!(transfers(&effects).is_empty());
+        assert_eq!(storage.pending_withdrawals[&LENDER.into()], UNIT / 2);
+
+        // A partial repayment is routed to the queue
+        let (_, effects) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| {
+            pool.repay(BORROWER.into(), UNIT / 5)
+        });
+        assert_eq!(transfers(&effects), [(LENDER.into(), UNIT / 5)]);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::WithdrawalPartiallyFilled(fill))
+                if fill.request_id == request_id && fill.remaining == UNIT / 2 - UNIT / 5
+        )));
+        assert_eq!(storage.total_liquidity, 0);
+
+        // A new deposit completes it and the rest stays as liquidity
+        let other_lender = 5;
+        let (_, effects) = run(&mut storage, ctx(other_lender, UNIT, 0), |pool| pool.lend());
+        assert_eq!(transfers(&effects), [(LENDER.into(), UNIT / 2 - UNIT / 5)]);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::WithdrawalCompleted(fill)) if fill.request_id == request_id
+        )));
+        assert!(storage.withdrawal_queue.is_empty());
+        assert!(storage.pending_withdrawals.is_empty());
+        assert_eq!(storage.total_liquidity, UNIT - (UNIT / 2 - UNIT / 5));
+        assert_eq!(
+            storage.lender_balances[&LENDER.into()],
+            UNIT * 66 / 100 - UNIT / 2
+        );
+    }
+
+    #[test]
+    fn queued_withdrawals_are_served_before_new_borrows() {
+        // More requests than one fill pass serves, all waiting on an empty pool
+        let mut storage = pool_with_loan(UNIT * 66 / 100, UNIT);
+        let requests = MAX_WITHDRAWAL_FILLS as u128 + 1;
+        run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            for _ in 0..requests {
+                pool.request_withdrawal(UNIT / 100);
+            }
+        });
+        run(&mut storage, ctx(DEPOSITOR, 10 * UNIT, 0), |pool| {
+            pool.lend()
+        });
+        assert_eq!(storage.withdrawal_queue.len(), 1);
+
+        // The last request is paid before the new loan takes any liquidity
+        let other_borrower = 7;
+        run(&mut storage, ctx(other_borrower, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        let (_, effects) = run(&mut storage, ctx(other_borrower, 0, 0), |pool| {
+            pool.borrow()
+        });
+        assert_eq!(transfers(&effects), [(LENDER.into(), UNIT / 100)]);
+        assert!(storage.withdrawal_queue.is_empty());
+        assert!(storage.pending_withdrawals.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient principal balance to withdraw")]
+    fn over_requested_withdrawal_is_rejected_before_the_burn() {
+        let mut storage = pool_with_loan(UNIT, UNIT);
+        run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.prepare_request_withdrawal(UNIT + 1)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient principal balance to withdraw")]
+    fn queued_amount_cannot_be_withdrawn_again() {
+        let mut storage = pool_with_loan(UNIT, UNIT);
+        run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.request_withdrawal(UNIT)
+        });
+        run(&mut storage, ctx(LENDER, 0, 0), |pool| pool.withdraw(1));
+    }
+
+    #[test]
+    fn term_loan_keeps_its_rate_and_settles_interest_from_collateral() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        let (loan_id, effects) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| {
+            pool.borrow_term(UNIT / 2, 365)
+        });
+        let locked_rate = storage.term_loans[&loan_id].rate;
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Mint { to, amount } if *to == BORROWER.into() && *amount == UNIT / 2
+        )));
+
+        // A variable-rate borrower pushes utilization up; the term loan's rate doesn't move
+        run(&mut storage, ctx(LIQUIDATOR, 10 * UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| pool.borrow());
+        assert!(storage.borrow_rate_per_year() > locked_rate);
+
+        let year = SECONDS_PER_YEAR as u64;
+        let (_, effects) = run(&mut storage, ctx(BORROWER, 0, year), |pool| {
+            pool.repay_term_loan(loan_id, UNIT / 2)
+        });
+
+        let interest = interest_for(UNIT / 2, locked_rate, year, Rounding::Up);
+        assert_eq!(transfers(&effects), [(BORROWER.into(), UNIT - interest)]);
+        assert!(storage.term_loans.is_empty());
+        assert!(!storage.user_term_loans.contains_key(&BORROWER.into()));
+        assert_eq!(storage.total_term_principal, 0);
+    }
+
+    #[test]
+    fn overdue_term_loan_can_be_liquidated_while_healthy() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        let (loan_id, _) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| {
+            pool.borrow_term(UNIT / 10, 30)
+        });
+
+        let overdue = storage.term_loans[&loan_id].grace_ends_at + 1;
+        let loan = &storage.term_loans[&loan_id];
+        assert_eq!(
+            storage.term_loan_schedule(loan, overdue).status,
+            TermLoanStatus::Overdue
+        );
+        assert!(storage.health_factor(&BORROWER.into()) >= LIQUIDATION_THRESHOLD);
+
+        run(&mut storage, ctx(LIQUIDATOR, 0, overdue), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+
+        assert!(storage.term_loans.is_empty());
+        assert!(!storage.collateral.contains_key(&BORROWER.into()));
+        assert_eq!(storage.total_term_principal, 0);
+        assert!(storage.health_index.is_empty());
+    }
+
+    #[test]
+    fn term_deposit_earns_a_boosted_interest_share() {
+        let mut storage = pool_with_loan(5 * UNIT, UNIT);
+        let (deposit_id, _) = run(&mut storage, ctx(DEPOSITOR, 5 * UNIT, 0), |pool| {
+            pool.deposit_term(DepositTier::Days180)
+        });
+        assert_eq!(storage.term_deposits[&deposit_id].boost, 150);
+
+        let year = SECONDS_PER_YEAR as u64;
+        run(&mut storage, ctx(ADMIN, 0, year), |pool| {
+            pool.accrue_interest()
+        });
+
+        // Equal principal, so the locked deposit earns 1.5x the instant-access balance
+        let flexible = storage.lender_interest_earned[&LENDER.into()];
+        let locked = storage.lender_interest_earned[&DEPOSITOR.into()];
+        assert!(flexible > 0);
+        assert!(locked.abs_diff(flexible * 3 / 2) <= 1);
+    }
+
+    #[test]
+    fn early_term_withdrawal_pays_penalty_to_remaining_lenders() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(LENDER, 5 * UNIT, 0), |pool| pool.lend());
+        let (deposit_id, _) = run(&mut storage, ctx(DEPOSITOR, UNIT, 0), |pool| {
+            pool.deposit_term(DepositTier::Days30)
+        });
+
+        let day = SECONDS_PER_DAY;
+        let (_, effects) = run(&mut storage, ctx(DEPOSITOR, 0, day), |pool| {
+            pool.withdraw_term_deposit(deposit_id)
+        });
+
+        let penalty = UNIT * EARLY_WITHDRAWAL_PENALTY / 100;
+        assert_eq!(transfers(&effects), [(DEPOSITOR.into(), UNIT - penalty)]);
+        assert_eq!(storage.lender_interest_earned[&LENDER.into()], penalty);
+        assert_eq!(storage.total_term_deposits, 0);
+        assert_eq!(storage.total_liquidity, 5 * UNIT);
+        assert!(storage.check_invariants(5 * UNIT + penalty).all_hold);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient total liquidity for principal withdrawal")]
+    fn term_deposit_withdrawal_is_checked_before_the_burn() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let (deposit_id, _) = run(&mut storage, ctx(DEPOSITOR, UNIT, 0), |pool| {
+            pool.deposit_term(DepositTier::Days30)
+        });
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+
+        // Nothing to burn for: the deposit's principal is lent out
+        run(&mut storage, ctx(DEPOSITOR, 0, 0), |pool| {
+            pool.prepare_withdraw_term_deposit(deposit_id)
+        });
+    }
+
+    #[test]
+    fn matured_term_deposit_is_withdrawn_in_full() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let (deposit_id, _) = run(&mut storage, ctx(DEPOSITOR, UNIT, 0), |pool| {
+            pool.deposit_term(DepositTier::Days90)
+        });
+        let unlocks_at = storage.term_deposits[&deposit_id].unlocks_at;
+        assert_eq!(unlocks_at, 90 * SECONDS_PER_DAY);
+
+        let (_, effects) = run(&mut storage, ctx(DEPOSITOR, 0, unlocks_at), |pool| {
+            pool.withdraw_term_deposit(deposit_id)
+        });
+        assert_eq!(transfers(&effects), [(DEPOSITOR.into(), UNIT)]);
+        assert!(storage.lender_term_deposits.is_empty());
+    }
+
+    #[test]
+    fn checkpoint_accrues_and_flags_positions_below_warning_threshold() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let (_, effects) = run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_checkpoints(100, 10_000_000_000, 160)
+        });
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::ScheduleCheckpoint {
+                epoch: 1,
+                delay: 100,
+                reservation: None,
+            }
+        )));
+
+        // The fresh loan sits at ~151% health, below the 160% warning level
+        let day = SECONDS_PER_DAY;
+        let (warnings, effects) = run(&mut storage, ctx(ADMIN, 0, day), |pool| pool.checkpoint(1));
+        assert_eq!(warnings, 1);
+        assert!(storage.user_accrued_interest[&BORROWER.into()] > 0);
+        assert_eq!(storage.last_checkpoint_ts, day);
+        // No gas reserved, so the chain stops here and says so
+        assert!(
+            !effects
+                .iter()
+                .any(|effect| matches!(effect, Effect::ScheduleCheckpoint { .. }))
+        );
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::CheckpointsStopped(stopped)) if stopped.epoch == 1
+        )));
+
+        // Reconfiguring makes checkpoints already in flight stale
+        run(&mut storage, ctx(ADMIN, 0, day), |pool| {
+            pool.configure_checkpoints(100, 10_000_000_000, 160)
+        });
+        let (warnings, effects) = run(&mut storage, ctx(ADMIN, 0, 2 * day), |pool| {
+            pool.checkpoint(1)
+        });
+        assert_eq!(warnings, 0);
+        assert!(effects.is_empty());
+        assert_eq!(storage.last_checkpoint_ts, day);
+    }
+
+    #[test]
+    fn checkpoint_skips_expired_reservations_before_reporting_the_next_run() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_checkpoints(100, 10_000_000_000, 160)
+        });
+        let reservation = |byte: u8, expires_at| CheckpointReservation {
+            id: ReservationId::from([byte; 32]),
+            expires_at,
+        };
+        storage.checkpoint_reservations = VecDeque::from([reservation(1, 50), reservation(2, 300)]);
+
+        // The first reservation expired unused, the second pays for the next run
+        let at_block = |block_height| Context {
+            block_height,
+            ..ctx(ADMIN, 0, 0)
+        };
+        let (_, effects) = run(&mut storage, at_block(100), |pool| pool.checkpoint(1));
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::ScheduleCheckpoint {
+                reservation: Some(id),
+                ..
+            } if *id == ReservationId::from([2; 32])
+        )));
+        assert!(storage.checkpoint_reservations.is_empty());
+
+        // Only expired gas left: the run reports that nothing comes next
+        storage.checkpoint_reservations = VecDeque::from([reservation(3, 250)]);
+        let (_, effects) = run(&mut storage, at_block(300), |pool| pool.checkpoint(1));
+        assert!(
+            !effects
+                .iter()
+                .any(|effect| matches!(effect, Effect::ScheduleCheckpoint { .. }))
+        );
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::CheckpointRun(run)) if !run.next_scheduled
+        )));
+        assert!(
+            effects
+                .iter()
+                .any(|effect| matches!(effect, Effect::Event(LendingEvent::CheckpointsStopped(_))))
+        );
+        assert!(storage.checkpoint_reservations.is_empty());
+    }
+
+    #[test]
+    fn checkpoint_gas_reservations_outlive_the_ones_queued_before_them() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_checkpoints(100, 10_000_000_000, 160)
+        });
+        storage.checkpoint_reservations = VecDeque::from([
+            CheckpointReservation {
+                id: ReservationId::from([1; 32]),
+                expires_at: 50,
+            },
+            CheckpointReservation {
+                id: ReservationId::from([2; 32]),
+                expires_at: 300,
+            },
+        ]);
+
+        let (_, effects) = run(
+            &mut storage,
+            Context {
+                block_height: 100,
+                ..ctx(ADMIN, 0, 0)
+            },
+            |pool| pool.reserve_checkpoint_gas(2),
+        );
+        let durations: Vec<u32> = effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::ReserveCheckpointGas { duration, .. } => Some(*duration),
+                _ => None,
+            })
+            .collect();
+        // Only the live reservation is still queued ahead of the new ones
+        assert_eq!(durations, vec![300, 400]);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::CheckpointGasReserved(reserved)) if reserved.reservations == 3
+        )));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only admin can reserve checkpoint gas")]
+    fn only_admin_reserves_checkpoint_gas() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_checkpoints(100, 10_000_000_000, 160)
+        });
+        run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.reserve_checkpoint_gas(1)
+        });
+    }
+
+    #[test]
+    fn stale_accrual_pays_keeper_bounty_from_treasury() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+
+        let year = SECONDS_PER_YEAR as u64;
+        let (bounty, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, year), |pool| pool.accrue());
+        assert!(bounty > 0);
+        assert_eq!(transfers(&effects), [(LIQUIDATOR.into(), bounty)]);
+        // The keeper was paid 0.1% of the treasury as it stood after this accrual
+        assert_eq!(
+            bounty,
+            (storage.treasury + bounty) * KEEPER_BOUNTY_BPS / 10_000
+        );
+        assert_eq!(storage.last_accrual_ts, year);
+
+        // Accrual is fresh again, so the next call only accrues
+        let (bounty, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, year + 60), |pool| {
+            pool.accrue()
+        });
+        assert_eq!(bounty, 0);
+        assert!(transfers(&effects).is_empty());
+    }
+
+    #[test]
+    fn pool_funds_pay_out_one_to_one_whatever_the_prices() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Debt, 2 * WAD);
+            pool.set_treasury_beneficiaries(vec![TreasuryBeneficiary {
+                account: DEPOSITOR.into(),
+                weight_bps: BPS_DENOMINATOR,
+            }]);
+        });
+
+        // The keeper bounty, treasury and liquidity withdrawals and distributions all send the
+        // VARA backing the units, not their value at the TVARA price
+        let year = SECONDS_PER_YEAR as u64;
+        let (bounty, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, year), |pool| pool.accrue());
+        assert!(bounty > 0);
+        assert_eq!(transfers(&effects), [(LIQUIDATOR.into(), bounty)]);
+
+        let (_, effects) = run(&mut storage, ctx(ADMIN, 0, year), |pool| {
+            pool.admin_withdraw_treasury(100)
+        });
+        assert_eq!(transfers(&effects), [(ADMIN.into(), 100)]);
+        let (_, effects) = run(&mut storage, ctx(ADMIN, 0, year), |pool| {
+            pool.admin_withdraw_funds(100)
+        });
+        assert_eq!(transfers(&effects), [(ADMIN.into(), 100)]);
+
+        let treasury = storage.treasury;
+        let (distributed, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, year), |pool| {
+            pool.distribute_treasury()
+        });
+        assert_eq!(distributed, treasury);
+        assert_eq!(transfers(&effects), [(DEPOSITOR.into(), treasury)]);
+    }
+
+    #[test]
+    fn state_changes_emit_post_operation_values() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let (_, effects) = run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::LiquidityProvided(event))
+                if event.amount == 10 * UNIT
+                    && event.new_lender_balance == 10 * UNIT
+                    && event.new_liquidity == 10 * UNIT
+        )));
+
+        let (_, effects) = run(&mut storage, ctx(BORROWER, UNIT, 60), |pool| {
+            pool.deposit_collateral()
+        });
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::CollateralDeposited(event))
+                if event.user == ActorId::from(BORROWER)
+                    && event.new_collateral == UNIT
+                    && event.timestamp == 60
+        )));
+
+        let (_, effects) = run(&mut storage, ctx(BORROWER, 0, 60), |pool| pool.borrow());
+        let debt = storage.debt[&ActorId::from(BORROWER)];
+        let health = storage.health_factor(&BORROWER.into());
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::Borrowed(event))
+                if event.new_debt == debt
+                    && event.new_health_factor == health
+                    && event.new_liquidity == storage.total_liquidity
+        )));
+    }
+
+    #[test]
+    fn interest_accrued_event_carries_totals_only() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(DEPOSITOR, UNIT, 0), |pool| pool.lend());
+
+        let year = SECONDS_PER_YEAR as u64;
+        let (_, effects) = run(&mut storage, ctx(ADMIN, 0, year), |pool| {
+            pool.accrue_interest()
+        });
+        let events: Vec<&InterestAccrued> = effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::Event(LendingEvent::InterestAccrued(event)) => Some(event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events.len(), 1);
+        let event = events[0];
+        assert_eq!(event.elapsed, year);
+        assert_eq!(
+            event.borrower_interest,
+            storage.user_accrued_interest[&ActorId::from(BORROWER)]
+        );
+        assert_eq!(event.term_loan_interest, 0);
+        // Both lenders were credited, and the event only reports what they got together
+        assert_eq!(
+            event.lender_interest,
+            storage.lender_interest_earned[&ActorId::from(LENDER)]
+                + storage.lender_interest_earned[&ActorId::from(DEPOSITOR)]
+        );
+        assert_eq!(event.treasury_cut, storage.treasury);
+        assert_eq!(event.new_treasury, storage.treasury);
+    }
+
+    #[test]
+    fn poke_refreshes_positions_and_reports_health() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let (positions, _) = run(&mut storage, ctx(LIQUIDATOR, 0, 60), |pool| {
+            pool.poke(vec![BORROWER.into(), LENDER.into()])
+        });
+
+        assert_eq!(
+            positions,
+            [
+                (BORROWER.into(), storage.health_factor(&BORROWER.into())),
+                (LENDER.into(), u128::MAX),
+            ]
+        );
+        assert!(storage.user_accrued_interest[&BORROWER.into()] > 0);
+    }
+
+    // Pool in auction mode whose borrower was kicked after the collateral price fell to `price`
+    fn pool_with_auction(price: u128) -> LendingStorage {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_liquidation_mode(LiquidationMode::Auction);
+            pool.update_price(Asset::Collateral, price);
+        });
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.kick_auction(BORROWER.into())
+        });
+        storage
+    }
+
+    #[test]
+    fn auction_sells_collateral_at_a_growing_discount() {
+        let mut storage = pool_with_auction(WAD * 3 / 4);
+        let debt = UNIT * 66 / 100;
+        assert!(!storage.debt.contains_key(&BORROWER.into()));
+        assert!(storage.health_index.is_empty());
+        let auction = storage.auctions[&0].clone();
+        assert_eq!((auction.collateral, auction.debt), (UNIT, debt));
+
+        assert_eq!(
+            storage.auction_discount(&auction, 0),
+            Wad::from_percent(2).0
+        );
+        assert_eq!(
+            storage.auction_discount(&auction, AUCTION_DURATION / 2),
+            Wad::from_percent(11).0
+        );
+        assert_eq!(
+            storage.auction_discount(&auction, 10 * AUCTION_DURATION),
+            Wad::from_percent(20).0
+        );
+
+        // Covering the whole debt at 2% off leaves some collateral for the borrower
+        let (pay, bought) = storage.auction_quote(&auction, debt, 0);
+        assert_eq!(pay, debt);
+        let (_, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.take(0, debt, 0)
+        });
+        assert_eq!(
+            transfers(&effects),
+            [
+                (LIQUIDATOR.into(), bought),
+                (BORROWER.into(), UNIT - bought)
+            ]
+        );
+        assert!(storage.auctions.is_empty());
+        assert_eq!(storage.bad_debt, 0);
+        assert_eq!(storage.total_liquidity, 10 * UNIT);
+    }
+
+    #[test]
+    fn auction_that_runs_out_of_collateral_books_bad_debt() {
+        let mut storage = pool_with_auction(WAD / 2);
+        let debt = UNIT * 66 / 100;
+        let (pay, bought) = storage.auction_quote(&storage.auctions[&0], debt, 0);
+        assert_eq!(bought, UNIT);
+        assert!(pay < debt);
+
+        let (_, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.take(0, debt, 0)
+        });
+        // The unused part of the bid is minted back
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Mint { to, amount } if *to == LIQUIDATOR.into() && *amount == debt - pay
+        )));
+        assert_eq!(transfers(&effects), [(LIQUIDATOR.into(), UNIT)]);
+        assert_eq!(storage.bad_debt, debt - pay);
+        assert!(storage.auctions.is_empty());
+        assert!(storage.check_invariants(10 * UNIT).all_hold);
+    }
+
+    #[test]
+    fn bid_below_its_limit_after_the_burn_is_refunded() {
+        let mut storage = pool_with_auction(WAD * 3 / 4);
+        let debt = UNIT * 66 / 100;
+        let (pay, _) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.prepare_take(0, debt / 2, 0)
+        });
+        let (_, min_collateral) = storage.auction_quote(&storage.auctions[&0], pay, 0);
+
+        // The collateral price rises while the burn is in flight, so `pay` now buys less
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Collateral, WAD)
+        });
+        let (bought, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.take(0, pay, min_collateral)
+        });
+        assert_eq!(bought, 0);
+        assert!(transfers(&effects).is_empty());
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Mint { to, amount } if *to == LIQUIDATOR.into() && *amount == pay
+        )));
+        assert_eq!(storage.auctions[&0].debt, debt);
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol is paused")]
+    fn paused_auction_is_rejected_before_the_burn() {
+        let mut storage = pool_with_auction(WAD * 3 / 4);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| pool.pause());
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.prepare_take(0, UNIT, 0)
+        });
+    }
+
+    #[test]
+    fn auction_is_written_off_after_max_restarts() {
+        let mut storage = pool_with_auction(WAD / 2);
+        let debt = UNIT * 66 / 100;
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.take(0, debt / 2, 0)
+        });
+
+        let mut now = 0;
+        for restarts in 1..=AUCTION_MAX_RESTARTS {
+            now += AUCTION_DURATION;
+            run(&mut storage, ctx(LIQUIDATOR, 0, now), |pool| {
+                pool.settle_auction(0)
+            });
+            assert_eq!(storage.auctions[&0].restarts, restarts);
+        }
+
+        // One restart too many closes it for good
+        let auction = storage.auctions[&0].clone();
+        let liquidity = storage.total_liquidity;
+        now += AUCTION_DURATION;
+        let (_, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, now), |pool| {
+            pool.settle_auction(0)
+        });
+        assert!(storage.auctions.is_empty());
+        assert!(transfers(&effects).is_empty());
+        assert!(auction.debt > auction.collateral);
+        assert_eq!(storage.bad_debt, auction.debt - auction.collateral);
+        assert_eq!(storage.total_liquidity, liquidity + auction.collateral);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::AuctionSettled(AuctionSettled {
+                outcome: AuctionOutcome::WrittenOff,
+                ..
+            }))
+        )));
+        assert!(storage.check_invariants(10 * UNIT).all_hold);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidations go through auctions")]
+    fn instant_liquidation_is_disabled_in_auction_mode() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_liquidation_mode(LiquidationMode::Auction);
+            pool.update_price(Asset::Collateral, WAD / 2);
+        });
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+    }
+
+    // Pool in DEX mode whose borrower's collateral price fell to 0.75, with the swap sent
+    fn pool_with_dex_swap() -> (LendingStorage, DexSwap) {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_dex(DEX.into(), 300);
+            pool.set_liquidation_mode(LiquidationMode::Dex);
+            pool.update_price(Asset::Collateral, WAD * 3 / 4);
+        });
+        let (swap, _) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.start_dex_liquidation(BORROWER.into())
+        });
+        (storage, swap)
+    }
+
+    #[test]
+    fn dex_liquidation_burns_debt_from_swap_proceeds() {
+        let (mut storage, swap) = pool_with_dex_swap();
+        let debt = UNIT * 66 / 100;
+        assert_eq!((swap.collateral, swap.debt), (UNIT, debt));
+        // 0.75 TVARA of oracle value less 3% slippage
+        assert_eq!(swap.min_out, UNIT * 75 / 100 * 97 / 100);
+        assert!(!storage.collateral.contains_key(&BORROWER.into()));
+        // The collateral is at the DEX, the debt is still owed to lenders
+        assert!(storage.check_invariants(10 * UNIT).all_hold);
+
+        let proceeds = UNIT * 74 / 100;
+        let ((), effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.settle_dex_swap(swap.id, proceeds)
+        });
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Burn { from, amount } if *from == PROGRAM.into() && *amount == debt
+        )));
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::TokenTransfer { to, amount }
+                if *to == BORROWER.into() && *amount == proceeds - debt
+        )));
+        assert!(storage.dex_swaps.is_empty());
+        assert_eq!(storage.total_liquidity, 10 * UNIT);
+        assert_eq!(storage.bad_debt, 0);
+        assert!(storage.check_invariants(10 * UNIT).all_hold);
+    }
+
+    #[test]
+    fn rejected_dex_swap_falls_back_to_an_auction() {
+        let (mut storage, swap) = pool_with_dex_swap();
+        let (auction_id, _) = run(&mut storage, ctx(LIQUIDATOR, 0, 60), |pool| {
+            pool.fail_dex_swap(swap.id)
+        });
+
+        assert!(storage.dex_swaps.is_empty());
+        let auction = &storage.auctions[&auction_id];
+        assert_eq!(
+            (auction.collateral, auction.debt),
+            (swap.collateral, swap.debt)
+        );
+        assert_eq!(auction.kicker, LIQUIDATOR.into());
+        assert_eq!(auction.started_at, 60);
+        // The refunded VARA is back in the program
+        assert!(storage.check_invariants(11 * UNIT).all_hold);
+    }
+
+    #[test]
+    fn admin_resolves_a_stuck_dex_swap() {
+        let (mut storage, swap) = pool_with_dex_swap();
+        let debt = UNIT * 66 / 100;
+
+        // Nothing was received for it, so the whole debt is written off
+        let later = DEX_SWAP_RESOLVE_DELAY;
+        run(&mut storage, ctx(ADMIN, 0, later), |pool| {
+            pool.resolve_dex_swap(swap.id, 0)
+        });
+        assert!(storage.dex_swaps.is_empty());
+        assert_eq!(storage.bad_debt, debt);
+        assert!(storage.check_invariants(10 * UNIT).all_hold);
+    }
+
+    #[test]
+    #[should_panic(expected = "DEX swap may still be settling")]
+    fn pending_dex_swap_cannot_be_resolved_early() {
+        let (mut storage, swap) = pool_with_dex_swap();
+        run(
+            &mut storage,
+            ctx(ADMIN, 0, DEX_SWAP_RESOLVE_DELAY - 1),
+            |pool| pool.resolve_dex_swap(swap.id, 0),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "DEX is not configured")]
+    fn dex_mode_requires_a_dex() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_liquidation_mode(LiquidationMode::Dex)
+        });
+    }
+
+    #[test]
+    fn twap_of_large_prices_held_for_the_whole_window_does_not_overflow() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let price = u128::MAX / 2;
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Collateral, price)
+        });
+
+        let (collateral_price, debt_price, covered) =
+            storage.twap(MAX_TWAP_WINDOW, MAX_TWAP_WINDOW);
+        assert_eq!(
+            (collateral_price, debt_price),
+            (price, crate::DEFAULT_PRICE)
+        );
+        assert_eq!(covered, MAX_TWAP_WINDOW);
+    }
+
+    #[test]
+    fn twap_weighs_prices_by_how_long_they_held() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 600), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 2)
+        });
+
+        // 600s at 1.0 and 1200s at 0.5
+        let (collateral_price, debt_price, covered) = storage.twap(1800, 1800);
+        assert_eq!(collateral_price, WAD * 2 / 3);
+        assert_eq!(debt_price, WAD);
+        assert_eq!(covered, 1800);
+        assert_eq!(storage.twap(600, 1800).0, WAD / 2);
+        // History only reaches back to the pool's creation
+        assert_eq!(storage.twap(3600, 1800), (WAD * 2 / 3, WAD, 1800));
+
+        // Updates within one second keep a single observation, the last one
+        run(&mut storage, ctx(ADMIN, 0, 600), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 4)
+        });
+        assert_eq!(storage.price_observations.len(), 2);
+        assert_eq!(storage.twap(1200, 1800).0, WAD / 4);
+    }
+
+    #[test]
+    fn price_spike_alone_does_not_make_a_position_liquidatable() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let debt = storage.total_debt_of(&BORROWER.into());
+        run(&mut storage, ctx(ADMIN, 0, 3600), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 2)
+        });
+        assert!(storage.health_factor(&BORROWER.into()) < LIQUIDATION_THRESHOLD);
+        // The TWAP still sits at the old price
+        assert!(storage.liquidation_health_of(UNIT, debt, 3600) >= LIQUIDATION_THRESHOLD);
+
+        // Once the low price has held for most of the window the TWAP follows it
+        let later = 3600 + storage.twap_window;
+        assert!(storage.liquidation_health_of(UNIT, debt, later) < LIQUIDATION_THRESHOLD);
+        run(&mut storage, ctx(LIQUIDATOR, 0, later), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+        assert!(!storage.collateral.contains_key(&BORROWER.into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Position not eligible for liquidation")]
+    fn liquidation_right_after_a_price_drop_is_rejected() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 3600), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 2)
+        });
+        run(&mut storage, ctx(LIQUIDATOR, 0, 3600), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+    }
+
+    // Pool whose oracle needs two of the three `oracle-signer` test vector publishers
+    fn pool_with_oracle() -> LendingStorage {
+        use oracle::test_vectors::*;
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let publishers = [
+            (ED25519_A_KEY, SignatureScheme::Ed25519),
+            (SR25519_KEY, SignatureScheme::Sr25519),
+            (ED25519_B_KEY, SignatureScheme::Ed25519),
+        ]
+        .map(|(key, scheme)| OraclePublisher {
+            public_key: bytes(key),
+            scheme,
+        });
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_oracle_publishers(publishers.to_vec(), 2)
+        });
+        storage
+    }
+
+    // Relays the test vector update, at `price`, through the program the vectors were signed for
+    fn relay_signed_price(storage: &mut LendingStorage, price: u128, signers: &[(&str, &str)]) {
+        use oracle::test_vectors::*;
+        let signatures = signers
+            .iter()
+            .map(|(key, signature)| PublisherSignature {
+                public_key: bytes(key),
+                signature: bytes(signature),
+            })
+            .collect();
+        let context = Context {
+            program: PROGRAM_ID.into(),
+            ..ctx(LIQUIDATOR, 0, TIMESTAMP)
+        };
+        run(storage, context, |pool| {
+            pool.submit_signed_price(Asset::Collateral, price, TIMESTAMP, signatures)
+        });
+    }
+
+    #[test]
+    fn signed_price_from_enough_publishers_is_accepted() {
+        use oracle::test_vectors::*;
+        let mut storage = pool_with_oracle();
+        relay_signed_price(
+            &mut storage,
+            PRICE,
+            &[
+                (ED25519_A_KEY, ED25519_A_SIGNATURE),
+                (SR25519_KEY, SR25519_SIGNATURE),
+            ],
+        );
+
+        assert_eq!(storage.collateral_price, PRICE);
+        assert_eq!(storage.collateral_price_updated_at, TIMESTAMP);
+        let observation = storage.price_observations.back().unwrap();
+        assert_eq!(observation.collateral_price, PRICE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price update is older than the current price")]
+    fn signed_price_cannot_be_replayed() {
+        use oracle::test_vectors::*;
+        let mut storage = pool_with_oracle();
+        let signers = [
+            (SR25519_KEY, SR25519_SIGNATURE),
+            (ED25519_B_KEY, ED25519_B_SIGNATURE),
+        ];
+        relay_signed_price(&mut storage, PRICE, &signers);
+        relay_signed_price(&mut storage, PRICE, &signers);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough publisher signatures")]
+    fn signed_price_below_threshold_is_rejected() {
+        use oracle::test_vectors::*;
+        let mut storage = pool_with_oracle();
+        relay_signed_price(&mut storage, PRICE, &[(SR25519_KEY, SR25519_SIGNATURE)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid price signature")]
+    fn signed_price_must_match_what_was_signed() {
+        use oracle::test_vectors::*;
+        let mut storage = pool_with_oracle();
+        relay_signed_price(
+            &mut storage,
+            PRICE / 10,
+            &[
+                (ED25519_A_KEY, ED25519_A_SIGNATURE),
+                (SR25519_KEY, SR25519_SIGNATURE),
+            ],
+        );
+    }
+
+    #[test]
+    fn rate_checkpoints_are_recorded_at_most_once_per_period() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        for ts in [1800, 3000, 5400] {
+            run(&mut storage, ctx(LENDER, 0, ts), |pool| {
+                pool.accrue_interest()
+            });
+        }
+        // 3000 falls within an hour of the first checkpoint
+        let timestamps: Vec<u64> = storage.rate_history.iter().map(|c| c.timestamp).collect();
+        assert_eq!(timestamps, [1800, 5400]);
+        let last = storage.rate_history.back().unwrap();
+        assert_eq!(last.borrow_rate, storage.borrow_rate_per_year());
+        assert_eq!(last.utilization, storage.utilization_rate());
+        assert!(last.supply_rate > 0 && last.supply_rate < last.borrow_rate);
+
+        run(&mut storage, ctx(ADMIN, 0, 5400), |pool| {
+            pool.set_rate_checkpoint_period(0)
+        });
+        run(&mut storage, ctx(LENDER, 0, 5401), |pool| {
+            pool.accrue_interest()
+        });
+        assert_eq!(storage.rate_history.len(), 3);
+
+        // The oldest checkpoints make way once the buffer is full
+        for ts in 0..MAX_RATE_CHECKPOINTS as u64 {
+            run(&mut storage, ctx(LENDER, 0, 6000 + ts), |pool| {
+                pool.accrue_interest()
+            });
+        }
+        assert_eq!(storage.rate_history.len(), MAX_RATE_CHECKPOINTS);
+        assert_eq!(storage.rate_history.front().unwrap().timestamp, 6000);
+    }
+
+    #[test]
+    fn realized_apy_tracks_the_rates_charged_and_paid() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let (borrow_rate, supply_rate) = (
+            storage.borrow_rate_per_year(),
+            storage.supply_rate_per_year(),
+        );
+        assert_eq!(storage.realized_apy(86_400), (0, 0, 0));
+
+        for hour in 1..=25 {
+            run(&mut storage, ctx(LENDER, 0, hour * 3600), |pool| {
+                pool.accrue_interest()
+            });
+        }
+
+        // Measured from the first checkpoint, a day before the last accrual
+        let (borrow_apy, supply_apy, covered) = storage.realized_apy(86_400);
+        assert_eq!(covered, 86_400);
+        // Unpaid interest nudges utilization, and with it the rates, only slightly over a day
+        assert!(borrow_apy.abs_diff(borrow_rate) < borrow_rate / 1000);
+        assert!(supply_apy.abs_diff(supply_rate) < supply_rate / 1000);
+        // A longer window is capped at the history available
+        assert_eq!(storage.realized_apy(1_000_000).2, 86_400);
+    }
+
+    #[test]
+    fn account_history_explains_collateral_taken_on_repay() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_account_history(true)
+        });
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+        let (borrowed, _) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+        let year = SECONDS_PER_YEAR as u64;
+        run(&mut storage, ctx(BORROWER, 0, year), |pool| {
+            pool.repay(BORROWER.into(), borrowed)
+        });
+
+        let history = &storage.account_history[&ActorId::from(BORROWER)];
+        let actions: Vec<AccountAction> = history.iter().map(|entry| entry.action).collect();
+        assert_eq!(
+            actions,
+            [
+                AccountAction::CollateralDeposited,
+                AccountAction::Borrowed,
+                AccountAction::Repaid,
+                AccountAction::InterestSettled,
+            ]
+        );
+        assert_eq!((history[0].amount, history[0].collateral), (UNIT, UNIT));
+        assert_eq!((history[1].amount, history[1].debt), (borrowed, borrowed));
+        // A year's interest was settled from the collateral, and the rest went back
+        let interest = history[3].amount;
+        assert!(interest > 0);
+        assert_eq!((history[3].collateral, history[3].debt), (0, 0));
+        assert_eq!(history[3].timestamp, year);
+        let ids: Vec<u64> = history.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        // Lenders have no position history
+        assert!(!storage.account_history.contains_key(&ActorId::from(LENDER)));
+
+        // Only the latest entries are kept
+        for _ in 0..MAX_ACCOUNT_HISTORY {
+            run(&mut storage, ctx(BORROWER, UNIT, year), |pool| {
+                pool.deposit_collateral()
+            });
+        }
+        let history = &storage.account_history[&ActorId::from(BORROWER)];
+        assert_eq!(history.len(), MAX_ACCOUNT_HISTORY);
+        assert_eq!(history.front().unwrap().id, 4);
+    }
+
+    #[test]
+    fn account_history_is_off_by_default() {
+        let storage = pool_with_loan(10 * UNIT, UNIT);
+        assert!(storage.account_history.is_empty());
+        assert_eq!(storage.next_account_history_id, 0);
+    }
+
+    #[test]
+    fn rewards_accrue_pro_rata_on_each_side() {
+        const REWARD_TOKEN: u64 = 7;
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(DEPOSITOR, 30 * UNIT, 0), |pool| {
+            pool.lend()
+        });
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_rewards(REWARD_TOKEN.into(), RewardPayout::Mint, 1_000, 500)
+        });
+
+        // Lenders split 1000/s by balance, the only borrower takes all 500/s
+        let pending =
+            |storage: &LendingStorage, user: u64, now| storage.pending_rewards(&user.into(), now);
+        assert!(pending(&storage, LENDER, 100).abs_diff(25_000) <= 1);
+        assert!(pending(&storage, DEPOSITOR, 100).abs_diff(75_000) <= 1);
+        assert!(pending(&storage, BORROWER, 100).abs_diff(50_000) <= 1);
+
+        // Balances settled at a change keep what they earned before it
+        run(&mut storage, ctx(DEPOSITOR, 0, 100), |pool| {
+            pool.withdraw(20 * UNIT)
+        });
+        assert!(pending(&storage, LENDER, 200).abs_diff(25_000 + 50_000) <= 2);
+        assert!(pending(&storage, DEPOSITOR, 200).abs_diff(75_000 + 50_000) <= 2);
+
+        let (claimed, effects) = run(&mut storage, ctx(BORROWER, 0, 200), |pool| {
+            pool.claim_rewards()
+        });
+        assert!(claimed.abs_diff(100_000) <= 1);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Reward { token, to, amount, payout: RewardPayout::Mint }
+                if *token == ActorId::from(REWARD_TOKEN)
+                    && *to == ActorId::from(BORROWER)
+                    && *amount == claimed
+        )));
+        assert_eq!(pending(&storage, BORROWER, 200), 0);
+        assert_eq!(storage.total_rewards_claimed, claimed);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward token is already set")]
+    fn reward_token_cannot_be_swapped() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        for token in [7u64, 8] {
+            run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+                pool.configure_rewards(token.into(), RewardPayout::Transfer, 1, 1)
+            });
+        }
+    }
+
+    #[test]
+    fn protocol_fees_are_credited_to_the_treasury() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        // 1% origination, 5% liquidation, 0.5% withdrawal
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_fees(100, 500, 50)
+        });
+        run(&mut storage, ctx(LENDER, 10 * UNIT, 0), |pool| pool.lend());
+        run(&mut storage, ctx(BORROWER, UNIT, 0), |pool| {
+            pool.deposit_collateral()
+        });
+
+        // The whole borrow is owed, 1% of it is never minted
+        let (minted, _) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| pool.borrow());
+        let borrowed = UNIT * 66 / 100;
+        assert_eq!(minted, borrowed - borrowed / 100);
+        assert_eq!(storage.debt[&ActorId::from(BORROWER)], borrowed);
+        assert_eq!(storage.treasury, borrowed / 100);
+
+        // 5% of the seized collateral goes to the treasury rather than liquidity
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.update_price(Asset::Collateral, WAD / 2)
+        });
+        let liquidity = storage.total_liquidity;
+        let (_, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.liquidate(BORROWER.into())
+        });
+        assert_eq!(storage.total_liquidity, liquidity + UNIT * 95 / 100);
+        assert_eq!(storage.treasury, borrowed / 100 + UNIT * 5 / 100);
+        assert!(effects.iter().any(|effect| matches!(
+            effect,
+            Effect::Event(LendingEvent::FeeCharged(FeeCharged {
+                kind: FeeKind::Liquidation,
+                amount,
+                ..
+            })) if *amount == UNIT * 5 / 100
+        )));
+
+        // A withdrawal burns the full amount but pays out 0.5% less
+        let (_, effects) = run(&mut storage, ctx(LENDER, 0, 0), |pool| pool.withdraw(UNIT));
+        assert_eq!(transfers(&effects), [(LENDER.into(), UNIT - UNIT / 200)]);
+        let fees = borrowed / 100 + UNIT * 5 / 100 + UNIT / 200;
+        assert_eq!(storage.treasury, fees);
+        assert_eq!(storage.total_fees_collected, fees);
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee is too high")]
+    fn fees_are_capped() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_fees(0, MAX_FEE_BPS + 1, 0)
+        });
+    }
+
+    fn beneficiaries(weights: &[(u64, u128)]) -> Vec<TreasuryBeneficiary> {
+        weights
+            .iter()
+            .map(|&(account, weight_bps)| TreasuryBeneficiary {
+                account: account.into(),
+                weight_bps,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn treasury_is_split_between_beneficiaries_by_weight() {
+        const INSURANCE: u64 = 10;
+        const DEV_FUND: u64 = 11;
+        const STAKERS: u64 = 12;
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        let weights = [(INSURANCE, 5_000), (DEV_FUND, 3_000), (STAKERS, 2_000)];
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_treasury_beneficiaries(beneficiaries(&weights))
+        });
+        storage.treasury = 1_000;
+
+        // Anyone can trigger the split
+        let (distributed, effects) = run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.distribute_treasury()
+        });
+        assert_eq!(distributed, 1_000);
+        assert_eq!(
+            transfers(&effects),
+            [
+                (INSURANCE.into(), 500),
+                (DEV_FUND.into(), 300),
+                (STAKERS.into(), 200)
+            ]
+        );
+        assert_eq!(storage.treasury, 0);
+
+        // Totals accumulate, and outlive a beneficiary's removal
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_treasury_beneficiaries(beneficiaries(&[(INSURANCE, 10_000)]))
+        });
+        storage.treasury = 999;
+        run(&mut storage, ctx(LIQUIDATOR, 0, 0), |pool| {
+            pool.distribute_treasury()
+        });
+        let totals: Vec<(ActorId, u128)> = storage
+            .treasury_distributed
+            .iter()
+            .map(|(account, total)| (*account, *total))
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                (INSURANCE.into(), 1_499),
+                (DEV_FUND.into(), 300),
+                (STAKERS.into(), 200)
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Beneficiary weights must sum to 10000 bps")]
+    fn beneficiary_weights_must_cover_the_whole_treasury() {
+        let mut storage =
+            LendingStorage::new(ActorId::zero(), ADMIN.into(), 0, AssetDecimals::default());
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_treasury_beneficiaries(beneficiaries(&[(10, 5_000), (11, 4_000)]))
+        });
+    }
+
+    // Shuts `storage` down at the given collateral price with the program holding `balance`
+    fn shut_down(storage: &mut LendingStorage, collateral_price: u128, balance: u128) {
+        let admin = Context {
+            balance,
+            ..ctx(ADMIN, 0, 0)
+        };
+        run(storage, admin, |pool| pool.shutdown(collateral_price, WAD));
+    }
+
+    #[test]
+    fn shutdown_turns_positions_into_claims() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        let borrowed = UNIT * 66 / 100;
+        // The lender's 10 VARA and the borrower's 1 VARA of collateral
+        shut_down(&mut storage, WAD, 11 * UNIT);
+
+        let status = storage.shutdown.clone().unwrap();
+        assert_eq!(status.positions_settled, 1);
+        assert_eq!(status.uncovered_debt, 0);
+        assert_eq!(status.collateral_claims, UNIT - borrowed);
+        // Everything else, the collateral that paid the debt included, goes to lenders
+        assert_eq!(status.lender_assets, 10 * UNIT + borrowed);
+        assert_eq!(status.lender_deposits, 10 * UNIT);
+        assert!(storage.debt.is_empty() && storage.lender_balances.is_empty());
+
+        let (claim, effects) = run(&mut storage, ctx(BORROWER, 0, 0), |pool| {
+            pool.claim_shutdown()
+        });
+        assert_eq!(claim.collateral, UNIT - borrowed);
+        assert_eq!(transfers(&effects), [(BORROWER.into(), UNIT - borrowed)]);
+        let (claim, _) = run(&mut storage, ctx(LENDER, 0, 0), |pool| {
+            pool.claim_shutdown()
+        });
+        assert_eq!(claim.lender_share, 10 * UNIT + borrowed);
+        let status = storage.shutdown.unwrap();
+        assert_eq!(status.collateral_claimed, UNIT - borrowed);
+        assert_eq!(status.lender_assets_claimed, 10 * UNIT + borrowed);
+    }
+
+    #[test]
+    fn shutdown_below_water_leaves_no_collateral_claim() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        // 1 VARA at 0.5 USD covers 0.5 of the 0.66 TVARA owed
+        shut_down(&mut storage, WAD / 2, 11 * UNIT);
+
+        let status = storage.shutdown.clone().unwrap();
+        assert_eq!(status.collateral_claims, 0);
+        assert_eq!(status.uncovered_debt, UNIT * 16 / 100);
+        assert_eq!(status.lender_assets, 11 * UNIT);
+        assert_eq!(
+            storage.shutdown_claim_of(&BORROWER.into()),
+            ShutdownClaim::default()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol is shut down")]
+    fn shutdown_is_final() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        shut_down(&mut storage, WAD, 11 * UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| pool.resume());
+        run(&mut storage, ctx(LENDER, UNIT, 0), |pool| pool.lend());
+    }
+
+    #[test]
+    fn exported_state_keeps_rewards_fees_bad_debt_and_settings() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.configure_rewards(DEX.into(), RewardPayout::Transfer, 1_000, 500);
+            pool.set_fees(50, 100, 25);
+            pool.set_treasury_beneficiaries(vec![TreasuryBeneficiary {
+                account: ADMIN.into(),
+                weight_bps: BPS_DENOMINATOR,
+            }]);
+            pool.set_oracle_publishers(
+                vec![OraclePublisher {
+                    public_key: [1; 32],
+                    scheme: SignatureScheme::Ed25519,
+                }],
+                1,
+            );
+            pool.configure_dex(DEX.into(), 150);
+            pool.set_liquidation_mode(LiquidationMode::Dex);
+            pool.set_twap_window(600);
+            pool.configure_checkpoints(100, 10_000_000_000, 160);
+            pool.set_rate_checkpoint_period(60);
+            pool.set_account_history(true);
+        });
+        storage.bad_debt = 7;
+        // Settles the lender's rewards so far into unclaimed rewards
+        run(&mut storage, ctx(LENDER, UNIT, 100), |pool| pool.lend());
+        assert!(storage.unclaimed_rewards[&ActorId::from(LENDER)] > 0);
+        run(&mut storage, ctx(ADMIN, 0, 100), |pool| {
+            pool.set_strict_invariants(true)
+        });
+
+        let imported = LendingStorage::from(crate::ContractState::from(&storage));
+        assert_eq!(imported.storage_version, STORAGE_VERSION);
+        assert_eq!(imported.bad_debt, 7);
+        assert_eq!(imported.reward_token, Some(DEX.into()));
+        assert_eq!(imported.reward_payout, RewardPayout::Transfer);
+        assert_eq!(imported.supply_reward_index, storage.supply_reward_index);
+        assert_eq!(imported.borrow_reward_index, storage.borrow_reward_index);
+        assert_eq!(imported.rewards_updated_at, storage.rewards_updated_at);
+        assert_eq!(imported.reward_snapshots, storage.reward_snapshots);
+        assert_eq!(imported.unclaimed_rewards, storage.unclaimed_rewards);
+        assert_eq!(
+            (
+                imported.origination_fee_bps,
+                imported.liquidation_fee_bps,
+                imported.withdrawal_fee_bps
+            ),
+            (50, 100, 25)
+        );
+        assert_eq!(imported.total_fees_collected, storage.total_fees_collected);
+        assert_eq!(
+            imported.treasury_beneficiaries,
+            storage.treasury_beneficiaries
+        );
+        assert_eq!(imported.oracle_publishers, storage.oracle_publishers);
+        assert_eq!(imported.oracle_threshold, 1);
+        assert_eq!(imported.liquidation_mode, LiquidationMode::Dex);
+        assert_eq!(
+            (imported.dex_address, imported.max_slippage_bps),
+            (Some(DEX.into()), 150)
+        );
+        assert_eq!(imported.twap_window, 600);
+        assert_eq!(
+            (
+                imported.checkpoint_interval,
+                imported.checkpoint_gas,
+                imported.health_warning_threshold
+            ),
+            (100, 10_000_000_000, 160)
+        );
+        assert_eq!(imported.rate_checkpoint_period, 60);
+        assert!(imported.account_history_enabled);
+        assert!(imported.strict_invariants);
+    }
+
+    #[test]
+    fn invariants_hold_for_a_normal_pool() {
+        let storage = pool_with_loan(10 * UNIT, UNIT);
+        // The program holds the lent VARA plus the deposited collateral
+        let report = storage.check_invariants(11 * UNIT);
+        assert!(report.all_hold, "{:?}", report.checks);
+    }
+
+    #[test]
+    fn invariant_report_flags_principal_mismatch() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        storage.total_principal_borrowed += 1;
+
+        let report = storage.check_invariants(11 * UNIT);
+        assert!(!report.all_hold);
+        let broken = &report.checks[0];
+        assert!(!broken.holds);
+        assert_eq!(broken.actual, broken.expected + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invariant violated")]
+    fn strict_mode_rejects_operations_that_break_invariants() {
+        let mut storage = pool_with_loan(10 * UNIT, UNIT);
+        run(&mut storage, ctx(ADMIN, 0, 0), |pool| {
+            pool.set_strict_invariants(true)
+        });
+        storage.total_principal_borrowed += 1;
+
+        run(&mut storage, ctx(LENDER, UNIT, 0), |pool| pool.lend());
+    }
+
+    proptest! {
+        #[test]
+        fn borrow_then_repay_restores_pool(
+            liquidity in UNIT..1_000 * UNIT,
+            collateral in UNIT / 1_000..UNIT,
+            elapsed in 0u64..(SECONDS_PER_YEAR as u64),
+        ) {
+            let mut storage = pool_with_loan(liquidity, collateral);
+            let debt = storage.debt[&BORROWER.into()];
+
+            // Right after borrowing the position sits at the ~66% LTV target
+            prop_assert!(storage.health_factor(&BORROWER.into()) >= 150);
+            prop_assert_eq!(storage.total_principal_borrowed, storage.debt.values().sum::<u128>());
+
+            run(&mut storage, ctx(BORROWER, 0, elapsed), |pool| {
+                pool.repay(BORROWER.into(), debt)
+            });
+
+            prop_assert_eq!(storage.total_principal_borrowed, 0);
+            prop_assert_eq!(storage.total_liquidity, liquidity);
+            prop_assert!(storage.user_accrued_interest.is_empty());
+        }
+
+        #[test]
+        fn accrued_interest_never_decreases(
+            collateral in UNIT / 1_000..UNIT,
+            first in 0u64..1_000_000,
+            second in 0u64..1_000_000,
+        ) {
+            let mut storage = pool_with_loan(10 * UNIT, collateral);
+
+            run(&mut storage, ctx(ADMIN, 0, first), |pool| pool.accrue_interest());
+            let after_first = storage.total_debt_of(&BORROWER.into());
+            run(&mut storage, ctx(ADMIN, 0, first + second), |pool| pool.accrue_interest());
+
+            prop_assert!(storage.total_debt_of(&BORROWER.into()) >= after_first);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+#![no_std]
+#![allow(static_mut_refs)]
+
+// Constant-product VARA/TVARA pool standing in for a real DEX, so DEX liquidations can be tested
+// offline. Only the native-to-token direction the lending program needs is implemented.
+//
+// With a token program configured, swaps pay out through its VFT `Transfer` and the DEX must
+// hold the tokens it quotes. Without one, payouts are credited to an internal ledger instead.
+
+use extended_vft_client::vft::io as vft_io;
+use sails_rs::U256;
+use sails_rs::gstd::msg;
+use sails_rs::prelude::*;
+use sails_rs::{program, service};
+extern crate alloc;
+use alloc::collections::BTreeMap;
+
+#[cfg(feature = "wasm-binary")]
+#[cfg(not(target_arch = "wasm32"))]
+pub use code::WASM_BINARY_OPT as WASM_BINARY;
+
+#[cfg(feature = "wasm-binary")]
+#[cfg(not(target_arch = "wasm32"))]
+mod code {
+    include!(concat!(env!("OUT_DIR"), "/wasm_binary.rs"));
+}
+
+const FEE_BPS: u128 = 30; // 0.3% of the input stays in the pool
+const BPS_DENOMINATOR: u128 = 10_000;
+
+static mut STORAGE: Option<DexStorage> = None;
+
+struct DexStorage {
+    admin: ActorId,
+    token: Option<ActorId>,
+    reserve_native: u128,
+    reserve_token: u128,
+    balances: BTreeMap<ActorId, u128>, // Payouts when no token program is configured
+}
+
+#[derive(Encode, Decode, TypeInfo, Clone, Debug, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct Reserves {
+    pub native: u128,
+    pub token: u128,
+}
+
+#[derive(Encode, TypeInfo, Clone)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum DexEvent {
+    LiquidityAdded {
+        native: u128,
+        token: u128,
+    },
+    Swapped {
+        sender: ActorId,
+        recipient: ActorId,
+        amount_in: u128,
+        amount_out: u128,
+    },
+}
+
+// Tokens out for `amount_in` after the fee, keeping reserve_in * reserve_out constant
+pub fn amount_out(reserve_in: u128, reserve_out: u128, amount_in: u128) -> u128 {
+    let amount_in = U256::from(amount_in) * U256::from(BPS_DENOMINATOR - FEE_BPS);
+    let numerator = amount_in * U256::from(reserve_out);
+    let denominator = U256::from(reserve_in) * U256::from(BPS_DENOMINATOR) + amount_in;
+    if denominator.is_zero() {
+        return 0;
+    }
+    // Never more than `reserve_out`, so this fits in u128
+    (numerator / denominator).as_u128()
+}
+
+pub struct DexService(());
+
+impl DexService {
+    pub fn new() -> Self {
+        Self(())
+    }
+
+    fn get(&self) -> &'static DexStorage {
+        unsafe { STORAGE.as_ref().expect("DEX is not initialized") }
+    }
+
+    fn get_mut(&mut self) -> &'static mut DexStorage {
+        unsafe { STORAGE.as_mut().expect("DEX is not initialized") }
+    }
+}
+
+#[service(events = DexEvent)]
+impl DexService {
+    // Adds the attached VARA and `token_amount` to the reserves. The tokens are not pulled: with
+    // a token program the admin transfers them to the DEX separately.
+    pub fn add_liquidity(&mut self, token_amount: u128) {
+        let storage = self.get_mut();
+        assert_eq!(msg::source(), storage.admin, "Only admin can add liquidity");
+        let native = msg::value();
+        storage.reserve_native += native;
+        storage.reserve_token += token_amount;
+
+        let _ = self.emit_event(DexEvent::LiquidityAdded {
+            native,
+            token: token_amount,
+        });
+    }
+
+    // Sells the attached VARA for tokens paid to `recipient`, failing (and so refunding the VARA)
+    // if they would come to less than `min_out`. Returns the tokens paid.
+    pub async fn swap_native_for_tokens(&mut self, min_out: u128, recipient: ActorId) -> u128 {
+        let amount_in = msg::value();
+        assert!(amount_in > 0, "Nothing to swap");
+        let storage = self.get_mut();
+        let out = amount_out(storage.reserve_native, storage.reserve_token, amount_in);
+        assert!(out > 0, "Insufficient liquidity");
+        assert!(out >= min_out, "Insufficient output amount");
+        storage.reserve_native += amount_in;
+        storage.reserve_token -= out;
+
+        match storage.token {
+            Some(token) => {
+                let transfer_call = vft_io::Transfer::encode_call(recipient, out.into());
+                msg::send_bytes_with_gas_for_reply(token, transfer_call, 5_000_000_000, 0, 0)
+                    .expect("Transfer call failed")
+                    .await
+                    .expect("Token transfer failed");
+            }
+            None => *storage.balances.entry(recipient).or_default() += out,
+        }
+
+        let _ = self.emit_event(DexEvent::Swapped {
+            sender: msg::source(),
+            recipient,
+            amount_in,
+            amount_out: out,
+        });
+        out
+    }
+
+    pub fn quote_native_for_tokens(&self, amount_in: u128) -> u128 {
+        let storage = self.get();
+        amount_out(storage.reserve_native, storage.reserve_token, amount_in)
+    }
+
+    pub fn reserves(&self) -> Reserves {
+        let storage = self.get();
+        Reserves {
+            native: storage.reserve_native,
+            token: storage.reserve_token,
+        }
+    }
+
+    // Ledger balance, only used without a token program
+    pub fn balance_of(&self, account: ActorId) -> u128 {
+        *self.get().balances.get(&account).unwrap_or(&0)
+    }
+}
+
+pub struct DexProgram(());
+
+#[program]
+impl DexProgram {
+    pub fn new(token: Option<ActorId>) -> Self {
+        unsafe {
+            STORAGE = Some(DexStorage {
+                admin: msg::source(),
+                token,
+                reserve_native: 0,
+                reserve_token: 0,
+                balances: BTreeMap::new(),
+            });
+        }
+        Self(())
+    }
+
+    pub fn dex(&self) -> DexService {
+        DexService::new()
+    }
+}
@@ -0,0 +1,70 @@
+// Signs price updates for `LendingService::submit_signed_price` and prints the publisher's public
+// key and signature in hex. Keys are derived from a 32-byte seed: sr25519 seeds are expanded the
+// way Substrate expands mini secret keys, so a seed from a Substrate wallet gives the same key.
+//
+//     cargo run -p oracle-signer -- <sr25519|ed25519> <seed> <program> <collateral|debt> <price> <timestamp>
+//
+// `seed` and `program` are 32 bytes in hex, `price` is an 18-decimal USD price.
+
+use schnorrkel::{ExpansionMode, MiniSecretKey};
+use std::env;
+
+// Must match `oracle::PRICE_UPDATE_DOMAIN` and `oracle::SR25519_SIGNING_CONTEXT`
+const PRICE_UPDATE_DOMAIN: &[u8] = b"varafi/price-update/v1";
+const SR25519_SIGNING_CONTEXT: &[u8] = b"substrate";
+
+// The domain followed by SCALE-encoded (program, asset, price, timestamp)
+fn signing_payload(program: [u8; 32], asset: u8, price: u128, timestamp: u64) -> Vec<u8> {
+    let mut payload = PRICE_UPDATE_DOMAIN.to_vec();
+    payload.extend_from_slice(&program);
+    payload.push(asset);
+    payload.extend_from_slice(&price.to_le_bytes());
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    payload
+}
+
+fn bytes32(arg: &str) -> [u8; 32] {
+    hex::decode(arg.trim_start_matches("0x"))
+        .expect("Invalid hex")
+        .try_into()
+        .expect("Expected 32 bytes")
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [scheme, seed, program, asset, price, timestamp] = args.as_slice() else {
+        eprintln!(
+            "usage: oracle-signer <sr25519|ed25519> <seed> <program> <collateral|debt> <price> <timestamp>"
+        );
+        std::process::exit(1);
+    };
+    let asset = match asset.as_str() {
+        "collateral" => 0,
+        "debt" => 1,
+        _ => panic!("Asset must be collateral or debt"),
+    };
+    let payload = signing_payload(
+        bytes32(program),
+        asset,
+        price.parse().expect("Invalid price"),
+        timestamp.parse().expect("Invalid timestamp"),
+    );
+
+    let (public_key, signature) = match scheme.as_str() {
+        "sr25519" => {
+            let keypair = MiniSecretKey::from_bytes(&bytes32(seed))
+                .expect("Invalid seed")
+                .expand_to_keypair(ExpansionMode::Ed25519);
+            let signature = keypair.sign_simple(SR25519_SIGNING_CONTEXT, &payload);
+            (keypair.public.to_bytes(), signature.to_bytes())
+        }
+        "ed25519" => {
+            let key = ed25519_dalek::SigningKey::from_bytes(&bytes32(seed));
+            let signature = ed25519_dalek::Signer::sign(&key, &payload);
+            (key.verifying_key().to_bytes(), signature.to_bytes())
+        }
+        _ => panic!("Scheme must be sr25519 or ed25519"),
+    };
+    println!("public_key: 0x{}", hex::encode(public_key));
+    println!("signature:  0x{}", hex::encode(signature));
+}
@@ -1,5 +1,8 @@
 #[warn(unused_variables)]
+use blockchain_app::PositionFilter;
 use blockchain_app::io::*;
+use blockchain_app::migration::{ContractStateV1, STORAGE_VERSION, VersionedState};
+use blockchain_app::{DepositTier, LiquidationMode};
 use sails_rs::gtest::{Program, System};
 use sails_rs::prelude::*;
 
@@ -19,6 +22,7 @@ fn test_init() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     // Note: In a real test, you'd check the result status
@@ -38,6 +42,7 @@ fn test_deposit_collateral() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000; // 1 TVARA
@@ -82,6 +87,7 @@ fn test_borrow() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000; // 1 TVARA
@@ -113,6 +119,7 @@ fn test_repay() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000;
@@ -153,6 +160,7 @@ fn test_lend() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let lend_amount = 1_000_000_000_000;
@@ -188,6 +196,7 @@ fn test_withdraw_liquidity() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let lend_amount = 1_000_000_000_000;
@@ -228,6 +237,7 @@ fn test_liquidate() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000;
@@ -264,6 +274,7 @@ fn test_health_factor() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000;
@@ -326,12 +337,12 @@ fn test_price_update() {
 
     let lending_program = Program::current(&sys);
     let new_price = 1_100_000_000_000_000_000; // 1.1 USD per TVARA
-    lending_program.send(USERS[0], LendingAction::UpdateTvaraPrice(new_price));
-    // Assert contract state: tvara_price should be updated
+    lending_program.send(USERS[0], LendingAction::UpdateCollateralPrice(new_price));
+    // Assert contract state: collateral_price should be updated
     let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
     if let LendingReply::ContractState(state) = reply {
         assert_eq!(
-            state.tvara_price, new_price,
+            state.collateral_price, new_price,
             "TVARA price should be updated"
         );
     } else {
@@ -353,6 +364,7 @@ fn test_utilization_rate() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let lend_amount = 1_000_000_000_000;
@@ -384,6 +396,7 @@ fn test_interest_accrual() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let lend_amount = 1_000_000_000_000;
@@ -422,6 +435,7 @@ fn test_collateral_withdrawal() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000;
@@ -462,6 +476,7 @@ fn test_reentrancy_protection() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     let deposit_amount = 1_000_000_000_000;
@@ -493,6 +508,7 @@ fn test_edge_cases() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     lending_program.send_with_value(USERS[1], LendingAction::DepositCollateral, 0);
@@ -530,6 +546,7 @@ fn test_multiple_users() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
     for user in &USERS[1..] {
@@ -569,6 +586,7 @@ fn test_insufficient_collateral_borrow() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -598,6 +616,7 @@ fn test_over_borrow_limit() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -639,6 +658,7 @@ fn test_repay_more_than_debt() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -682,6 +702,7 @@ fn test_withdraw_more_than_liquidity() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -720,6 +741,7 @@ fn test_withdraw_collateral_with_debt() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -768,6 +790,7 @@ fn test_liquidate_healthy_position() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -804,6 +827,7 @@ fn test_price_manipulation() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -817,14 +841,14 @@ fn test_price_manipulation() {
 
     // Dramatically increase price to make position unhealthy
     let high_price = 10_000_000_000_000_000_000; // 10x price increase
-    lending_program.send(USERS[0], LendingAction::UpdateTvaraPrice(high_price));
+    lending_program.send(USERS[0], LendingAction::UpdateCollateralPrice(high_price));
 
     // Check if position is now liquidatable
     let _user_info = lending_program.send(USERS[1], LendingAction::GetUserInfo(USERS[1].into()));
-    // Assert tvara_price updated
+    // Assert collateral_price updated
     let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
     if let LendingReply::ContractState(state) = reply {
-        assert_eq!(state.tvara_price, 10_000_000_000_000_000_000);
+        assert_eq!(state.collateral_price, 10_000_000_000_000_000_000);
     } else {
         panic!("Expected ContractState reply");
     }
@@ -843,15 +867,16 @@ fn test_zero_price_update() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
     // Try to set price to zero (should fail)
-    lending_program.send(USERS[0], LendingAction::UpdateTvaraPrice(0));
-    // Assert tvara_price did not change to zero
+    lending_program.send(USERS[0], LendingAction::UpdateCollateralPrice(0));
+    // Assert collateral_price did not change to zero
     let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
     if let LendingReply::ContractState(state) = reply {
-        assert!(state.tvara_price > 0, "Price should not be zero");
+        assert!(state.collateral_price > 0, "Price should not be zero");
     } else {
         panic!("Expected ContractState reply");
     }
@@ -871,16 +896,17 @@ fn test_non_admin_price_update() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
     // Try to update price as non-admin user
     let new_price = 1_100_000_000_000_000_000;
-    lending_program.send(USERS[1], LendingAction::UpdateTvaraPrice(new_price));
-    // Assert tvara_price did not change
+    lending_program.send(USERS[1], LendingAction::UpdateCollateralPrice(new_price));
+    // Assert collateral_price did not change
     let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
     if let LendingReply::ContractState(state) = reply {
-        assert_eq!(state.tvara_price, 1_000_000_000_000_000_000);
+        assert_eq!(state.collateral_price, 1_000_000_000_000_000_000);
     } else {
         panic!("Expected ContractState reply");
     }
@@ -901,6 +927,7 @@ fn test_pause_and_resume_operations() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -944,6 +971,7 @@ fn test_multiple_borrows_and_repays() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -1007,6 +1035,7 @@ fn test_high_utilization_scenario() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -1054,6 +1083,7 @@ fn test_collateral_ratio_scenarios() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -1120,6 +1150,7 @@ fn test_protocol_pause_during_active_positions() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -1181,6 +1212,7 @@ fn test_concurrent_user_operations() {
         USERS[0],
         LendingInit {
             vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
         },
     );
 
@@ -1234,3 +1266,701 @@ fn test_concurrent_user_operations() {
         panic!("Expected ContractState reply");
     }
 }
+
+#[test]
+fn test_borrowers_pagination() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to all users
+    for user in USERS {
+        sys.mint_to(*user, 1_000_000_000_000_000);
+    }
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    // Provide liquidity and open several borrow positions
+    let lend_amount = 10_000_000_000_000;
+    lending_program.send_with_value(USERS[0], LendingAction::Lend, lend_amount);
+    for user in &USERS[1..] {
+        lending_program.send_with_value(*user, LendingAction::DepositCollateral, 1_000_000_000_000);
+        lending_program.send(*user, LendingAction::Borrow);
+    }
+
+    // Walk all borrowers two at a time
+    let mut seen = 0;
+    let mut cursor = None;
+    loop {
+        let reply = lending_program.send(
+            USERS[0],
+            LendingAction::GetBorrowersPage {
+                start_after: cursor,
+                limit: 2,
+                filter: Default::default(),
+            },
+        );
+        if let LendingReply::UserInfoPage(page) = reply {
+            assert!(page.items.len() <= 2, "Page should respect the limit");
+            seen += page.items.len();
+            cursor = page.next_cursor;
+        } else {
+            panic!("Expected UserInfoPage reply");
+        }
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(
+        seen,
+        USERS.len() - 1,
+        "Every borrower should be paged exactly once"
+    );
+
+    // A minimum-debt filter above every position returns nothing
+    let reply = lending_program.send(
+        USERS[0],
+        LendingAction::GetBorrowersPage {
+            start_after: None,
+            limit: 10,
+            filter: PositionFilter {
+                min_debt: Some(u128::MAX),
+                ..Default::default()
+            },
+        },
+    );
+    if let LendingReply::UserInfoPage(page) = reply {
+        assert!(page.items.is_empty());
+        assert!(page.next_cursor.is_none());
+    } else {
+        panic!("Expected UserInfoPage reply");
+    }
+}
+
+#[test]
+fn test_liquidation_opportunities_sorted() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to users
+    sys.mint_to(USERS[0], 1_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+    sys.mint_to(USERS[2], 1_000_000_000_000_000);
+    sys.mint_to(USERS[3], 1_000_000_000_000_000);
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    // Setup: provide liquidity and open two positions
+    let lend_amount = 5_000_000_000_000;
+    lending_program.send_with_value(USERS[2], LendingAction::Lend, lend_amount);
+    lending_program.send_with_value(
+        USERS[1],
+        LendingAction::DepositCollateral,
+        1_000_000_000_000,
+    );
+    lending_program.send(USERS[1], LendingAction::Borrow);
+    lending_program.send_with_value(
+        USERS[3],
+        LendingAction::DepositCollateral,
+        2_000_000_000_000,
+    );
+    lending_program.send(USERS[3], LendingAction::Borrow);
+
+    // Top up USERS[3] so it is healthier than USERS[1]
+    lending_program.send_with_value(
+        USERS[3],
+        LendingAction::DepositCollateral,
+        1_000_000_000_000,
+    );
+
+    // Halve the price so both positions fall below the liquidation threshold
+    lending_program.send(
+        USERS[0],
+        LendingAction::UpdateCollateralPrice(500_000_000_000_000_000),
+    );
+
+    // Right after the drop the TWAP still reads healthy, and `liquidate` would refuse both
+    let reply = lending_program.send(
+        USERS[0],
+        LendingAction::GetLiquidationOpportunities {
+            threshold: 120,
+            limit: 10,
+        },
+    );
+    if let LendingReply::LiquidationOpportunities(opportunities) = reply {
+        assert!(opportunities.is_empty(), "The TWAP should still be healthy");
+    } else {
+        panic!("Expected LiquidationOpportunities reply");
+    }
+
+    // Once the new price has held for a whole TWAP window (3 s blocks) both are liquidatable
+    sys.run_to_block(sys.block_height() + 1_000);
+    let reply = lending_program.send(
+        USERS[0],
+        LendingAction::GetLiquidationOpportunities {
+            threshold: 120,
+            limit: 10,
+        },
+    );
+    if let LendingReply::LiquidationOpportunities(opportunities) = reply {
+        assert_eq!(
+            opportunities.len(),
+            2,
+            "Both positions should be liquidatable"
+        );
+        assert_eq!(
+            opportunities[0].user,
+            USERS[1].into(),
+            "Worst position comes first"
+        );
+        assert!(opportunities[0].health_factor <= opportunities[1].health_factor);
+        for opportunity in opportunities.iter() {
+            assert!(opportunity.health_factor < 120);
+            assert!(!opportunity.overdue_term_loan);
+            assert_eq!(opportunity.max_repayable_debt, opportunity.total_debt);
+            assert_eq!(
+                opportunity.expected_collateral_seized,
+                opportunity.collateral
+            );
+        }
+    } else {
+        panic!("Expected LiquidationOpportunities reply");
+    }
+}
+
+#[test]
+fn test_preview_borrow_and_withdraw() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to users
+    sys.mint_to(USERS[0], 1_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+    sys.mint_to(USERS[2], 1_000_000_000_000_000);
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    let deposit_amount = 1_000_000_000_000;
+    lending_program.send_with_value(USERS[1], LendingAction::DepositCollateral, deposit_amount);
+
+    // No liquidity yet: the preview reports the broken rule without changing state
+    let reply = lending_program.send(
+        USERS[1],
+        LendingAction::PreviewBorrow {
+            user: USERS[1].into(),
+            amount: 0,
+        },
+    );
+    if let LendingReply::Preview(preview) = reply {
+        assert_eq!(preview.violation.as_deref(), Some("Insufficient liquidity"));
+        assert_eq!(preview.debt, 0);
+    } else {
+        panic!("Expected Preview reply");
+    }
+
+    // With liquidity the preview matches what `borrow` does
+    lending_program.send_with_value(USERS[2], LendingAction::Lend, 5_000_000_000_000);
+    let reply = lending_program.send(
+        USERS[1],
+        LendingAction::PreviewBorrow {
+            user: USERS[1].into(),
+            amount: 0,
+        },
+    );
+    let previewed_debt = if let LendingReply::Preview(preview) = reply {
+        assert!(preview.violation.is_none());
+        preview.debt
+    } else {
+        panic!("Expected Preview reply");
+    };
+    lending_program.send(USERS[1], LendingAction::Borrow);
+    let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
+    if let LendingReply::ContractState(state) = reply {
+        assert_eq!(state.debt.get(&USERS[1].into()), Some(&previewed_debt));
+    } else {
+        panic!("Expected ContractState reply");
+    }
+
+    // Withdrawing all collateral while in debt breaks the LTV rule
+    let reply = lending_program.send(
+        USERS[1],
+        LendingAction::PreviewWithdrawCollateral {
+            user: USERS[1].into(),
+            amount: deposit_amount,
+        },
+    );
+    if let LendingReply::Preview(preview) = reply {
+        assert_eq!(
+            preview.violation.as_deref(),
+            Some("Withdrawal would exceed LTV ratio")
+        );
+        assert_eq!(preview.collateral, deposit_amount);
+    } else {
+        panic!("Expected Preview reply");
+    }
+
+    // Deposit, repay and liquidate previews are reachable through the same actions
+    let reply = lending_program.send(
+        USERS[1],
+        LendingAction::PreviewDeposit {
+            user: USERS[1].into(),
+            amount: deposit_amount,
+        },
+    );
+    if let LendingReply::Preview(preview) = reply {
+        assert!(preview.violation.is_none());
+        assert_eq!(preview.collateral, 2 * deposit_amount);
+    } else {
+        panic!("Expected Preview reply");
+    }
+
+    let reply = lending_program.send(
+        USERS[1],
+        LendingAction::PreviewRepay {
+            user: USERS[1].into(),
+            amount: previewed_debt,
+        },
+    );
+    if let LendingReply::Preview(preview) = reply {
+        assert!(preview.violation.is_none());
+        assert_eq!(preview.debt, 0);
+    } else {
+        panic!("Expected Preview reply");
+    }
+
+    let reply = lending_program.send(USERS[0], LendingAction::PreviewLiquidate(USERS[1].into()));
+    if let LendingReply::Preview(preview) = reply {
+        assert_eq!(
+            preview.violation.as_deref(),
+            Some("Position not eligible for liquidation: Health factor is >= 120")
+        );
+    } else {
+        panic!("Expected Preview reply");
+    }
+}
+
+#[test]
+fn test_migrate_v1_state_to_v2() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to users
+    sys.mint_to(USERS[0], 1_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+
+    // Deploy the new code
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    // A populated state exported by a v1 program
+    let borrower: ActorId = USERS[1].into();
+    let lender: ActorId = USERS[2].into();
+    let v1_state = ContractStateV1 {
+        vft_address: VFT_ADDRESS.into(),
+        collateral: [(borrower, 1_000_000_000_000)].into_iter().collect(),
+        tvara_price: 1_000_000_000_000_000_000,
+        debt: [(borrower, 600_000_000_000)].into_iter().collect(),
+        lender_balances: [(lender, 2_000_000_000_000)].into_iter().collect(),
+        lender_interest_earned: [(lender, 1_000_000)].into_iter().collect(),
+        total_liquidity: 1_400_000_000_000,
+        treasury: 500_000,
+        paused: false,
+        admin: USERS[0].into(),
+        last_accrual_ts: 0,
+        total_interest_earned: 2_000_000,
+        user_accrued_interest: [(borrower, 2_000_000)].into_iter().collect(),
+        total_principal_borrowed: 600_000_000_000,
+    };
+
+    lending_program.send(
+        USERS[0],
+        LendingAction::ImportState(Box::new(VersionedState::V1(v1_state.clone()))),
+    );
+
+    // Assert every v1 field survived and the state is now at the current version
+    let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
+    if let LendingReply::ContractState(state) = reply {
+        assert_eq!(state.storage_version, STORAGE_VERSION);
+        assert_eq!(state.collateral, v1_state.collateral);
+        assert_eq!(state.debt, v1_state.debt);
+        assert_eq!(state.lender_balances, v1_state.lender_balances);
+        assert_eq!(
+            state.lender_interest_earned,
+            v1_state.lender_interest_earned
+        );
+        assert_eq!(state.user_accrued_interest, v1_state.user_accrued_interest);
+        assert_eq!(state.total_liquidity, v1_state.total_liquidity);
+        assert_eq!(state.treasury, v1_state.treasury);
+        // v1's single price was VARA's, TVARA starts pinned to 1 USD
+        assert_eq!(state.collateral_price, v1_state.tvara_price);
+        assert_eq!(state.debt_price, 1_000_000_000_000_000_000);
+        assert_eq!(
+            state.total_principal_borrowed,
+            v1_state.total_principal_borrowed
+        );
+    } else {
+        panic!("Expected ContractState reply");
+    }
+
+    let reply = lending_program.send(USERS[0], LendingAction::GetVersion);
+    if let LendingReply::Version(version) = reply {
+        assert_eq!(version.storage_version, STORAGE_VERSION);
+    } else {
+        panic!("Expected Version reply");
+    }
+
+    // A second import is rejected because the pool is no longer empty
+    lending_program.send(
+        USERS[0],
+        LendingAction::ImportState(Box::new(VersionedState::V1(v1_state))),
+    );
+    let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
+    if let LendingReply::ContractState(state) = reply {
+        assert_eq!(state.total_liquidity, 1_400_000_000_000);
+    } else {
+        panic!("Expected ContractState reply");
+    }
+}
+
+#[test]
+fn test_check_invariants() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to users
+    sys.mint_to(USERS[0], 1_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+    sys.mint_to(USERS[2], 1_000_000_000_000_000);
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    // Setup: provide liquidity, deposit collateral, and borrow
+    lending_program.send_with_value(USERS[2], LendingAction::Lend, 5_000_000_000_000);
+    lending_program.send_with_value(
+        USERS[1],
+        LendingAction::DepositCollateral,
+        1_000_000_000_000,
+    );
+    lending_program.send(USERS[1], LendingAction::Borrow);
+
+    // Every invariant holds and reports its expected and actual values
+    let reply = lending_program.send(USERS[0], LendingAction::CheckInvariants);
+    if let LendingReply::InvariantReport(report) = reply {
+        assert!(
+            report.all_hold,
+            "Invariants should hold: {:?}",
+            report.checks
+        );
+        assert!(!report.checks.is_empty());
+        for check in report.checks.iter() {
+            assert!(check.holds, "{} should hold", check.name);
+        }
+    } else {
+        panic!("Expected InvariantReport reply");
+    }
+}
+
+#[test]
+fn test_failed_burn_keeps_term_deposit() {
+    let sys = System::new();
+    sys.init_logger();
+
+    // Mint balance to users
+    sys.mint_to(USERS[0], 1_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    let deposit_amount = 1_000_000_000_000; // 1 TVARA
+    let before_deposit = sys.balance_of(USERS[1]);
+    let reply = lending_program.send_with_value(
+        USERS[1],
+        LendingAction::DepositTerm(DepositTier::Days30),
+        deposit_amount,
+    );
+    let LendingReply::TermDepositId(deposit_id) = reply else {
+        panic!("Expected TermDepositId reply");
+    };
+    let before_balance = sys.balance_of(USERS[1]);
+    assert_eq!(before_deposit - before_balance, deposit_amount);
+    let reply = lending_program.send(USERS[1], LendingAction::GetTermDeposit(deposit_id));
+    assert!(
+        matches!(reply, LendingReply::TermDeposit(Some(_))),
+        "Expected the term deposit to be open"
+    );
+
+    // The VFT address is a plain account, so the burn never succeeds: the withdrawal must fail
+    // and leave the deposit and the lender's VARA where they were
+    let reply = lending_program.send(USERS[1], LendingAction::WithdrawTermDeposit(deposit_id));
+    assert!(
+        matches!(reply, LendingReply::Error(_)),
+        "Expected the withdrawal to fail on the burn"
+    );
+    assert_eq!(sys.balance_of(USERS[1]), before_balance);
+
+    let reply = lending_program.send(USERS[1], LendingAction::GetTermDeposit(deposit_id));
+    if let LendingReply::TermDeposit(Some(info)) = reply {
+        assert_eq!(info.deposit.lender, USERS[1].into());
+        assert_eq!(info.deposit.amount, deposit_amount);
+    } else {
+        panic!("Expected the term deposit to remain open");
+    }
+}
+
+const DEX_ID: u64 = 20;
+
+// Sends a call to the mock DEX's `Dex` service and decodes its reply; `None` if the call failed
+fn dex_call<R: Decode>(
+    sys: &System,
+    dex: &Program,
+    from: u64,
+    method: &str,
+    args: impl Encode,
+    value: u128,
+) -> Option<R> {
+    let message_id = dex.send_bytes_with_value(from, ("Dex", method, args).encode(), value);
+    let result = sys.run_next_block();
+    if !result.succeed.contains(&message_id) {
+        return None;
+    }
+    let reply = result
+        .log()
+        .iter()
+        .find(|log| log.reply_to() == Some(message_id))
+        .expect("DEX call has no reply");
+    let (_, _, reply): (String, String, R) =
+        Decode::decode(&mut reply.payload()).expect("Invalid DEX reply");
+    Some(reply)
+}
+
+#[test]
+fn test_mock_dex_swap_respects_min_out() {
+    let sys = System::new();
+    sys.init_logger();
+
+    sys.mint_to(USERS[0], 10_000_000_000_000_000);
+    sys.mint_to(USERS[1], 1_000_000_000_000_000);
+
+    // A ledger-only DEX, no token program needed
+    let dex = Program::from_binary_with_id(&sys, DEX_ID, mock_dex::WASM_BINARY);
+    let init = dex.send_bytes(USERS[0], ("New", None::<ActorId>).encode());
+    assert!(sys.run_next_block().succeed.contains(&init));
+
+    // 1000 VARA against 750 TVARA, so 1 VARA is worth about 0.75 TVARA
+    let reserve_native = 1_000_000_000_000_000;
+    let reserve_token = 750_000_000_000_000;
+    dex_call::<()>(
+        &sys,
+        &dex,
+        USERS[0],
+        "AddLiquidity",
+        reserve_token,
+        reserve_native,
+    )
+    .expect("Adding liquidity should succeed");
+
+    let amount_in = 1_000_000_000_000; // 1 VARA
+    let expected = mock_dex::amount_out(reserve_native, reserve_token, amount_in);
+    let quote: u128 = dex_call(&sys, &dex, USERS[1], "QuoteNativeForTokens", amount_in, 0)
+        .expect("Quote should succeed");
+    assert_eq!(quote, expected);
+    // The 0.3% fee and price impact keep the output below the spot value
+    assert!(expected < 750_000_000_000);
+
+    // Asking for more than the pool pays fails and leaves the reserves untouched
+    let recipient: ActorId = USERS[2].into();
+    let rejected: Option<u128> = dex_call(
+        &sys,
+        &dex,
+        USERS[1],
+        "SwapNativeForTokens",
+        (expected + 1, recipient),
+        amount_in,
+    );
+    assert!(rejected.is_none());
+    let reserves: mock_dex::Reserves =
+        dex_call(&sys, &dex, USERS[1], "Reserves", (), 0).expect("Reserves should succeed");
+    assert_eq!(reserves.native, reserve_native);
+    assert_eq!(reserves.token, reserve_token);
+
+    // At the quoted minimum the swap goes through and pays the recipient
+    let amount_out: u128 = dex_call(
+        &sys,
+        &dex,
+        USERS[1],
+        "SwapNativeForTokens",
+        (expected, recipient),
+        amount_in,
+    )
+    .expect("Swap should succeed");
+    assert_eq!(amount_out, expected);
+    let balance: u128 =
+        dex_call(&sys, &dex, USERS[1], "BalanceOf", recipient, 0).expect("Balance should succeed");
+    assert_eq!(balance, expected);
+    let reserves: mock_dex::Reserves =
+        dex_call(&sys, &dex, USERS[1], "Reserves", (), 0).expect("Reserves should succeed");
+    assert_eq!(reserves.native, reserve_native + amount_in);
+    assert_eq!(reserves.token, reserve_token - expected);
+}
+
+#[test]
+fn test_dex_liquidation_settles_or_falls_back_to_auction() {
+    let sys = System::new();
+    sys.init_logger();
+
+    sys.mint_to(USERS[0], 10_000_000_000_000_000);
+    for &user in &USERS[1..5] {
+        sys.mint_to(user, 1_000_000_000_000_000);
+    }
+
+    let lending_program = Program::current(&sys);
+    lending_program.send(
+        USERS[0],
+        LendingInit {
+            vft_address: VFT_ADDRESS.into(),
+            debt_decimals: Some(12),
+        },
+    );
+
+    // 1000 VARA against 750 TVARA, in line with the 0.75 oracle price set below
+    let dex = Program::from_binary_with_id(&sys, DEX_ID, mock_dex::WASM_BINARY);
+    let init = dex.send_bytes(USERS[0], ("New", None::<ActorId>).encode());
+    assert!(sys.run_next_block().succeed.contains(&init));
+    dex_call::<()>(
+        &sys,
+        &dex,
+        USERS[0],
+        "AddLiquidity",
+        750_000_000_000_000u128,
+        1_000_000_000_000_000,
+    )
+    .expect("Adding liquidity should succeed");
+
+    // Two positions backed by 1 VARA each, liquidated through the DEX once VARA drops to 0.75
+    let collateral = 1_000_000_000_000;
+    lending_program.send_with_value(USERS[2], LendingAction::Lend, 5_000_000_000_000);
+    for user in [USERS[1], USERS[3]] {
+        lending_program.send_with_value(user, LendingAction::DepositCollateral, collateral);
+        lending_program.send(user, LendingAction::Borrow);
+    }
+    lending_program.send(
+        USERS[0],
+        LendingAction::ConfigureDex {
+            dex: DEX_ID.into(),
+            max_slippage_bps: 300,
+        },
+    );
+    lending_program.send(
+        USERS[0],
+        LendingAction::SetLiquidationMode(LiquidationMode::Dex),
+    );
+    lending_program.send(
+        USERS[0],
+        LendingAction::UpdateCollateralPrice(750_000_000_000_000_000),
+    );
+    // Let the TWAP catch up with the drop
+    sys.run_to_block(sys.block_height() + 1_000);
+
+    // Within 3% of the oracle value the swap fills and its proceeds settle the debt
+    let quote: u128 = dex_call(&sys, &dex, USERS[0], "QuoteNativeForTokens", collateral, 0)
+        .expect("Quote should succeed");
+    lending_program.send(USERS[4], LendingAction::Liquidate(USERS[1].into()));
+    let proceeds: u128 = dex_call(&sys, &dex, USERS[0], "BalanceOf", lending_program.id(), 0)
+        .expect("Balance should succeed");
+    assert_eq!(
+        proceeds, quote,
+        "The program should receive the swap output"
+    );
+
+    let reply = lending_program.send(USERS[0], LendingAction::GetDexSwaps);
+    let LendingReply::DexSwaps(swaps) = reply else {
+        panic!("Expected DexSwaps reply");
+    };
+    assert!(swaps.is_empty(), "The swap should be settled");
+    let reply = lending_program.send(USERS[0], LendingAction::GetContractState);
+    if let LendingReply::ContractState(state) = reply {
+        assert!(!state.collateral.contains_key(&USERS[1].into()));
+        assert!(!state.debt.contains_key(&USERS[1].into()));
+        assert_eq!(state.bad_debt, 0, "The proceeds cover the debt");
+    } else {
+        panic!("Expected ContractState reply");
+    }
+
+    // With no slippage allowed the DEX can't meet `min_out`: it refunds the VARA and the
+    // position is auctioned instead
+    lending_program.send(
+        USERS[0],
+        LendingAction::ConfigureDex {
+            dex: DEX_ID.into(),
+            max_slippage_bps: 0,
+        },
+    );
+    let reserves: mock_dex::Reserves =
+        dex_call(&sys, &dex, USERS[0], "Reserves", (), 0).expect("Reserves should succeed");
+    lending_program.send(USERS[4], LendingAction::Liquidate(USERS[3].into()));
+    let after: mock_dex::Reserves =
+        dex_call(&sys, &dex, USERS[0], "Reserves", (), 0).expect("Reserves should succeed");
+    assert_eq!(
+        after, reserves,
+        "The rejected swap should leave the DEX untouched"
+    );
+
+    let reply = lending_program.send(USERS[0], LendingAction::GetDexSwaps);
+    let LendingReply::DexSwaps(swaps) = reply else {
+        panic!("Expected DexSwaps reply");
+    };
+    assert!(swaps.is_empty(), "The failed swap should be closed");
+    let reply = lending_program.send(
+        USERS[0],
+        LendingAction::GetAuctions {
+            start_after: None,
+            limit: 10,
+        },
+    );
+    let LendingReply::Auctions(auctions) = reply else {
+        panic!("Expected Auctions reply");
+    };
+    assert_eq!(auctions.len(), 1);
+    assert_eq!(auctions[0].auction.user, USERS[3].into());
+    assert_eq!(auctions[0].auction.collateral, collateral);
+}